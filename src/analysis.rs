@@ -0,0 +1,490 @@
+// Emotion analysis pipeline shared by every API surface
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Json, Response},
+};
+
+use futures::{pin_mut, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc;
+use tracing::{debug, info, info_span, warn, Span};
+
+use crate::auth::Tenant;
+use crate::confidence::{ConfidenceSource, HEURISTIC_CONFIDENCE};
+use crate::metrics::ParseMatch;
+use crate::cache::CacheKeyParts;
+use crate::classifier::Backend;
+use crate::openapi::ErrorBody;
+use crate::ranges::TierLookup;
+use crate::ratelimit::{RateLimitKey, RateLimited};
+use crate::registry::RegistryError;
+use crate::validation::{EmptyInputPolicy, FieldError, Validator};
+use crate::{calculate_changes, parse_behavior_from_response, ranges, system_prompt};
+use crate::{metrics, session};
+use crate::{AppState, EmotionRequest, EmotionResponse, EmotionResponseV2, ResolvedCharacter, StateChange};
+
+#[derive(Debug)]
+pub enum AnalysisError {
+    /// The request has invalid fields; every one of them is listed
+    Invalid(Vec<FieldError>),
+    /// The request could not be resolved to a registry character
+    Character(RegistryError),
+    /// A rate limit bucket for the tenant, user or character is empty
+    RateLimited(RateLimited),
+    /// The upstream provider failed or returned an unusable response
+    Upstream(String),
+}
+
+impl AnalysisError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AnalysisError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AnalysisError::Character(error) => error.status(),
+            AnalysisError::RateLimited(_) => StatusCode::TOO_MANY_REQUESTS,
+            AnalysisError::Upstream(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        match self {
+            AnalysisError::Invalid(errors) => errors.iter().map(ToString::to_string).collect(),
+            AnalysisError::Character(error) => error.messages(),
+            AnalysisError::RateLimited(limited) => vec![format!(
+                "{} rate limit exceeded; retry in {}s",
+                limited.scope,
+                limited.retry_after_secs()
+            )],
+            AnalysisError::Upstream(message) => vec![message.clone()],
+        }
+    }
+
+    pub fn fields(&self) -> Vec<FieldError> {
+        match self {
+            AnalysisError::Invalid(errors) => errors.clone(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl From<RegistryError> for AnalysisError {
+    fn from(error: RegistryError) -> Self {
+        match error {
+            RegistryError::Invalid(errors) => AnalysisError::Invalid(errors),
+            error => AnalysisError::Character(error),
+        }
+    }
+}
+
+impl IntoResponse for AnalysisError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            errors: self.messages(),
+            fields: self.fields(),
+        };
+        let mut response = (self.status(), Json(body)).into_response();
+        if let AnalysisError::RateLimited(limited) = &self {
+            response.headers_mut().insert(header::RETRY_AFTER, limited.retry_after_secs().into());
+        }
+        response
+    }
+}
+
+/// A request that has been resolved and is ready to be sent to the provider
+pub struct PreparedAnalysis {
+    pub character: ResolvedCharacter,
+    pub current_emotion: i32,
+    pub current_relationship: i32,
+    /// Tier lookup of the API version that received the request
+    pub tiers: TierLookup,
+    pub prompt: String,
+    /// The input and history as sent, for local classifiers
+    pub user_input: String,
+    pub history: String,
+    /// Blank input accepted under the neutral policy; the provider is not called
+    pub empty_input: bool,
+    /// Classification cache key for this input, context and state
+    pub cache_key: String,
+}
+
+/// Provider prompt: the system prompt followed by the request's context
+pub fn build_prompt(history: &str, description: &str, relationship: &str, emotion: &str, user_input: &str) -> String {
+    format!(
+        "{}\n\nCharacter's History Output: {}\n{}\nCurrent Relationship: {}\nCurrent Emotion: {}\nUser Input: {}",
+        system_prompt::SYSTEM_PROMPT,
+        history,
+        description,
+        relationship,
+        emotion,
+        user_input
+    )
+}
+
+/// Validate the request, resolve the character and current state, and build the provider prompt
+pub fn prepare(
+    state: &AppState,
+    tenant: &Tenant,
+    payload: &EmotionRequest,
+    tiers: TierLookup,
+) -> Result<PreparedAnalysis, AnalysisError> {
+    let settings = &state.validation;
+    let mut validator = Validator::new();
+
+    if settings.empty_input == EmptyInputPolicy::Reject {
+        validator.not_blank("user_input", &payload.user_input);
+    }
+    validator
+        .max_chars("user_input", &payload.user_input, settings.max_user_input_chars)
+        .max_chars("character_history", &payload.character_history, settings.max_history_chars)
+        .max_chars("character_personality", &payload.character_personality, settings.max_personality_chars);
+
+    // Resolve and validate the character profile; its field errors are reported with the rest
+    let character = payload.resolve_character(&state.registry, tenant);
+    if let Err(RegistryError::Invalid(errors)) = &character {
+        validator.extend(errors.clone());
+    }
+
+    // Out-of-range state values are rejected or clamped, depending on the policy
+    let current_emotion = payload
+        .current_emotion
+        .map(|value| settings.state_value(&mut validator, "current_emotion", value, ranges::emotion_bounds()));
+    let current_relationship = payload
+        .current_relationship
+        .map(|value| settings.state_value(&mut validator, "current_relationship", value, ranges::relationship_bounds()));
+
+    validator.finish().map_err(AnalysisError::Invalid)?;
+    let character = character?;
+    Span::current().record("character", character.key());
+    let current_emotion = current_emotion.unwrap_or(character.default_emotion);
+    let current_relationship = current_relationship.unwrap_or(character.default_relationship);
+
+    // Convert emotion and relationship i32 values to category names for Grok
+    let emotion_str = ranges::get_emotion_from_value(current_emotion, tiers)
+        .unwrap_or("Neutral");
+    let relationship_str = ranges::get_relationship_from_value(current_relationship, tiers)
+        .unwrap_or("Acquaintance");
+
+    // Construct the user prompt with dynamic data
+    let description = character.profile.render_description();
+    let prompt = build_prompt(
+        &payload.character_history,
+        &description,
+        relationship_str,
+        emotion_str,
+        &payload.user_input,
+    );
+
+    let cache_key = state.classification_cache.key(&CacheKeyParts {
+        tenant: &tenant.id,
+        model: tenant.provider.model.as_deref().unwrap_or(state.provider.model()),
+        character_description: &description,
+        emotion_tier: emotion_str,
+        relationship_tier: relationship_str,
+        history: &payload.character_history,
+        user_input: &payload.user_input,
+    });
+
+    Ok(PreparedAnalysis {
+        character,
+        current_emotion,
+        current_relationship,
+        tiers,
+        prompt,
+        user_input: payload.user_input.clone(),
+        history: payload.character_history.clone(),
+        empty_input: payload.user_input.trim().is_empty(),
+        cache_key,
+    })
+}
+
+/// Charge the request to the tenant, user and character rate limit buckets
+pub async fn check_rate_limit(
+    state: &AppState,
+    tenant: &Tenant,
+    payload: &EmotionRequest,
+    prepared: &PreparedAnalysis,
+) -> Result<(), AnalysisError> {
+    let key = RateLimitKey {
+        tenant: &tenant.id,
+        user: payload.user_id.as_deref(),
+        character: prepared.character.key(),
+    };
+    state.rate_limiter.check(&key).await.map_err(|limited| {
+        warn!(scope = limited.scope, retry_after_secs = limited.retry_after_secs(), "🚦 rate limited");
+        AnalysisError::RateLimited(limited)
+    })
+}
+
+/// Behavior category chosen for an input
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Classification {
+    pub category: String,
+    /// How sure the classifier is of the category, 0 to 1
+    pub confidence: f32,
+    pub confidence_source: ConfidenceSource,
+}
+
+/// Parse the behavior category from the provider's reply. `probability` is the
+/// reply's token probability, when the provider returned one.
+pub fn classify(provider_response: &str, probability: Option<f32>) -> Classification {
+    let _span = info_span!("parse_behavior").entered();
+    debug!(response = provider_response, "🤖 provider response received");
+
+    // Parse the behavior category from Grok's response
+    let (behavior_category, matched) = parse_behavior_from_response(provider_response);
+    metrics::record_parse(matched);
+
+    // A guessed category is less certain than the model's own probability suggests
+    let (confidence, confidence_source) = match (matched, probability) {
+        (ParseMatch::Fallback, _) => (0.0, ConfidenceSource::Fallback),
+        (ParseMatch::Heuristic, p) => (p.unwrap_or(1.0) * HEURISTIC_CONFIDENCE, ConfidenceSource::Heuristic),
+        (ParseMatch::Exact, Some(p)) => (p, ConfidenceSource::Logprobs),
+        (ParseMatch::Exact, None) => (1.0, ConfidenceSource::Exact),
+    };
+    info!(category = %behavior_category, confidence, source = ?confidence_source, "🎭 behavior parsed");
+
+    Classification {
+        category: behavior_category,
+        confidence,
+        confidence_source,
+    }
+}
+
+/// Classification of blank input, which never reaches the provider
+fn empty_input_classification() -> Classification {
+    Classification {
+        category: "Neutral Behavior".to_string(),
+        confidence: 1.0,
+        confidence_source: ConfidenceSource::EmptyInput,
+    }
+}
+
+/// Result of analyzing one request, before it is shaped into an API response
+pub struct AnalysisOutcome {
+    pub behavior_category: String,
+    pub confidence: f32,
+    pub confidence_source: ConfidenceSource,
+    /// Low confidence under a `review` policy
+    pub needs_review: bool,
+    /// Category the classifier chose, when a low-confidence policy replaced it
+    pub adjusted_from: Option<String>,
+    pub emotion_change: i32,
+    pub relationship_change: i32,
+    /// Emotion and relationship the changes were computed from
+    pub current_emotion: i32,
+    pub current_relationship: i32,
+    /// Registry id and version when the character came from the registry
+    pub character_source: Option<(String, u32)>,
+}
+
+impl From<AnalysisOutcome> for EmotionResponse {
+    fn from(outcome: AnalysisOutcome) -> Self {
+        let (character_id, character_version) = outcome.character_source.unzip();
+        EmotionResponse {
+            emotion_change: outcome.emotion_change,
+            relationship_change: outcome.relationship_change,
+            character_id,
+            character_version,
+        }
+    }
+}
+
+impl From<AnalysisOutcome> for EmotionResponseV2 {
+    fn from(outcome: AnalysisOutcome) -> Self {
+        let (emotion_min, emotion_max) = ranges::emotion_bounds();
+        let (relationship_min, relationship_max) = ranges::relationship_bounds();
+        let emotion = outcome
+            .current_emotion
+            .saturating_add(outcome.emotion_change)
+            .clamp(emotion_min, emotion_max);
+        let relationship = outcome
+            .current_relationship
+            .saturating_add(outcome.relationship_change)
+            .clamp(relationship_min, relationship_max);
+
+        let (character_id, character_version) = outcome.character_source.unzip();
+        EmotionResponseV2 {
+            behavior_category: outcome.behavior_category,
+            confidence: outcome.confidence,
+            confidence_source: outcome.confidence_source,
+            needs_review: outcome.needs_review,
+            adjusted_from: outcome.adjusted_from,
+            emotion: StateChange {
+                change: outcome.emotion_change,
+                previous: outcome.current_emotion,
+                current: emotion,
+                previous_tier: session::emotion_tier(outcome.current_emotion),
+                current_tier: session::emotion_tier(emotion),
+            },
+            relationship: StateChange {
+                change: outcome.relationship_change,
+                previous: outcome.current_relationship,
+                current: relationship,
+                previous_tier: session::relationship_tier(outcome.current_relationship),
+                current_tier: session::relationship_tier(relationship),
+            },
+            character_id,
+            character_version,
+        }
+    }
+}
+
+/// Apply the character's low-confidence policy, then compute the emotion and
+/// relationship changes for a classified request
+pub fn compute(state: &AppState, prepared: PreparedAnalysis, classification: &Classification) -> AnalysisOutcome {
+    let decision = state.confidence_policies.decide(
+        &prepared.character.balance_profile,
+        &classification.category,
+        classification.confidence,
+    );
+    let behavior_category = decision.category.as_str();
+    // Calculate emotion and relationship changes based on behavior category and current state
+    let (emotion_change, relationship_change) = calculate_changes(
+        behavior_category,
+        prepared.current_emotion,
+        prepared.current_relationship,
+        prepared.tiers,
+    );
+    metrics::record_analysis(behavior_category, emotion_change, relationship_change);
+
+    AnalysisOutcome {
+        behavior_category: behavior_category.to_string(),
+        confidence: classification.confidence,
+        confidence_source: classification.confidence_source,
+        needs_review: decision.needs_review,
+        adjusted_from: decision.adjusted_from,
+        emotion_change,
+        relationship_change,
+        current_emotion: prepared.current_emotion,
+        current_relationship: prepared.current_relationship,
+        character_source: prepared.character.source,
+    }
+}
+
+/// Receives the provider's reply token by token, for streaming responses
+pub type TokenSender = mpsc::UnboundedSender<String>;
+
+/// Ask the provider for a classification: once, or once per ensemble sample.
+/// A single reply is streamed into `tokens` when set; ensemble votes are not.
+async fn classify_upstream(
+    state: &AppState,
+    tenant: &Tenant,
+    prompt: &str,
+    tokens: Option<&TokenSender>,
+) -> Result<Classification, AnalysisError> {
+    if state.ensemble.enabled() {
+        return state
+            .ensemble
+            .classify(&state.provider, &state.upstream_permits, &tenant.provider, prompt)
+            .await
+            .map_err(|e| AnalysisError::Upstream(e.to_string()));
+    }
+
+    // Make request to Grok API, bounded by the shared upstream concurrency limit
+    let _permit = state.upstream_permits.acquire().await
+        .map_err(|e| AnalysisError::Upstream(e.to_string()))?;
+    let Some(tokens) = tokens else {
+        let completion = state.provider.complete(prompt, &tenant.provider).await
+            .map_err(|e| AnalysisError::Upstream(e.to_string()))?;
+        return Ok(classify(&completion.content, completion.probability));
+    };
+
+    let stream = state.provider.stream(prompt, &tenant.provider).await
+        .map_err(|e| AnalysisError::Upstream(e.to_string()))?;
+    pin_mut!(stream);
+    let mut reply = String::new();
+    while let Some(token) = stream.next().await {
+        let text = token.map_err(|e| AnalysisError::Upstream(e.to_string()))?;
+        reply.push_str(&text);
+        // A closed receiver means the client left; the reply is still classified and cached
+        let _ = tokens.send(text);
+    }
+    Ok(classify(reply.trim(), None))
+}
+
+/// Cached provider classification; local backends are not cached
+async fn cached(state: &AppState, prepared: &PreparedAnalysis) -> Option<Classification> {
+    if state.classifier.primary != Backend::Provider {
+        return None;
+    }
+    state.classification_cache.get(&prepared.cache_key).await
+}
+
+/// The pre-filter's classification, when it is confident enough to skip the primary backend
+fn prefilter(state: &AppState, prepared: &PreparedAnalysis) -> Option<Classification> {
+    let classification = state.classifier.prefilter(&prepared.user_input, &prepared.history)?;
+    info!(category = %classification.category, confidence = classification.confidence, "⚡ answered by the pre-filter");
+    metrics::record_classification(&Backend::Local(state.classifier.prefilter?).to_string(), "prefilter");
+    Some(classification)
+}
+
+/// Classify with the fallback backend after the provider failed, or pass the error on
+fn fallback(state: &AppState, prepared: &PreparedAnalysis, error: AnalysisError) -> Result<Classification, AnalysisError> {
+    let (AnalysisError::Upstream(message), Some(backend)) = (&error, state.classifier.fallback) else {
+        return Err(error);
+    };
+    warn!(error = %message, backend = %Backend::Local(backend), "🛟 provider failed, using the fallback classifier");
+    metrics::record_classification(&Backend::Local(backend).to_string(), "fallback");
+    Ok(state.classifier.local(backend, &prepared.user_input, &prepared.history))
+}
+
+/// Classify with the pre-filter, the primary backend and the fallback, in that order
+async fn classify_input(
+    state: &AppState,
+    tenant: &Tenant,
+    prepared: &PreparedAnalysis,
+    tokens: Option<&TokenSender>,
+) -> Result<Classification, AnalysisError> {
+    if let Some(classification) = prefilter(state, prepared) {
+        return Ok(classification);
+    }
+    let primary = state.classifier.primary;
+    let classification = match primary {
+        Backend::Provider => match classify_upstream(state, tenant, &prepared.prompt, tokens).await {
+            Ok(classification) => classification,
+            Err(error) => return fallback(state, prepared, error),
+        },
+        Backend::Local(backend) => state.classifier.local(backend, &prepared.user_input, &prepared.history),
+    };
+    metrics::record_classification(&primary.to_string(), "primary");
+    Ok(classification)
+}
+
+/// Classify a prepared request: blank input and cached inputs first, then the classifiers.
+/// With `tokens`, a single provider reply is passed on as it streams in.
+pub async fn classification(
+    state: &AppState,
+    tenant: &Tenant,
+    prepared: &PreparedAnalysis,
+    tokens: Option<&TokenSender>,
+) -> Result<Classification, AnalysisError> {
+    if prepared.empty_input {
+        info!("💤 empty input, answering as Neutral Behavior");
+        return Ok(empty_input_classification());
+    }
+
+    // Recurring inputs reuse their classification; the draw in compute still happens per request
+    if let Some(classification) = cached(state, prepared).await {
+        info!(category = %classification.category, "📦 classification cache hit");
+        return Ok(classification);
+    }
+
+    let classification = classify_input(state, tenant, prepared, tokens).await?;
+    state.classification_cache.put(&prepared.cache_key, &classification).await;
+    Ok(classification)
+}
+
+/// Run the full analysis for one request
+pub async fn run(
+    state: &AppState,
+    tenant: &Tenant,
+    payload: &EmotionRequest,
+    tiers: TierLookup,
+) -> Result<AnalysisOutcome, AnalysisError> {
+    info!("🎯 emotion analysis request");
+
+    let prepared = prepare(state, tenant, payload, tiers)?;
+    check_rate_limit(state, tenant, payload, &prepared).await?;
+    let classification = classification(state, tenant, &prepared, None).await?;
+    Ok(compute(state, prepared, &classification))
+}
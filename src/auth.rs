@@ -0,0 +1,264 @@
+// API key authentication: every key belongs to a tenant
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn, Span};
+
+use crate::openapi::ErrorBody;
+use crate::provider::ProviderOverrides;
+use crate::AppState;
+
+/// Tenant used for every request when authentication is disabled
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Authenticated caller, attached to the request extensions by [`require_api_key`]
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub id: String,
+    /// Balance profiles the tenant's characters may use
+    pub balance_profiles: Vec<String>,
+    /// Provider endpoint, key and model used instead of the server defaults
+    pub provider: ProviderOverrides,
+}
+
+impl Tenant {
+    fn default_tenant() -> Self {
+        Tenant {
+            id: DEFAULT_TENANT.to_string(),
+            balance_profiles: default_balance_profiles(),
+            provider: ProviderOverrides::default(),
+        }
+    }
+}
+
+fn default_balance_profiles() -> Vec<String> {
+    vec!["default".to_string()]
+}
+
+/// Key file layout: tenants with the SHA-256 hashes of their keys
+#[derive(Deserialize)]
+struct KeyFile {
+    tenants: Vec<TenantKeys>,
+}
+
+#[derive(Deserialize)]
+struct TenantKeys {
+    id: String,
+    /// Hex SHA-256 of each accepted key; several keys allow rotation
+    key_hashes: Vec<String>,
+    #[serde(default = "default_balance_profiles")]
+    balance_profiles: Vec<String>,
+    #[serde(default)]
+    provider_url: Option<String>,
+    /// Name of the environment variable holding the tenant's provider key
+    #[serde(default)]
+    provider_api_key_env: Option<String>,
+    #[serde(default)]
+    model: Option<String>,
+}
+
+impl TenantKeys {
+    fn tenant(&self) -> anyhow::Result<Tenant> {
+        let api_key = match &self.provider_api_key_env {
+            Some(name) => Some(std::env::var(name).map_err(|_| {
+                anyhow::anyhow!("tenant '{}': environment variable {} is not set", self.id, name)
+            })?),
+            None => None,
+        };
+        Ok(Tenant {
+            id: self.id.clone(),
+            balance_profiles: self.balance_profiles.clone(),
+            provider: ProviderOverrides {
+                api_url: self.provider_url.clone(),
+                api_key,
+                model: self.model.clone(),
+            },
+        })
+    }
+}
+
+/// Hashed API keys, looked up by the hash of the presented key
+pub struct ApiKeys {
+    tenants: HashMap<String, Tenant>,
+    enabled: bool,
+}
+
+/// Hex SHA-256 of an API key, as stored in the key file
+pub fn hash_key(key: &str) -> String {
+    format!("{:x}", Sha256::digest(key.as_bytes()))
+}
+
+impl ApiKeys {
+    /// Load the key file; `None`, allowed only with `auth_disabled`, disables authentication
+    pub fn open(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let Some(path) = path else {
+            return Ok(ApiKeys {
+                tenants: HashMap::new(),
+                enabled: false,
+            });
+        };
+        let file: KeyFile = serde_json::from_str(&std::fs::read_to_string(&path)?)?;
+
+        let mut tenants = HashMap::new();
+        for entry in file.tenants {
+            let tenant = entry.tenant()?;
+            for hash in &entry.key_hashes {
+                let tenant = tenant.clone();
+                if tenants.insert(hash.trim().to_lowercase(), tenant).is_some() {
+                    anyhow::bail!("key hash listed more than once in {}", path.display());
+                }
+            }
+        }
+        Ok(ApiKeys {
+            tenants,
+            enabled: true,
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Resolve the tenant owning `key`
+    pub fn authenticate(&self, key: Option<&str>) -> Result<Tenant, String> {
+        if !self.enabled {
+            return Ok(Tenant::default_tenant());
+        }
+        let key = key.filter(|k| !k.is_empty()).ok_or("missing API key")?;
+        self.tenants
+            .get(&hash_key(key))
+            .cloned()
+            .ok_or_else(|| "invalid API key".to_string())
+    }
+}
+
+/// Key from `Authorization: Bearer <key>` or `X-API-Key: <key>`
+pub fn presented_key(authorization: Option<&str>, api_key: Option<&str>) -> Option<String> {
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .or(api_key)
+        .map(|key| key.trim().to_string())
+}
+
+fn header_key(headers: &HeaderMap) -> Option<String> {
+    presented_key(
+        headers.get(header::AUTHORIZATION).and_then(|v| v.to_str().ok()),
+        headers.get("x-api-key").and_then(|v| v.to_str().ok()),
+    )
+}
+
+/// Reject requests without a valid API key and attach the caller's [`Tenant`]
+pub async fn require_api_key(State(state): State<AppState>, mut request: Request, next: Next) -> Response {
+    match state.api_keys.authenticate(header_key(request.headers()).as_deref()) {
+        Ok(tenant) => {
+            Span::current().record("tenant", tenant.id.as_str());
+            debug!("🔑 authenticated");
+            request.extensions_mut().insert(tenant);
+            next.run(request).await
+        }
+        Err(message) => {
+            warn!(reason = %message, "⛔ authentication failed");
+            let body = ErrorBody {
+                errors: vec![message],
+                fields: Vec::new(),
+            };
+            (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+                Json(body),
+            )
+                .into_response()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn keys_are_stored_as_sha256_hex() {
+        assert_eq!(hash_key("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    fn key_file(name: &str, json: &str) -> anyhow::Result<ApiKeys> {
+        let path = std::env::temp_dir().join(format!("auth-test-{}-{}.json", name, std::process::id()));
+        std::fs::write(&path, json).unwrap();
+        let keys = ApiKeys::open(Some(path.clone()));
+        std::fs::remove_file(&path).unwrap();
+        keys
+    }
+
+    #[test]
+    fn keys_resolve_to_their_tenant() {
+        // Hashes are matched case-insensitively; each tenant may list several
+        let json = format!(
+            r#"{{"tenants": [
+                {{"id": "studio-a", "key_hashes": ["{}", "{}"], "model": "grok-3"}},
+                {{"id": "studio-b", "key_hashes": ["{}"], "balance_profiles": ["strict"]}}
+            ]}}"#,
+            hash_key("key-a1").to_uppercase(),
+            hash_key("key-a2"),
+            hash_key("key-b"),
+        );
+        let keys = key_file("lookup", &json).unwrap();
+        assert!(keys.enabled());
+
+        let a = keys.authenticate(Some("key-a1")).unwrap();
+        assert_eq!((a.id.as_str(), a.provider.model.as_deref()), ("studio-a", Some("grok-3")));
+        assert_eq!(keys.authenticate(Some("key-a2")).unwrap().id, "studio-a");
+        let b = keys.authenticate(Some("key-b")).unwrap();
+        assert_eq!((b.id.as_str(), b.balance_profiles.as_slice()), ("studio-b", ["strict".to_string()].as_slice()));
+
+        assert_eq!(keys.authenticate(Some("key-c")).unwrap_err(), "invalid API key");
+        assert_eq!(keys.authenticate(Some("")).unwrap_err(), "missing API key");
+        assert_eq!(keys.authenticate(None).unwrap_err(), "missing API key");
+    }
+
+    #[test]
+    fn a_hash_may_belong_to_one_tenant_only() {
+        let json = format!(
+            r#"{{"tenants": [{{"id": "a", "key_hashes": ["{0}"]}}, {{"id": "b", "key_hashes": ["{0}"]}}]}}"#,
+            hash_key("shared"),
+        );
+        assert!(key_file("duplicate", &json).is_err());
+    }
+
+    #[test]
+    fn without_a_key_file_every_request_is_the_default_tenant() {
+        let keys = ApiKeys::open(None).unwrap();
+        assert!(!keys.enabled());
+        assert_eq!(keys.authenticate(None).unwrap().id, DEFAULT_TENANT);
+    }
+
+    #[test]
+    fn keys_come_from_bearer_or_x_api_key() {
+        assert_eq!(presented_key(Some("Bearer abc"), None).as_deref(), Some("abc"));
+        assert_eq!(presented_key(None, Some(" abc ")).as_deref(), Some("abc"));
+        // Bearer wins when both are sent; other schemes fall through to X-API-Key
+        assert_eq!(presented_key(Some("Bearer abc"), Some("xyz")).as_deref(), Some("abc"));
+        assert_eq!(presented_key(Some("Basic dXNlcg=="), Some("xyz")).as_deref(), Some("xyz"));
+        assert_eq!(presented_key(Some("Basic dXNlcg=="), None), None);
+        assert_eq!(presented_key(None, None), None);
+    }
+
+    #[test]
+    fn http_headers_carry_the_key() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-api-key", HeaderValue::from_static("from-header"));
+        assert_eq!(header_key(&headers).as_deref(), Some("from-header"));
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Bearer from-bearer"));
+        assert_eq!(header_key(&headers).as_deref(), Some("from-bearer"));
+    }
+}
@@ -0,0 +1,171 @@
+// Batch analysis of many emotion requests in a single call
+
+use axum::{extract::State, http::StatusCode, response::Json, Extension};
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+use utoipa::ToSchema;
+
+use tracing::{field, info, info_span, Instrument};
+
+use crate::analysis::{self, AnalysisOutcome};
+use crate::auth::Tenant;
+use crate::openapi::ErrorBody;
+use crate::ranges::TierLookup;
+use crate::{AppState, EmotionRequest, EmotionResponse, EmotionResponseV2};
+
+/// Limits applied to `/analyze-emotion/batch`, validated by [`crate::config::Config::load`]
+#[derive(Debug, Clone, Copy)]
+pub struct BatchSettings {
+    /// Maximum number of items accepted in one batch
+    pub max_items: usize,
+    /// Number of items of one batch analyzed concurrently
+    pub concurrency: usize,
+}
+
+impl BatchSettings {
+    /// Reject batches larger than the configured maximum
+    pub fn check_size(&self, len: usize) -> Result<(), String> {
+        if len > self.max_items {
+            Err(format!("batch contains {} items, the maximum is {}", len, self.max_items))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Result of one batch item; `T` is the analysis response of the API version
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchItemResult<T> {
+    Ok {
+        index: usize,
+        result: T,
+    },
+    Error {
+        index: usize,
+        code: u16,
+        errors: Vec<String>,
+    },
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BatchResponse<T> {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub results: Vec<BatchItemResult<T>>,
+}
+
+/// Analyze an array of requests; each item succeeds or fails independently
+#[utoipa::path(
+    post,
+    path = "/analyze-emotion/batch",
+    request_body = Vec<EmotionRequest>,
+    responses(
+        (status = 200, description = "Per-item results in input order", body = BatchResponse<EmotionResponse>),
+        (status = 413, description = "Too many items", body = ErrorBody)
+    )
+)]
+pub async fn analyze_emotion_batch(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(items): Json<Vec<serde_json::Value>>,
+) -> Result<Json<BatchResponse<EmotionResponse>>, (StatusCode, Json<ErrorBody>)> {
+    batch_response(&state, &tenant, items, TierLookup::Legacy).await
+}
+
+/// `/v2` batch analysis, returning the richer per-item response
+#[utoipa::path(
+    post,
+    path = "/analyze-emotion/batch",
+    request_body = Vec<EmotionRequest>,
+    responses(
+        (status = 200, description = "Per-item results in input order", body = BatchResponse<EmotionResponseV2>),
+        (status = 413, description = "Too many items", body = ErrorBody)
+    )
+)]
+pub async fn analyze_emotion_batch_v2(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(items): Json<Vec<serde_json::Value>>,
+) -> Result<Json<BatchResponse<EmotionResponseV2>>, (StatusCode, Json<ErrorBody>)> {
+    batch_response(&state, &tenant, items, TierLookup::Corrected).await
+}
+
+async fn batch_response<T: From<AnalysisOutcome>>(
+    state: &AppState,
+    tenant: &Tenant,
+    items: Vec<serde_json::Value>,
+    tiers: TierLookup,
+) -> Result<Json<BatchResponse<T>>, (StatusCode, Json<ErrorBody>)> {
+    if let Err(message) = state.batch.check_size(items.len()) {
+        let body = ErrorBody {
+            errors: vec![message],
+            fields: Vec::new(),
+        };
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, Json(body)));
+    }
+
+    // Items are decoded individually so one malformed entry does not reject the batch
+    let items = items
+        .into_iter()
+        .map(|item| serde_json::from_value(item).map_err(|e| e.to_string()))
+        .collect();
+
+    Ok(Json(run_batch(state, tenant, items, tiers).await))
+}
+
+/// Analyze decoded batch items with the configured concurrency, preserving order
+pub async fn run_batch<T: From<AnalysisOutcome>>(
+    state: &AppState,
+    tenant: &Tenant,
+    items: Vec<Result<EmotionRequest, String>>,
+    tiers: TierLookup,
+) -> BatchResponse<T> {
+    info!(items = items.len(), concurrency = state.batch.concurrency, "📦 batch analysis");
+
+    let results: Vec<BatchItemResult<T>> = stream::iter(items.into_iter().enumerate())
+        .map(|(index, item)| {
+            analyze_item(state, tenant, index, item, tiers).instrument(info_span!("batch_item", index, character = field::Empty))
+        })
+        .buffered(state.batch.concurrency)
+        .collect()
+        .await;
+
+    let succeeded = results.iter().filter(|r| matches!(r, BatchItemResult::Ok { .. })).count();
+    BatchResponse {
+        succeeded,
+        failed: results.len() - succeeded,
+        results,
+    }
+}
+
+async fn analyze_item<T: From<AnalysisOutcome>>(
+    state: &AppState,
+    tenant: &Tenant,
+    index: usize,
+    item: Result<EmotionRequest, String>,
+    tiers: TierLookup,
+) -> BatchItemResult<T> {
+    let payload = match item {
+        Ok(payload) => payload,
+        Err(message) => {
+            return BatchItemResult::Error {
+                index,
+                code: StatusCode::UNPROCESSABLE_ENTITY.as_u16(),
+                errors: vec![message],
+            }
+        }
+    };
+
+    match analysis::run(state, tenant, &payload, tiers).await {
+        Ok(outcome) => BatchItemResult::Ok {
+            index,
+            result: outcome.into(),
+        },
+        Err(error) => BatchItemResult::Error {
+            index,
+            code: error.status().as_u16(),
+            errors: error.messages(),
+        },
+    }
+}
@@ -0,0 +1,307 @@
+// Local n-gram naive Bayes classifier, trained from labeled interactions and run on the CPU
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{BufRead, BufReader};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::analysis::Classification;
+use crate::behavior;
+use crate::confidence::ConfidenceSource;
+use crate::lexicon::tokens;
+
+/// Version of the model file layout
+const MODEL_FORMAT: u32 = 1;
+
+/// One labeled interaction in the training data
+#[derive(Deserialize)]
+pub struct LabeledRecord {
+    pub input: String,
+    /// Character history before the input, if any
+    #[serde(default)]
+    pub context: String,
+    pub category: String,
+    /// Character personality for provider prompts; the local classifiers ignore it
+    #[serde(default)]
+    pub personality: String,
+}
+
+/// Read a JSONL file of labeled records, reporting every malformed line
+pub fn read_records(path: &Path) -> anyhow::Result<Vec<LabeledRecord>> {
+    let file = std::fs::File::open(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+    let mut records = Vec::new();
+    let mut problems = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<LabeledRecord>(&line) {
+            Ok(record) if behavior::get_behavior_range(&record.category).is_some() => records.push(record),
+            Ok(record) => problems.push(format!("line {}: unknown category '{}'", index + 1, record.category)),
+            Err(e) => problems.push(format!("line {}: {}", index + 1, e)),
+        }
+    }
+    if !problems.is_empty() {
+        anyhow::bail!("{}: {}", path.display(), problems.join("; "));
+    }
+    Ok(records)
+}
+
+/// Training settings, stored in the model so inference extracts the same features
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Features {
+    /// Longest word n-gram, 1 to 3
+    pub ngram: usize,
+    /// Trailing history lines used as context features
+    pub context_lines: usize,
+}
+
+impl Features {
+    /// Word n-grams of the input, plus context unigrams under a `ctx:` prefix
+    fn extract(&self, input: &str, context: &str) -> Vec<String> {
+        let words = tokens(input);
+        let mut features = Vec::new();
+        for n in 1..=self.ngram {
+            features.extend(words.windows(n).map(|gram| gram.join(" ")));
+        }
+        let lines: Vec<&str> = context.lines().filter(|l| !l.trim().is_empty()).collect();
+        for line in &lines[lines.len().saturating_sub(self.context_lines)..] {
+            features.extend(tokens(line).into_iter().map(|word| format!("ctx:{}", word)));
+        }
+        features
+    }
+}
+
+/// Counts for one category
+#[derive(Serialize, Deserialize, Default)]
+struct CategoryCounts {
+    documents: u64,
+    features: u64,
+    counts: BTreeMap<String, u64>,
+}
+
+/// Model file layout
+#[derive(Serialize, Deserialize)]
+struct ModelFile {
+    format: u32,
+    features: Features,
+    /// Additive smoothing for unseen feature/category pairs
+    alpha: f64,
+    categories: BTreeMap<String, CategoryCounts>,
+}
+
+/// Summary of a training run
+pub struct TrainReport {
+    pub records: usize,
+    pub vocabulary: usize,
+    pub categories: Vec<(String, u64)>,
+}
+
+/// Count features per category and write the model file
+pub fn train(records: &[LabeledRecord], features: Features, output: &Path) -> anyhow::Result<TrainReport> {
+    if records.is_empty() {
+        anyhow::bail!("no training records");
+    }
+    if !(1..=3).contains(&features.ngram) {
+        anyhow::bail!("ngram must be between 1 and 3");
+    }
+
+    let mut categories: BTreeMap<String, CategoryCounts> = BTreeMap::new();
+    for record in records {
+        let counts = categories.entry(record.category.clone()).or_default();
+        counts.documents += 1;
+        for feature in features.extract(&record.input, &record.context) {
+            counts.features += 1;
+            *counts.counts.entry(feature).or_default() += 1;
+        }
+    }
+
+    let model = ModelFile {
+        format: MODEL_FORMAT,
+        features,
+        alpha: 1.0,
+        categories,
+    };
+    let report = TrainReport {
+        records: records.len(),
+        vocabulary: vocabulary(&model),
+        categories: model.categories.iter().map(|(name, c)| (name.clone(), c.documents)).collect(),
+    };
+    let data = serde_json::to_vec(&model)?;
+    std::fs::write(output, data).map_err(|e| anyhow::anyhow!("{}: {}", output.display(), e))?;
+    Ok(report)
+}
+
+fn vocabulary(model: &ModelFile) -> usize {
+    model.categories.values().flat_map(|c| c.counts.keys()).collect::<HashSet<_>>().len()
+}
+
+/// Log probabilities for one category
+struct CategoryModel {
+    name: String,
+    log_prior: f64,
+    log_likelihood: HashMap<String, f64>,
+    /// Log likelihood of a known feature never seen with this category
+    log_unseen: f64,
+}
+
+/// A trained model, ready for inference
+pub struct NaiveBayes {
+    features: Features,
+    categories: Vec<CategoryModel>,
+    /// Every feature seen in training; others carry no evidence and are skipped
+    vocabulary: HashSet<String>,
+}
+
+impl NaiveBayes {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let model: ModelFile = serde_json::from_slice(&data).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        if model.format != MODEL_FORMAT {
+            anyhow::bail!("{}: model format {} is not supported; retrain it", path.display(), model.format);
+        }
+        if model.categories.is_empty() {
+            anyhow::bail!("{}: the model has no categories", path.display());
+        }
+
+        let vocabulary: HashSet<String> =
+            model.categories.values().flat_map(|c| c.counts.keys().cloned()).collect();
+        let documents: u64 = model.categories.values().map(|c| c.documents).sum();
+        let denominator_extra = model.alpha * vocabulary.len() as f64;
+        let categories = model
+            .categories
+            .into_iter()
+            .map(|(name, counts)| {
+                let denominator = (counts.features as f64 + denominator_extra).ln();
+                CategoryModel {
+                    name,
+                    log_prior: (counts.documents as f64 / documents as f64).ln(),
+                    log_unseen: model.alpha.ln() - denominator,
+                    log_likelihood: counts
+                        .counts
+                        .into_iter()
+                        .map(|(feature, count)| (feature, (count as f64 + model.alpha).ln() - denominator))
+                        .collect(),
+                }
+            })
+            .collect();
+        Ok(NaiveBayes {
+            features: model.features,
+            categories,
+            vocabulary,
+        })
+    }
+
+    pub fn category_count(&self) -> usize {
+        self.categories.len()
+    }
+
+    /// Most probable category; the confidence is its posterior probability
+    pub fn classify(&self, user_input: &str, context: &str) -> Classification {
+        let features: Vec<String> = self
+            .features
+            .extract(user_input, context)
+            .into_iter()
+            .filter(|f| self.vocabulary.contains(f))
+            .collect();
+        let scores: Vec<f64> = self
+            .categories
+            .iter()
+            .map(|category| {
+                category.log_prior
+                    + features
+                        .iter()
+                        .map(|f| category.log_likelihood.get(f).copied().unwrap_or(category.log_unseen))
+                        .sum::<f64>()
+            })
+            .collect();
+
+        // Softmax over the log scores, shifted by the maximum to stay finite
+        let max = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let total: f64 = scores.iter().map(|s| (s - max).exp()).sum();
+        let best = scores
+            .iter()
+            .enumerate()
+            .fold(0, |best, (index, score)| if *score > scores[best] { index } else { best });
+        Classification {
+            category: self.categories[best].name.clone(),
+            confidence: (1.0 / total) as f32,
+            confidence_source: ConfidenceSource::Model,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(input: &str, category: &str) -> LabeledRecord {
+        LabeledRecord {
+            input: input.to_string(),
+            context: String::new(),
+            category: category.to_string(),
+            personality: String::new(),
+        }
+    }
+
+    /// Train on `records` and load the written model back
+    fn trained(name: &str, records: &[LabeledRecord]) -> NaiveBayes {
+        let path = std::env::temp_dir().join(format!("bayes-test-{}-{}.json", name, std::process::id()));
+        let features = Features { ngram: 2, context_lines: 2 };
+        let report = train(records, features, &path).unwrap();
+        assert_eq!(report.records, records.len());
+        let model = NaiveBayes::open(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        model
+    }
+
+    #[test]
+    fn classifies_what_it_was_trained_on() {
+        let model = trained(
+            "round-trip",
+            &[
+                record("thank you so much", "StrongPositiveBehavior"),
+                record("you are wonderful, thank you", "StrongPositiveBehavior"),
+                record("I hate you", "StrongNegativeBehavior"),
+                record("you are awful and I hate this", "StrongNegativeBehavior"),
+                record("hello there", "Neutral Behavior"),
+            ],
+        );
+        assert_eq!(model.category_count(), 3);
+
+        let positive = model.classify("thank you, you are wonderful", "");
+        assert_eq!(positive.category, "StrongPositiveBehavior");
+        assert!(positive.confidence > 0.5 && positive.confidence <= 1.0);
+        assert_eq!(positive.confidence_source, ConfidenceSource::Model);
+        assert_eq!(model.classify("I hate you", "").category, "StrongNegativeBehavior");
+    }
+
+    #[test]
+    fn unseen_tokens_fall_back_to_the_priors() {
+        let model = trained(
+            "unseen",
+            &[
+                record("great job", "ModeratePositiveBehavior"),
+                record("great work", "ModeratePositiveBehavior"),
+                record("go away", "LightNegativeBehavior"),
+            ],
+        );
+        for input in ["zxqv blorp", "", "🙂"] {
+            let classification = model.classify(input, "unknown context line");
+            // Only the priors remain, and the larger class wins with its share of the data
+            assert_eq!(classification.category, "ModeratePositiveBehavior");
+            assert!(classification.confidence.is_finite());
+            assert!((classification.confidence - 2.0 / 3.0).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn a_single_category_model_stays_finite() {
+        let model = trained("single", &[record("hello", "Neutral Behavior")]);
+        let classification = model.classify("never seen before", "");
+        assert_eq!(classification.category, "Neutral Behavior");
+        assert_eq!(classification.confidence, 1.0);
+    }
+}
@@ -32,9 +32,47 @@ pub mod negative_behaviors {
     ];
 }
 
+/// Each category with the next less intense category of the same kind
+pub const INTENSITY_STEPS: &[(&str, &str)] = &[
+    ("ExtremePositiveBehavior", "StrongPositiveBehavior"),
+    ("StrongPositiveBehavior", "ModeratePositiveBehavior"),
+    ("ModeratePositiveBehavior", "LightPositiveBehavior"),
+    ("LightPositiveBehavior", "Neutral Behavior"),
+    ("ExtremeNegativeBehavior", "StrongNegativeBehavior"),
+    ("StrongNegativeBehavior", "ModerateNegativeBehavior"),
+    ("ModerateNegativeBehavior", "LightNegativeBehavior"),
+    ("LightNegativeBehavior", "Neutral Behavior"),
+    ("Sexual_Extreme", "Sexual_Strong"),
+    ("Sexual_Strong", "Sexual_Moderate"),
+    ("Sexual_Moderate", "Sexual_Light"),
+    ("Sexual_Light", "Sexual_Neutral"),
+    ("Sexual_Neg_Extreme", "Sexual_Neg_Strong"),
+    ("Sexual_Neg_Strong", "Sexual_Neg_Moderate"),
+    ("Sexual_Neg_Moderate", "Sexual_Neg_Light"),
+    ("Sexual_Neg_Light", "Sexual_Neutral"),
+];
 
 // Helper functions
 
+/// One intensity step down; neutral categories stay as they are
+pub fn less_intense(behavior: &str) -> &str {
+    INTENSITY_STEPS.iter()
+        .find(|(name, _)| *name == behavior)
+        .map_or(behavior, |(_, lower)| lower)
+}
+
+/// Every category, positive then negative, in table order
+pub fn all_categories() -> impl Iterator<Item = &'static str> {
+    positive_behaviors::RANGES.iter()
+        .chain(negative_behaviors::RANGES.iter())
+        .map(|(name, _)| *name)
+}
+
+/// Midpoint of the category's range, used to measure how far apart two categories are
+pub fn severity(behavior: &str) -> Option<f64> {
+    get_behavior_range(behavior).map(|(min, max)| f64::from(min + max) / 2.0)
+}
+
 /// Get behavior range by name (used in calculate_changes)
 pub fn get_behavior_range(behavior: &str) -> Option<(i32, i32)> {
     // Check positive behaviors first
@@ -46,6 +84,7 @@ pub fn get_behavior_range(behavior: &str) -> Option<(i32, i32)> {
 }
 
 /// Determine behavior type from a value (used in parse_behavior_from_response)
+#[allow(dead_code)]
 pub fn get_behavior_from_value(value: i32) -> Option<&'static str> {
     // Check positive behaviors first
     for (name, (min, max)) in positive_behaviors::RANGES.iter() {
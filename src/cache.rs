@@ -0,0 +1,425 @@
+// Cache of behavior classifications for inputs that recur, such as "hi" or "thank you"
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::warn;
+
+use crate::analysis::Classification;
+use crate::{metrics, persist, system_prompt};
+
+/// Where cached classifications are kept
+#[derive(Debug, Clone, PartialEq)]
+pub enum CacheBackend {
+    Off,
+    Memory,
+    /// One file per entry in this directory, kept across restarts
+    Disk(PathBuf),
+}
+
+/// Validated by [`crate::config::Config::load`]
+#[derive(Debug, Clone)]
+pub struct CacheSettings {
+    pub backend: CacheBackend,
+    pub ttl: Duration,
+    pub max_entries: usize,
+    /// Trailing lines of the character history that are part of the key
+    pub context_lines: usize,
+}
+
+/// Server settings that change how a provider classification comes about
+pub struct PipelineParts<'a> {
+    pub provider_url: &'a str,
+    pub provider_logprobs: bool,
+    /// [`crate::ensemble::Ensemble::fingerprint`]
+    pub ensemble: &'a str,
+    pub classifier: &'a str,
+}
+
+/// Everything the classification depends on
+pub struct CacheKeyParts<'a> {
+    pub tenant: &'a str,
+    pub model: &'a str,
+    pub character_description: &'a str,
+    pub emotion_tier: &'a str,
+    pub relationship_tier: &'a str,
+    pub history: &'a str,
+    pub user_input: &'a str,
+}
+
+/// Case and whitespace do not change the classification
+fn normalize(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Layout of a cached classification; bumped when it changes so old entries miss
+const ENTRY_FORMAT: &str = "2";
+
+/// Version of the classification prompt; entries from another prompt are never used
+static PROMPT_VERSION: LazyLock<String> =
+    LazyLock::new(|| format!("{:x}", Sha256::digest(system_prompt::SYSTEM_PROMPT.as_bytes())));
+
+/// Disk entries are named after their key; other files in the directory are left alone
+fn is_entry_name(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+}
+
+struct MemoryEntry {
+    classification: Classification,
+    expires: Instant,
+}
+
+/// Entries plus their insertion order, oldest first, for eviction
+#[derive(Default)]
+struct MemoryStore {
+    entries: HashMap<String, MemoryEntry>,
+    order: VecDeque<String>,
+}
+
+/// Disk entry, one JSON file named after the key
+#[derive(Serialize, Deserialize)]
+struct DiskEntry {
+    #[serde(flatten)]
+    classification: Classification,
+    /// Unix seconds after which the entry is stale
+    expires_at: u64,
+}
+
+enum Store {
+    Off,
+    Memory(Mutex<MemoryStore>),
+    Disk { dir: PathBuf, entries: AtomicUsize },
+}
+
+pub struct ClassificationCache {
+    settings: CacheSettings,
+    /// Hash of the [`PipelineParts`]; entries from another pipeline never match
+    pipeline: String,
+    store: Store,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+impl ClassificationCache {
+    pub fn open(settings: CacheSettings, pipeline: &PipelineParts) -> anyhow::Result<Self> {
+        let mut hasher = Sha256::new();
+        for part in [
+            pipeline.provider_url,
+            if pipeline.provider_logprobs { "logprobs" } else { "no-logprobs" },
+            pipeline.ensemble,
+            pipeline.classifier,
+        ] {
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        let pipeline = format!("{:x}", hasher.finalize());
+
+        let store = match &settings.backend {
+            CacheBackend::Off => Store::Off,
+            CacheBackend::Memory => Store::Memory(Mutex::new(MemoryStore::default())),
+            CacheBackend::Disk(dir) => {
+                std::fs::create_dir_all(dir)?;
+                let mut entries = 0;
+                for file in std::fs::read_dir(dir)? {
+                    if file?.file_name().to_str().is_some_and(is_entry_name) {
+                        entries += 1;
+                    }
+                }
+                Store::Disk {
+                    dir: dir.clone(),
+                    entries: AtomicUsize::new(entries),
+                }
+            }
+        };
+        Ok(ClassificationCache {
+            settings,
+            pipeline,
+            store,
+        })
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        match self.store {
+            Store::Off => "off",
+            Store::Memory(_) => "memory",
+            Store::Disk { .. } => "disk",
+        }
+    }
+
+    /// Hex SHA-256 over the normalized inputs, the recent history, the prompt version
+    /// and the pipeline
+    pub fn key(&self, parts: &CacheKeyParts) -> String {
+        let lines: Vec<&str> = parts.history.lines().filter(|l| !l.trim().is_empty()).collect();
+        let context = &lines[lines.len().saturating_sub(self.settings.context_lines)..];
+
+        let mut hasher = Sha256::new();
+        for part in [
+            ENTRY_FORMAT,
+            PROMPT_VERSION.as_str(),
+            &self.pipeline,
+            parts.tenant,
+            parts.model,
+            &normalize(parts.character_description),
+            parts.emotion_tier,
+            parts.relationship_tier,
+            &normalize(&context.join("\n")),
+            &normalize(parts.user_input),
+        ] {
+            // Length prefixes keep ("ab", "c") and ("a", "bc") apart
+            hasher.update((part.len() as u64).to_le_bytes());
+            hasher.update(part.as_bytes());
+        }
+        format!("{:x}", hasher.finalize())
+    }
+
+    /// Cached classification for `key`, if present and fresh
+    pub async fn get(&self, key: &str) -> Option<Classification> {
+        let classification = match &self.store {
+            Store::Off => return None,
+            Store::Memory(store) => {
+                let store = store.lock().unwrap();
+                store
+                    .entries
+                    .get(key)
+                    .filter(|entry| entry.expires > Instant::now())
+                    .map(|entry| entry.classification.clone())
+            }
+            Store::Disk { dir, .. } => tokio::fs::read(dir.join(key))
+                .await
+                .ok()
+                .and_then(|data| serde_json::from_slice::<DiskEntry>(&data).ok())
+                .filter(|entry| entry.expires_at > unix_now())
+                .map(|entry| entry.classification),
+        };
+        metrics::record_cache_lookup(classification.is_some());
+        classification
+    }
+
+    /// Store a classification, unless it is local or a fallback (including a vote won
+    /// by fallbacks, at confidence 0). Beyond the size limit, expired entries are
+    /// dropped first, then the oldest, down to 90% of the limit.
+    pub async fn put(&self, key: &str, classification: &Classification) {
+        if !classification.confidence_source.is_cacheable() || classification.confidence <= 0.0 {
+            return;
+        }
+        match &self.store {
+            Store::Off => {}
+            Store::Memory(store) => {
+                let mut store = store.lock().unwrap();
+                let entry = MemoryEntry {
+                    classification: classification.clone(),
+                    expires: Instant::now() + self.settings.ttl,
+                };
+                if store.entries.insert(key.to_string(), entry).is_none() {
+                    store.order.push_back(key.to_string());
+                }
+                if store.entries.len() > self.settings.max_entries {
+                    let now = Instant::now();
+                    let MemoryStore { entries, order } = &mut *store;
+                    entries.retain(|_, entry| entry.expires > now);
+                    order.retain(|key| entries.contains_key(key));
+
+                    let target = (self.settings.max_entries * 9 / 10).max(1);
+                    while entries.len() > target {
+                        let Some(oldest) = order.pop_front() else { break };
+                        entries.remove(&oldest);
+                    }
+                }
+            }
+            Store::Disk { dir, entries } => {
+                let entry = DiskEntry {
+                    classification: classification.clone(),
+                    expires_at: unix_now() + self.settings.ttl.as_secs(),
+                };
+                let path = dir.join(key);
+                let existed = tokio::fs::try_exists(&path).await.unwrap_or(false);
+                let data = serde_json::to_vec(&entry).unwrap_or_default();
+                // A crash mid-write leaves a temporary file, never a torn entry
+                let write = {
+                    let path = path.clone();
+                    tokio::task::spawn_blocking(move || persist::write_atomically(&path, &data)).await
+                };
+                if let Err(e) = write.map_err(std::io::Error::other).and_then(|result| result) {
+                    warn!(error = %e, "⚠️ failed to write classification cache entry");
+                    return;
+                }
+                if !existed && entries.fetch_add(1, Ordering::Relaxed) + 1 > self.settings.max_entries {
+                    let remaining = prune_disk(dir, self.settings.max_entries).await;
+                    entries.store(remaining, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+}
+
+/// Delete expired entries, then the least recently written ones, until the
+/// directory is back to 90% of the limit. Only files named like a key are
+/// touched. Returns how many entries remain.
+async fn prune_disk(dir: &Path, max_entries: usize) -> usize {
+    let mut files = Vec::new();
+    let Ok(mut listing) = tokio::fs::read_dir(dir).await else {
+        return 0;
+    };
+    while let Ok(Some(file)) = listing.next_entry().await {
+        if !file.file_name().to_str().is_some_and(is_entry_name) {
+            continue;
+        }
+        let modified = file
+            .metadata()
+            .await
+            .and_then(|m| m.modified())
+            .unwrap_or(UNIX_EPOCH);
+        files.push((modified, file.path()));
+    }
+    files.sort();
+
+    let now = unix_now();
+    let target = max_entries * 9 / 10;
+    let mut remaining = files.len();
+    for (_, path) in files {
+        let expired = tokio::fs::read(&path)
+            .await
+            .ok()
+            .and_then(|data| serde_json::from_slice::<DiskEntry>(&data).ok())
+            .is_none_or(|entry| entry.expires_at <= now);
+        if (expired || remaining > target) && tokio::fs::remove_file(&path).await.is_ok() {
+            remaining -= 1;
+        }
+    }
+    remaining
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::confidence::ConfidenceSource;
+
+    const PIPELINE: PipelineParts = PipelineParts {
+        provider_url: "https://api.x.ai/v1/chat/completions",
+        provider_logprobs: false,
+        ensemble: "",
+        classifier: "provider",
+    };
+
+    fn settings(backend: CacheBackend, ttl: Duration) -> CacheSettings {
+        CacheSettings {
+            backend,
+            ttl,
+            max_entries: 100,
+            context_lines: 2,
+        }
+    }
+
+    fn parts<'a>(user_input: &'a str, history: &'a str) -> CacheKeyParts<'a> {
+        CacheKeyParts {
+            tenant: "default",
+            model: "grok",
+            character_description: "A cheerful baker",
+            emotion_tier: "Neutral",
+            relationship_tier: "Acquaintance",
+            history,
+            user_input,
+        }
+    }
+
+    fn classification(confidence_source: ConfidenceSource, confidence: f32) -> Classification {
+        Classification {
+            category: "Moderate Positive Behavior".to_string(),
+            confidence,
+            confidence_source,
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cache-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn key_covers_inputs_history_and_pipeline() {
+        let cache = ClassificationCache::open(settings(CacheBackend::Memory, Duration::from_secs(60)), &PIPELINE).unwrap();
+        let key = cache.key(&parts("Thank you!", "a\nb\nc"));
+
+        // Case, whitespace and history older than context_lines do not matter
+        assert_eq!(key, cache.key(&parts("  thank   YOU! ", "a\nb\nc")));
+        assert_eq!(key, cache.key(&parts("Thank you!", "x\nb\n\nc")));
+        assert_ne!(key, cache.key(&parts("Thank you!", "a\nb\nd")));
+        assert_ne!(key, cache.key(&parts("Thanks!", "a\nb\nc")));
+        assert_ne!(key, cache.key(&CacheKeyParts { tenant: "other", ..parts("Thank you!", "a\nb\nc") }));
+        assert_ne!(key, cache.key(&CacheKeyParts { model: "grok-mini", ..parts("Thank you!", "a\nb\nc") }));
+        assert_ne!(key, cache.key(&CacheKeyParts { emotion_tier: "Happy", ..parts("Thank you!", "a\nb\nc") }));
+
+        let settings = || settings(CacheBackend::Memory, Duration::from_secs(60));
+        for pipeline in [
+            PipelineParts { provider_url: "http://localhost:8080/v1/chat/completions", ..PIPELINE },
+            PipelineParts { provider_logprobs: true, ..PIPELINE },
+            PipelineParts { ensemble: "Weighted:None/None/1/3", ..PIPELINE },
+            PipelineParts { classifier: "lexicon", ..PIPELINE },
+        ] {
+            let other = ClassificationCache::open(settings(), &pipeline).unwrap();
+            assert_ne!(key, other.key(&parts("Thank you!", "a\nb\nc")));
+        }
+        let same = ClassificationCache::open(settings(), &PIPELINE).unwrap();
+        assert_eq!(key, same.key(&parts("Thank you!", "a\nb\nc")));
+    }
+
+    #[tokio::test]
+    async fn memory_entries_expire_after_the_ttl() {
+        let cache = ClassificationCache::open(settings(CacheBackend::Memory, Duration::from_millis(50)), &PIPELINE).unwrap();
+        let key = cache.key(&parts("hi", ""));
+        cache.put(&key, &classification(ConfidenceSource::Exact, 1.0)).await;
+        assert_eq!(cache.get(&key).await.unwrap().category, "Moderate Positive Behavior");
+
+        tokio::time::sleep(Duration::from_millis(80)).await;
+        assert!(cache.get(&key).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn disk_entries_expire_after_the_ttl() {
+        let dir = temp_dir("expiry");
+        let fresh = ClassificationCache::open(settings(CacheBackend::Disk(dir.clone()), Duration::from_secs(60)), &PIPELINE).unwrap();
+        let key = fresh.key(&parts("hi", ""));
+        fresh.put(&key, &classification(ConfidenceSource::Exact, 1.0)).await;
+        assert!(fresh.get(&key).await.is_some());
+
+        // Written atomically: only the entry itself is left in the directory
+        let names: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|f| f.unwrap().file_name()).collect();
+        assert_eq!(names, vec![std::ffi::OsString::from(&key)]);
+
+        let stale = ClassificationCache::open(settings(CacheBackend::Disk(dir.clone()), Duration::ZERO), &PIPELINE).unwrap();
+        stale.put(&key, &classification(ConfidenceSource::Exact, 1.0)).await;
+        assert!(stale.get(&key).await.is_none());
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn fallback_and_local_results_are_not_cached() {
+        let dir = temp_dir("skip");
+        for backend in [CacheBackend::Memory, CacheBackend::Disk(dir.clone())] {
+            let cache = ClassificationCache::open(settings(backend, Duration::from_secs(60)), &PIPELINE).unwrap();
+            for (source, confidence) in [
+                (ConfidenceSource::Fallback, 0.0),
+                (ConfidenceSource::Votes, 0.0),
+                (ConfidenceSource::Lexicon, 0.8),
+                (ConfidenceSource::Model, 0.8),
+                (ConfidenceSource::EmptyInput, 1.0),
+            ] {
+                let key = cache.key(&parts(&format!("{:?} {}", source, confidence), ""));
+                cache.put(&key, &classification(source, confidence)).await;
+                assert!(cache.get(&key).await.is_none(), "{:?} at {} was cached", source, confidence);
+            }
+            let key = cache.key(&parts("heuristic", ""));
+            cache.put(&key, &classification(ConfidenceSource::Heuristic, 0.7)).await;
+            assert!(cache.get(&key).await.is_some());
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
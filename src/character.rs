@@ -0,0 +1,199 @@
+// Character profile definitions for emotion analysis
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::validation::{FieldError, Validator};
+
+/// Maximum length of the character name
+pub const MAX_NAME_LEN: usize = 64;
+/// Maximum length of any free-text profile field (personality, backstory, speaking style)
+pub const MAX_TEXT_LEN: usize = 4000;
+/// Maximum number of entries in likes / dislikes / boundaries
+pub const MAX_LIST_ENTRIES: usize = 32;
+/// Maximum length of a single likes / dislikes / boundaries entry
+pub const MAX_LIST_ENTRY_LEN: usize = 200;
+
+/// Explicit description of the character the user is interacting with
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct CharacterProfile {
+    pub name: String,
+    pub personality: String,
+    #[serde(default)]
+    pub backstory: Option<String>,
+    #[serde(default)]
+    pub speaking_style: Option<String>,
+    #[serde(default)]
+    pub likes: Vec<String>,
+    #[serde(default)]
+    pub dislikes: Vec<String>,
+    #[serde(default)]
+    pub boundaries: Vec<String>,
+}
+
+impl CharacterProfile {
+    /// Build a profile from the legacy `character_personality` field
+    pub fn from_personality(personality: &str) -> Self {
+        CharacterProfile {
+            name: "Character".to_string(),
+            personality: personality.to_string(),
+            backstory: None,
+            speaking_style: None,
+            likes: Vec::new(),
+            dislikes: Vec::new(),
+            boundaries: Vec::new(),
+        }
+    }
+
+    /// Validate the profile, returning every problem found
+    pub fn validate(&self) -> Result<(), Vec<FieldError>> {
+        let mut validator = Validator::new();
+        self.check(&mut validator);
+        validator.finish()
+    }
+
+    /// Add the profile's field errors to `validator`
+    pub fn check(&self, validator: &mut Validator) {
+        validator
+            .not_blank("character.name", &self.name)
+            .max_chars("character.name", self.name.trim(), MAX_NAME_LEN)
+            .not_blank("character.personality", &self.personality);
+
+        let text_fields = [
+            ("character.personality", Some(&self.personality)),
+            ("character.backstory", self.backstory.as_ref()),
+            ("character.speaking_style", self.speaking_style.as_ref()),
+        ];
+        for (field, value) in text_fields {
+            if let Some(value) = value {
+                validator.max_chars(field, value, MAX_TEXT_LEN);
+            }
+        }
+
+        let list_fields = [
+            ("likes", &self.likes),
+            ("dislikes", &self.dislikes),
+            ("boundaries", &self.boundaries),
+        ];
+        for (field, entries) in list_fields {
+            validator.max_entries(&format!("character.{}", field), entries, MAX_LIST_ENTRIES);
+            for (i, entry) in entries.iter().enumerate() {
+                let field = format!("character.{}[{}]", field, i);
+                validator
+                    .not_blank(&field, entry)
+                    .max_chars(&field, entry, MAX_LIST_ENTRY_LEN);
+            }
+        }
+    }
+
+    /// Render the profile as the character description section of the prompt
+    pub fn render_description(&self) -> String {
+        let name = self.name.trim();
+        let mut lines = vec![
+            format!("Character Name: {}", name),
+            format!("{}'s Personality = {}", name, self.personality.trim()),
+        ];
+
+        if let Some(backstory) = self.backstory.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+            lines.push(format!("{}'s Backstory = {}", name, backstory));
+        }
+        if let Some(style) = self.speaking_style.as_deref().map(str::trim).filter(|s| !s.is_empty()) {
+            lines.push(format!("{}'s Speaking Style = {}", name, style));
+        }
+        if !self.likes.is_empty() {
+            lines.push(format!("{}'s Likes = {}", name, self.likes.join(", ")));
+        }
+        if !self.dislikes.is_empty() {
+            lines.push(format!("{}'s Dislikes = {}", name, self.dislikes.join(", ")));
+        }
+        if !self.boundaries.is_empty() {
+            lines.push(format!("{}'s Boundaries = {}", name, self.boundaries.join(", ")));
+        }
+
+        lines.join("\n")
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> CharacterProfile {
+        CharacterProfile {
+            name: " Mira ".to_string(),
+            personality: "Warm and curious ".to_string(),
+            backstory: Some("Runs a bakery".to_string()),
+            speaking_style: Some("   ".to_string()),
+            likes: vec!["bread".to_string(), "rain".to_string()],
+            dislikes: Vec::new(),
+            boundaries: vec!["no politics".to_string()],
+        }
+    }
+
+    fn fields(profile: &CharacterProfile) -> Vec<String> {
+        profile
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|e| e.field)
+            .collect()
+    }
+
+    #[test]
+    fn valid_profile_passes() {
+        assert!(profile().validate().is_ok());
+        assert!(CharacterProfile::from_personality("Shy").validate().is_ok());
+    }
+
+    #[test]
+    fn every_problem_is_reported() {
+        let mut invalid = profile();
+        invalid.name = "  ".to_string();
+        invalid.personality = String::new();
+        invalid.backstory = Some("x".repeat(MAX_TEXT_LEN + 1));
+        invalid.likes = vec!["bread".to_string(), " ".to_string()];
+        invalid.dislikes = vec!["x".repeat(MAX_LIST_ENTRY_LEN + 1)];
+        invalid.boundaries = vec!["b".to_string(); MAX_LIST_ENTRIES + 1];
+
+        assert_eq!(
+            fields(&invalid),
+            [
+                "character.name",
+                "character.personality",
+                "character.backstory",
+                "character.likes[1]",
+                "character.dislikes[0]",
+                "character.boundaries",
+            ]
+        );
+    }
+
+    #[test]
+    fn limits_are_inclusive() {
+        let mut at_limit = profile();
+        at_limit.name = "n".repeat(MAX_NAME_LEN);
+        at_limit.personality = "p".repeat(MAX_TEXT_LEN);
+        at_limit.likes = vec!["l".repeat(MAX_LIST_ENTRY_LEN); MAX_LIST_ENTRIES];
+        assert!(at_limit.validate().is_ok());
+
+        at_limit.name.push('n');
+        assert_eq!(fields(&at_limit), ["character.name"]);
+    }
+
+    #[test]
+    fn description_lists_the_filled_in_fields() {
+        assert_eq!(
+            profile().render_description(),
+            "Character Name: Mira\n\
+             Mira's Personality = Warm and curious\n\
+             Mira's Backstory = Runs a bakery\n\
+             Mira's Likes = bread, rain\n\
+             Mira's Boundaries = no politics"
+        );
+        assert_eq!(
+            CharacterProfile::from_personality("Shy").render_description(),
+            "Character Name: Character\nCharacter's Personality = Shy"
+        );
+    }
+}
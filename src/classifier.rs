@@ -0,0 +1,134 @@
+// Choice of classifier backend: the provider, or a local classifier as primary, fallback or pre-filter
+
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::analysis::Classification;
+use crate::bayes::NaiveBayes;
+use crate::lexicon::Lexicon;
+
+/// Classifiers that run in-process, without network access
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LocalBackend {
+    Lexicon,
+    /// Naive Bayes model trained with the `train` subcommand
+    Model,
+}
+
+/// Where classifications come from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    /// The chat completions provider, or the ensemble when one is configured
+    Provider,
+    Local(LocalBackend),
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "provider" => Ok(Backend::Provider),
+            "lexicon" => Ok(Backend::Local(LocalBackend::Lexicon)),
+            "model" => Ok(Backend::Local(LocalBackend::Model)),
+            other => Err(format!("unknown classifier backend '{}' (expected provider, lexicon or model)", other)),
+        }
+    }
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backend::Provider => f.write_str("provider"),
+            Backend::Local(LocalBackend::Lexicon) => f.write_str("lexicon"),
+            Backend::Local(LocalBackend::Model) => f.write_str("model"),
+        }
+    }
+}
+
+/// Confidence a pre-filter classification needs to be used without asking the primary
+pub const DEFAULT_PREFILTER_MIN_CONFIDENCE: f32 = 0.8;
+
+/// Validated by [`crate::config::Config::load`]
+#[derive(Debug, Clone)]
+pub struct ClassifierSettings {
+    pub primary: Backend,
+    pub fallback: Option<LocalBackend>,
+    pub prefilter: Option<LocalBackend>,
+    pub prefilter_min_confidence: f32,
+    /// Set whenever a role uses the model backend
+    pub model_path: Option<PathBuf>,
+    /// Built-in lexicon tables only when unset
+    pub lexicon_path: Option<PathBuf>,
+}
+
+impl ClassifierSettings {
+    /// Some role runs the trained model
+    pub fn uses_model(&self) -> bool {
+        self.primary == Backend::Local(LocalBackend::Model)
+            || self.fallback == Some(LocalBackend::Model)
+            || self.prefilter == Some(LocalBackend::Model)
+    }
+}
+
+pub struct Classifier {
+    pub primary: Backend,
+    /// Used when the provider fails
+    pub fallback: Option<LocalBackend>,
+    /// Tried first; a confident answer skips the primary
+    pub prefilter: Option<LocalBackend>,
+    pub prefilter_min_confidence: f32,
+    lexicon: Lexicon,
+    /// Loaded when a role uses the model backend
+    model: Option<NaiveBayes>,
+}
+
+impl Classifier {
+    /// Load the lexicon, and the model when a role uses it
+    pub fn load(settings: &ClassifierSettings) -> anyhow::Result<Self> {
+        let model = match &settings.model_path {
+            Some(path) if settings.uses_model() => {
+                Some(NaiveBayes::open(path).map_err(|e| anyhow::anyhow!("model: {}", e))?)
+            }
+            _ => None,
+        };
+
+        Ok(Classifier {
+            primary: settings.primary,
+            fallback: settings.fallback,
+            prefilter: settings.prefilter,
+            prefilter_min_confidence: settings.prefilter_min_confidence,
+            lexicon: Lexicon::load(settings.lexicon_path.as_deref()).map_err(|e| anyhow::anyhow!("lexicon: {}", e))?,
+            model,
+        })
+    }
+
+    /// Nothing can be classified while the provider is down
+    pub fn requires_provider(&self) -> bool {
+        self.primary == Backend::Provider && self.fallback.is_none()
+    }
+
+    pub fn lexicon(&self) -> &Lexicon {
+        &self.lexicon
+    }
+
+    pub fn model(&self) -> Option<&NaiveBayes> {
+        self.model.as_ref()
+    }
+
+    /// Classify with a local backend; `history` is the character history before the input
+    pub fn local(&self, backend: LocalBackend, user_input: &str, history: &str) -> Classification {
+        match (backend, &self.model) {
+            (LocalBackend::Model, Some(model)) => model.classify(user_input, history),
+            // load opens the model whenever a role uses it
+            (LocalBackend::Model, None) | (LocalBackend::Lexicon, _) => self.lexicon.classify(user_input),
+        }
+    }
+
+    /// The pre-filter's classification, if one is configured and confident enough
+    pub fn prefilter(&self, user_input: &str, history: &str) -> Option<Classification> {
+        let classification = self.local(self.prefilter?, user_input, history);
+        (classification.confidence >= self.prefilter_min_confidence).then_some(classification)
+    }
+}
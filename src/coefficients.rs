@@ -6,7 +6,6 @@
 ///
 /// - Positive behaviors (≥ 0) use the left coefficient
 /// - Negative behaviors (< 0) use the right coefficient
-
 // Relationship Coefficients - How relationships affect behavior intensity
 pub const RELATIONSHIP_COEFFICIENTS: &[(&str, (f32, f32))] = &[
     ("Romantic Partner", (2.0, 0.2)),      // Strong positive amplification, weak negative
@@ -75,6 +74,7 @@ pub fn get_emotion_coefficient(emotion: &str) -> Option<BehaviorCoefficients> {
 }
 
 /// Legacy function for backward compatibility - returns tuple
+#[allow(dead_code)]
 pub fn get_relationship_coefficient_tuple(relationship: &str) -> Option<(f32, f32)> {
     RELATIONSHIP_COEFFICIENTS.iter()
         .find(|(name, _)| *name == relationship)
@@ -82,6 +82,7 @@ pub fn get_relationship_coefficient_tuple(relationship: &str) -> Option<(f32, f3
 }
 
 /// Legacy function for backward compatibility - returns tuple
+#[allow(dead_code)]
 pub fn get_emotion_coefficient_tuple(emotion: &str) -> Option<(f32, f32)> {
     EMOTION_COEFFICIENTS.iter()
         .find(|(name, _)| *name == emotion)
@@ -89,11 +90,13 @@ pub fn get_emotion_coefficient_tuple(emotion: &str) -> Option<(f32, f32)> {
 }
 
 /// Get all available relationship types
+#[allow(dead_code)]
 pub fn get_relationship_types() -> Vec<&'static str> {
     RELATIONSHIP_COEFFICIENTS.iter().map(|(name, _)| *name).collect()
 }
 
 /// Get all available emotion types
+#[allow(dead_code)]
 pub fn get_emotion_types() -> Vec<&'static str> {
     EMOTION_COEFFICIENTS.iter().map(|(name, _)| *name).collect()
 }
\ No newline at end of file
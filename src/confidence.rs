@@ -0,0 +1,226 @@
+// Classification confidence and the per-profile policy for low-confidence results
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::info;
+use utoipa::ToSchema;
+
+use crate::{behavior, metrics};
+
+/// Where a classification's confidence comes from
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum ConfidenceSource {
+    /// Share of ensemble votes for the category, scaled by the winning samples' confidence
+    Votes,
+    /// Probability of the provider's reply tokens
+    Logprobs,
+    /// The reply named a category exactly; the provider gave no probabilities
+    Exact,
+    /// The category was inferred from a partial match in the reply
+    Heuristic,
+    /// The reply named no category and Neutral Behavior was assumed; confidence is 0
+    Fallback,
+    /// Blank input answered as Neutral Behavior without calling the provider
+    EmptyInput,
+    /// Share of the rule-based lexicon's cues that agree on the category
+    Lexicon,
+    /// Posterior probability from the trained local model
+    Model,
+}
+
+impl ConfidenceSource {
+    /// Produced in-process; cheap to recompute, so never cached
+    pub fn is_local(self) -> bool {
+        matches!(self, ConfidenceSource::Lexicon | ConfidenceSource::Model | ConfidenceSource::EmptyInput)
+    }
+
+    /// Worth caching: a provider classification that named a category. A fallback
+    /// neutral is left out, so a bad reply is not repeated for the whole TTL.
+    pub fn is_cacheable(self) -> bool {
+        !self.is_local() && self != ConfidenceSource::Fallback
+    }
+}
+
+/// Confidence given to a heuristic match, and the factor applied to its probability
+pub const HEURISTIC_CONFIDENCE: f32 = 0.5;
+
+/// What happens to a classification below the profile's threshold
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LowConfidenceAction {
+    /// Use the next less intense category of the same kind
+    Downgrade,
+    /// Use Neutral Behavior
+    Neutral,
+    /// Keep the category and flag the result as needing review
+    Review,
+}
+
+impl LowConfidenceAction {
+    fn label(self) -> &'static str {
+        match self {
+            LowConfidenceAction::Downgrade => "downgrade",
+            LowConfidenceAction::Neutral => "neutral",
+            LowConfidenceAction::Review => "review",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfidencePolicy {
+    /// Classifications below this confidence, 0 to 1, are low confidence
+    pub min_confidence: f32,
+    pub action: LowConfidenceAction,
+}
+
+/// Category to use for a request, after the policy
+pub struct Decision {
+    pub category: String,
+    pub needs_review: bool,
+    /// Category the provider chose, when the policy replaced it
+    pub adjusted_from: Option<String>,
+}
+
+/// Policies by balance profile; profiles without one use `default`, if set
+#[derive(Debug, Clone, Default)]
+pub struct ConfidencePolicies {
+    profiles: HashMap<String, ConfidencePolicy>,
+}
+
+impl ConfidencePolicies {
+    /// Read the JSON file at `path`; without one, no policy applies
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        match path {
+            Some(path) => Self::open(path),
+            None => Ok(Self::default()),
+        }
+    }
+
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let profiles: HashMap<String, ConfidencePolicy> = serde_json::from_str(&data)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        for (profile, policy) in &profiles {
+            if !(0.0..=1.0).contains(&policy.min_confidence) {
+                anyhow::bail!("{}: min_confidence must be between 0 and 1", profile);
+            }
+        }
+        Ok(ConfidencePolicies { profiles })
+    }
+
+    pub fn profiles(&self) -> usize {
+        self.profiles.len()
+    }
+
+    /// Apply the profile's policy to a classification
+    pub fn decide(&self, profile: &str, category: &str, confidence: f32) -> Decision {
+        let policy = self.profiles.get(profile).or_else(|| self.profiles.get("default"));
+        let Some(policy) = policy.filter(|policy| confidence < policy.min_confidence) else {
+            return Decision {
+                category: category.to_string(),
+                needs_review: false,
+                adjusted_from: None,
+            };
+        };
+
+        metrics::record_low_confidence(policy.action.label());
+        let adjusted = match policy.action {
+            LowConfidenceAction::Downgrade => behavior::less_intense(category),
+            LowConfidenceAction::Neutral => "Neutral Behavior",
+            LowConfidenceAction::Review => category,
+        };
+        info!(
+            profile,
+            category,
+            confidence,
+            min_confidence = policy.min_confidence,
+            action = policy.action.label(),
+            adjusted,
+            "🤔 low-confidence classification",
+        );
+        Decision {
+            category: adjusted.to_string(),
+            needs_review: policy.action == LowConfidenceAction::Review,
+            adjusted_from: (adjusted != category).then(|| category.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policies() -> ConfidencePolicies {
+        let policy = |min_confidence, action| ConfidencePolicy { min_confidence, action };
+        ConfidencePolicies {
+            profiles: HashMap::from([
+                ("default".to_string(), policy(0.6, LowConfidenceAction::Downgrade)),
+                ("strict".to_string(), policy(0.8, LowConfidenceAction::Neutral)),
+                ("careful".to_string(), policy(0.5, LowConfidenceAction::Review)),
+            ]),
+        }
+    }
+
+    #[test]
+    fn decide_applies_the_profile_action_below_its_threshold() {
+        // Profile, category, confidence; then category, needs_review, adjusted_from
+        let cases = [
+            ("default", "StrongNegativeBehavior", 0.59, "ModerateNegativeBehavior", false, true),
+            ("default", "StrongNegativeBehavior", 0.6, "StrongNegativeBehavior", false, false),
+            ("default", "LightPositiveBehavior", 0.0, "Neutral Behavior", false, true),
+            ("default", "Neutral Behavior", 0.1, "Neutral Behavior", false, false),
+            ("default", "Sexual_Light", 0.1, "Sexual_Neutral", false, true),
+            ("strict", "ExtremePositiveBehavior", 0.79, "Neutral Behavior", false, true),
+            ("strict", "Sexual_Strong", 0.5, "Neutral Behavior", false, true),
+            ("strict", "ExtremePositiveBehavior", 0.8, "ExtremePositiveBehavior", false, false),
+            ("careful", "ModerateNegativeBehavior", 0.49, "ModerateNegativeBehavior", true, false),
+            ("careful", "ModerateNegativeBehavior", 0.5, "ModerateNegativeBehavior", false, false),
+            // Profiles without a policy use the default one
+            ("unlisted", "ExtremeNegativeBehavior", 0.3, "StrongNegativeBehavior", false, true),
+        ];
+        let policies = policies();
+        for (profile, category, confidence, expected, needs_review, adjusted) in cases {
+            let decision = policies.decide(profile, category, confidence);
+            let case = format!("{} {} at {}", profile, category, confidence);
+            assert_eq!(decision.category, expected, "{}", case);
+            assert_eq!(decision.needs_review, needs_review, "{}", case);
+            assert_eq!(decision.adjusted_from.as_deref(), adjusted.then_some(category), "{}", case);
+        }
+    }
+
+    #[test]
+    fn without_policies_nothing_changes() {
+        let decision = ConfidencePolicies::default().decide("default", "StrongNegativeBehavior", 0.0);
+        assert_eq!(decision.category, "StrongNegativeBehavior");
+        assert!(!decision.needs_review && decision.adjusted_from.is_none());
+    }
+
+    #[test]
+    fn less_intense_steps_toward_neutral() {
+        let cases = [
+            ("ExtremePositiveBehavior", "StrongPositiveBehavior"),
+            ("StrongPositiveBehavior", "ModeratePositiveBehavior"),
+            ("ModeratePositiveBehavior", "LightPositiveBehavior"),
+            ("LightPositiveBehavior", "Neutral Behavior"),
+            ("ExtremeNegativeBehavior", "StrongNegativeBehavior"),
+            ("StrongNegativeBehavior", "ModerateNegativeBehavior"),
+            ("ModerateNegativeBehavior", "LightNegativeBehavior"),
+            ("LightNegativeBehavior", "Neutral Behavior"),
+            ("Sexual_Extreme", "Sexual_Strong"),
+            ("Sexual_Light", "Sexual_Neutral"),
+            ("Sexual_Neg_Strong", "Sexual_Neg_Moderate"),
+            ("Sexual_Neg_Light", "Sexual_Neutral"),
+            ("Neutral Behavior", "Neutral Behavior"),
+            ("Sexual_Neutral", "Sexual_Neutral"),
+            ("Unknown", "Unknown"),
+        ];
+        for (category, expected) in cases {
+            assert_eq!(behavior::less_intense(category), expected, "{}", category);
+        }
+    }
+}
@@ -0,0 +1,923 @@
+// Server configuration from a TOML file, environment variables and command-line flags
+
+use std::env;
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use axum::http::HeaderValue;
+use clap::{Args, Parser, Subcommand};
+use serde::Deserialize;
+
+use crate::classifier::{self, Backend, ClassifierSettings};
+use crate::batch::BatchSettings;
+use crate::cache::{CacheBackend, CacheSettings};
+use crate::health::ReadinessSettings;
+use crate::provider;
+use crate::ratelimit::RateLimitSettings;
+use crate::tls::TlsPaths;
+use crate::validation::ValidationSettings;
+
+/// Command-line flags; each one overrides the environment and the config file
+#[derive(Parser, Debug, Default)]
+#[command(version, about)]
+pub struct Cli {
+    /// Runs the server when omitted
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// TOML config file (env: CONFIG_PATH)
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Address the HTTP and gRPC servers listen on
+    #[arg(long)]
+    pub bind_address: Option<String>,
+    /// HTTP port
+    #[arg(long)]
+    pub port: Option<u16>,
+    #[arg(long)]
+    pub grpc_port: Option<u16>,
+    /// Allowed CORS origins, comma separated; `*` allows any origin
+    #[arg(long, value_delimiter = ',')]
+    pub cors_origins: Option<Vec<String>>,
+    /// Largest accepted request body, in bytes
+    #[arg(long)]
+    pub body_limit_bytes: Option<usize>,
+    /// Chat completions endpoint of the provider
+    #[arg(long)]
+    pub provider_url: Option<String>,
+    #[arg(long)]
+    pub model: Option<String>,
+    /// Ask the provider for token log probabilities, used as classification confidence
+    #[arg(long)]
+    pub provider_logprobs: Option<bool>,
+    #[arg(long)]
+    pub character_registry_path: Option<PathBuf>,
+    #[arg(long)]
+    pub session_store_path: Option<PathBuf>,
+    #[arg(long)]
+    pub api_keys_path: Option<PathBuf>,
+    /// Seconds in-flight requests get to finish after SIGTERM/SIGINT
+    #[arg(long)]
+    pub shutdown_timeout_secs: Option<u64>,
+    /// PEM certificate chain; with --tls-key-path, serves HTTPS
+    #[arg(long)]
+    pub tls_cert_path: Option<PathBuf>,
+    /// PEM private key
+    #[arg(long)]
+    pub tls_key_path: Option<PathBuf>,
+    /// Seconds between writes of changed sessions to the session store
+    #[arg(long)]
+    pub session_flush_interval_secs: Option<u64>,
+    /// Seconds a session may sit idle before it is dropped
+    #[arg(long)]
+    pub session_ttl_secs: Option<u64>,
+    /// Requests per tenant, as <requests>/<seconds>
+    #[arg(long)]
+    pub rate_limit_tenant: Option<String>,
+    /// Requests per tenant and user_id, as <requests>/<seconds>
+    #[arg(long)]
+    pub rate_limit_user: Option<String>,
+    /// Requests per tenant and character, as <requests>/<seconds>
+    #[arg(long)]
+    pub rate_limit_character: Option<String>,
+    /// Share rate limit buckets between instances through this Redis
+    #[arg(long)]
+    pub rate_limit_redis_url: Option<String>,
+    /// Classification cache: off, memory or disk
+    #[arg(long)]
+    pub classification_cache: Option<String>,
+    /// Directory of the disk cache, used only by the cache
+    #[arg(long)]
+    pub classification_cache_dir: Option<PathBuf>,
+    /// Seconds a cached classification stays valid
+    #[arg(long)]
+    pub classification_cache_ttl_secs: Option<u64>,
+    /// Cached classifications kept before the oldest are dropped
+    #[arg(long)]
+    pub classification_cache_max_entries: Option<usize>,
+    /// Trailing history lines that are part of the cache key
+    #[arg(long)]
+    pub classification_cache_context_lines: Option<usize>,
+    /// JSON file of ensemble members that vote on each classification
+    #[arg(long)]
+    pub ensemble_path: Option<PathBuf>,
+    /// Samples of the tenant's provider per classification, without an ensemble file
+    #[arg(long)]
+    pub ensemble_samples: Option<usize>,
+    /// JSON file of low-confidence policies by balance profile
+    #[arg(long)]
+    pub confidence_policy_path: Option<PathBuf>,
+    /// Primary classifier backend: provider, lexicon or model
+    #[arg(long)]
+    pub classifier_backend: Option<String>,
+    /// Local backend used when the provider fails: none, lexicon or model
+    #[arg(long)]
+    pub classifier_fallback: Option<String>,
+    /// Local backend that answers first when confident: none, lexicon or model
+    #[arg(long)]
+    pub classifier_prefilter: Option<String>,
+    /// Confidence the pre-filter needs to answer alone
+    #[arg(long)]
+    pub prefilter_min_confidence: Option<f32>,
+    /// Model file for the model backend
+    #[arg(long)]
+    pub classifier_model_path: Option<PathBuf>,
+    /// JSON file adding to, or replacing, the built-in lexicon
+    #[arg(long)]
+    pub lexicon_path: Option<PathBuf>,
+    /// Largest accepted batch
+    #[arg(long)]
+    pub batch_max_items: Option<usize>,
+    /// Items of one batch analyzed concurrently
+    #[arg(long)]
+    pub batch_concurrency: Option<usize>,
+    /// Concurrent provider calls across all requests
+    #[arg(long)]
+    pub upstream_concurrency: Option<usize>,
+    /// Longest accepted user_input
+    #[arg(long)]
+    pub max_user_input_chars: Option<usize>,
+    /// Longest accepted character_history
+    #[arg(long)]
+    pub max_history_chars: Option<usize>,
+    /// Longest accepted character_personality
+    #[arg(long)]
+    pub max_personality_chars: Option<usize>,
+    /// Blank user_input: reject, or neutral
+    #[arg(long)]
+    pub empty_input_policy: Option<String>,
+    /// State values outside the tier tables: reject, or clamp
+    #[arg(long)]
+    pub out_of_range_policy: Option<String>,
+    /// Seconds an upstream reachability result is reused by the readiness probe
+    #[arg(long)]
+    pub upstream_check_ttl_secs: Option<u64>,
+    /// Timeout of the readiness probe's upstream check, in seconds
+    #[arg(long)]
+    pub upstream_check_timeout_secs: Option<u64>,
+    /// Run without API keys, every request as the default tenant; required when api_keys_path is unset
+    #[arg(long)]
+    pub auth_disabled: Option<bool>,
+}
+
+/// Tools that run instead of the server
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Train the local classifier model from labeled interactions
+    Train(TrainArgs),
+    /// Measure a classifier backend against labeled interactions
+    Eval(EvalArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct TrainArgs {
+    /// JSONL file with one `{"input", "context", "category"}` record per line
+    #[arg(long)]
+    pub data: PathBuf,
+    /// Where to write the model file
+    #[arg(long)]
+    pub output: PathBuf,
+    /// Longest word n-gram used as a feature, 1 to 3
+    #[arg(long, default_value_t = 2)]
+    pub ngram: usize,
+    /// Trailing context lines whose words are used as features
+    #[arg(long, default_value_t = 2)]
+    pub context_lines: usize,
+}
+
+#[derive(Args, Debug)]
+pub struct EvalArgs {
+    /// JSONL file with one `{"input", "context", "category"}` record per line
+    #[arg(long)]
+    pub data: PathBuf,
+    /// `provider` (configured as for the server, including an ensemble), `lexicon` or `model`
+    #[arg(long, default_value = "provider")]
+    pub backend: Backend,
+    /// Model file for the `model` backend
+    #[arg(long, env = "CLASSIFIER_MODEL_PATH")]
+    pub model_path: Option<PathBuf>,
+    /// Use provider replies saved with --record instead of calling the provider
+    #[arg(long, conflicts_with = "record")]
+    pub replay: Option<PathBuf>,
+    /// Save every provider reply, for a later --replay
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    /// Save this run's results, for a later --baseline
+    #[arg(long)]
+    pub output: Option<PathBuf>,
+    /// Results of a previous run to compare against
+    #[arg(long)]
+    pub baseline: Option<PathBuf>,
+    /// Provider calls in flight at once
+    #[arg(long, default_value_t = 4)]
+    pub concurrency: usize,
+}
+
+/// One configuration source; unset values fall through to the next source
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Layer {
+    bind_address: Option<String>,
+    port: Option<u16>,
+    grpc_port: Option<u16>,
+    cors_origins: Option<Vec<String>>,
+    body_limit_bytes: Option<usize>,
+    provider_url: Option<String>,
+    model: Option<String>,
+    provider_logprobs: Option<bool>,
+    character_registry_path: Option<PathBuf>,
+    session_store_path: Option<PathBuf>,
+    api_keys_path: Option<PathBuf>,
+    shutdown_timeout_secs: Option<u64>,
+    tls_cert_path: Option<PathBuf>,
+    tls_key_path: Option<PathBuf>,
+    session_flush_interval_secs: Option<u64>,
+    session_ttl_secs: Option<u64>,
+    rate_limit_tenant: Option<String>,
+    rate_limit_user: Option<String>,
+    rate_limit_character: Option<String>,
+    rate_limit_redis_url: Option<String>,
+    classification_cache: Option<String>,
+    classification_cache_dir: Option<PathBuf>,
+    classification_cache_ttl_secs: Option<u64>,
+    classification_cache_max_entries: Option<usize>,
+    classification_cache_context_lines: Option<usize>,
+    ensemble_path: Option<PathBuf>,
+    ensemble_samples: Option<usize>,
+    confidence_policy_path: Option<PathBuf>,
+    classifier_backend: Option<String>,
+    classifier_fallback: Option<String>,
+    classifier_prefilter: Option<String>,
+    prefilter_min_confidence: Option<f32>,
+    classifier_model_path: Option<PathBuf>,
+    lexicon_path: Option<PathBuf>,
+    batch_max_items: Option<usize>,
+    batch_concurrency: Option<usize>,
+    upstream_concurrency: Option<usize>,
+    max_user_input_chars: Option<usize>,
+    max_history_chars: Option<usize>,
+    max_personality_chars: Option<usize>,
+    empty_input_policy: Option<String>,
+    out_of_range_policy: Option<String>,
+    upstream_check_ttl_secs: Option<u64>,
+    upstream_check_timeout_secs: Option<u64>,
+    auth_disabled: Option<bool>,
+}
+
+impl Layer {
+    /// Values set in `self` win over `lower`
+    fn over(self, lower: Layer) -> Layer {
+        Layer {
+            bind_address: self.bind_address.or(lower.bind_address),
+            port: self.port.or(lower.port),
+            grpc_port: self.grpc_port.or(lower.grpc_port),
+            cors_origins: self.cors_origins.or(lower.cors_origins),
+            body_limit_bytes: self.body_limit_bytes.or(lower.body_limit_bytes),
+            provider_url: self.provider_url.or(lower.provider_url),
+            model: self.model.or(lower.model),
+            provider_logprobs: self.provider_logprobs.or(lower.provider_logprobs),
+            character_registry_path: self.character_registry_path.or(lower.character_registry_path),
+            session_store_path: self.session_store_path.or(lower.session_store_path),
+            api_keys_path: self.api_keys_path.or(lower.api_keys_path),
+            shutdown_timeout_secs: self.shutdown_timeout_secs.or(lower.shutdown_timeout_secs),
+            tls_cert_path: self.tls_cert_path.or(lower.tls_cert_path),
+            tls_key_path: self.tls_key_path.or(lower.tls_key_path),
+            session_flush_interval_secs: self.session_flush_interval_secs.or(lower.session_flush_interval_secs),
+            session_ttl_secs: self.session_ttl_secs.or(lower.session_ttl_secs),
+            rate_limit_tenant: self.rate_limit_tenant.or(lower.rate_limit_tenant),
+            rate_limit_user: self.rate_limit_user.or(lower.rate_limit_user),
+            rate_limit_character: self.rate_limit_character.or(lower.rate_limit_character),
+            rate_limit_redis_url: self.rate_limit_redis_url.or(lower.rate_limit_redis_url),
+            classification_cache: self.classification_cache.or(lower.classification_cache),
+            classification_cache_dir: self.classification_cache_dir.or(lower.classification_cache_dir),
+            classification_cache_ttl_secs: self.classification_cache_ttl_secs.or(lower.classification_cache_ttl_secs),
+            classification_cache_max_entries: self.classification_cache_max_entries.or(lower.classification_cache_max_entries),
+            classification_cache_context_lines: self.classification_cache_context_lines.or(lower.classification_cache_context_lines),
+            ensemble_path: self.ensemble_path.or(lower.ensemble_path),
+            ensemble_samples: self.ensemble_samples.or(lower.ensemble_samples),
+            confidence_policy_path: self.confidence_policy_path.or(lower.confidence_policy_path),
+            classifier_backend: self.classifier_backend.or(lower.classifier_backend),
+            classifier_fallback: self.classifier_fallback.or(lower.classifier_fallback),
+            classifier_prefilter: self.classifier_prefilter.or(lower.classifier_prefilter),
+            prefilter_min_confidence: self.prefilter_min_confidence.or(lower.prefilter_min_confidence),
+            classifier_model_path: self.classifier_model_path.or(lower.classifier_model_path),
+            lexicon_path: self.lexicon_path.or(lower.lexicon_path),
+            batch_max_items: self.batch_max_items.or(lower.batch_max_items),
+            batch_concurrency: self.batch_concurrency.or(lower.batch_concurrency),
+            upstream_concurrency: self.upstream_concurrency.or(lower.upstream_concurrency),
+            max_user_input_chars: self.max_user_input_chars.or(lower.max_user_input_chars),
+            max_history_chars: self.max_history_chars.or(lower.max_history_chars),
+            max_personality_chars: self.max_personality_chars.or(lower.max_personality_chars),
+            empty_input_policy: self.empty_input_policy.or(lower.empty_input_policy),
+            out_of_range_policy: self.out_of_range_policy.or(lower.out_of_range_policy),
+            upstream_check_ttl_secs: self.upstream_check_ttl_secs.or(lower.upstream_check_ttl_secs),
+            upstream_check_timeout_secs: self.upstream_check_timeout_secs.or(lower.upstream_check_timeout_secs),
+            auth_disabled: self.auth_disabled.or(lower.auth_disabled),
+        }
+    }
+
+    fn from_cli(cli: Cli) -> Layer {
+        Layer {
+            bind_address: cli.bind_address,
+            port: cli.port,
+            grpc_port: cli.grpc_port,
+            cors_origins: cli.cors_origins,
+            body_limit_bytes: cli.body_limit_bytes,
+            provider_url: cli.provider_url,
+            model: cli.model,
+            provider_logprobs: cli.provider_logprobs,
+            character_registry_path: cli.character_registry_path,
+            session_store_path: cli.session_store_path,
+            api_keys_path: cli.api_keys_path,
+            shutdown_timeout_secs: cli.shutdown_timeout_secs,
+            tls_cert_path: cli.tls_cert_path,
+            tls_key_path: cli.tls_key_path,
+            session_flush_interval_secs: cli.session_flush_interval_secs,
+            session_ttl_secs: cli.session_ttl_secs,
+            rate_limit_tenant: cli.rate_limit_tenant,
+            rate_limit_user: cli.rate_limit_user,
+            rate_limit_character: cli.rate_limit_character,
+            rate_limit_redis_url: cli.rate_limit_redis_url,
+            classification_cache: cli.classification_cache,
+            classification_cache_dir: cli.classification_cache_dir,
+            classification_cache_ttl_secs: cli.classification_cache_ttl_secs,
+            classification_cache_max_entries: cli.classification_cache_max_entries,
+            classification_cache_context_lines: cli.classification_cache_context_lines,
+            ensemble_path: cli.ensemble_path,
+            ensemble_samples: cli.ensemble_samples,
+            confidence_policy_path: cli.confidence_policy_path,
+            classifier_backend: cli.classifier_backend,
+            classifier_fallback: cli.classifier_fallback,
+            classifier_prefilter: cli.classifier_prefilter,
+            prefilter_min_confidence: cli.prefilter_min_confidence,
+            classifier_model_path: cli.classifier_model_path,
+            lexicon_path: cli.lexicon_path,
+            batch_max_items: cli.batch_max_items,
+            batch_concurrency: cli.batch_concurrency,
+            upstream_concurrency: cli.upstream_concurrency,
+            max_user_input_chars: cli.max_user_input_chars,
+            max_history_chars: cli.max_history_chars,
+            max_personality_chars: cli.max_personality_chars,
+            empty_input_policy: cli.empty_input_policy,
+            out_of_range_policy: cli.out_of_range_policy,
+            upstream_check_ttl_secs: cli.upstream_check_ttl_secs,
+            upstream_check_timeout_secs: cli.upstream_check_timeout_secs,
+            auth_disabled: cli.auth_disabled,
+        }
+    }
+
+    /// Read the environment, collecting a message for every value that does not parse
+    fn from_env(errors: &mut Vec<String>) -> Layer {
+        Layer {
+            port: env_number("PORT", errors),
+            grpc_port: env_number("GRPC_PORT", errors),
+            body_limit_bytes: env_number("BODY_LIMIT_BYTES", errors),
+            bind_address: text("BIND_ADDRESS"),
+            cors_origins: text("CORS_ORIGINS").map(|v| v.split(',').map(|o| o.trim().to_string()).collect()),
+            provider_url: text("PROVIDER_URL"),
+            model: text("PROVIDER_MODEL"),
+            provider_logprobs: env_bool("PROVIDER_LOGPROBS", errors),
+            character_registry_path: text("CHARACTER_REGISTRY_PATH").map(PathBuf::from),
+            session_store_path: text("SESSION_STORE_PATH").map(PathBuf::from),
+            api_keys_path: text("API_KEYS_PATH").map(PathBuf::from),
+            shutdown_timeout_secs: env_number("SHUTDOWN_TIMEOUT_SECS", errors),
+            tls_cert_path: text("TLS_CERT_PATH").map(PathBuf::from),
+            tls_key_path: text("TLS_KEY_PATH").map(PathBuf::from),
+            session_flush_interval_secs: env_number("SESSION_FLUSH_INTERVAL_SECS", errors),
+            session_ttl_secs: env_number("SESSION_TTL_SECS", errors),
+            rate_limit_tenant: text("RATE_LIMIT_TENANT"),
+            rate_limit_user: text("RATE_LIMIT_USER"),
+            rate_limit_character: text("RATE_LIMIT_CHARACTER"),
+            rate_limit_redis_url: text("RATE_LIMIT_REDIS_URL"),
+            classification_cache: text("CLASSIFICATION_CACHE"),
+            classification_cache_dir: text("CLASSIFICATION_CACHE_DIR").map(PathBuf::from),
+            classification_cache_ttl_secs: env_number("CLASSIFICATION_CACHE_TTL_SECS", errors),
+            classification_cache_max_entries: env_number("CLASSIFICATION_CACHE_MAX_ENTRIES", errors),
+            classification_cache_context_lines: env_number("CLASSIFICATION_CACHE_CONTEXT_LINES", errors),
+            ensemble_path: text("ENSEMBLE_PATH").map(PathBuf::from),
+            ensemble_samples: env_number("ENSEMBLE_SAMPLES", errors),
+            confidence_policy_path: text("CONFIDENCE_POLICY_PATH").map(PathBuf::from),
+            classifier_backend: text("CLASSIFIER_BACKEND"),
+            classifier_fallback: text("CLASSIFIER_FALLBACK"),
+            classifier_prefilter: text("CLASSIFIER_PREFILTER"),
+            prefilter_min_confidence: env_number("PREFILTER_MIN_CONFIDENCE", errors),
+            classifier_model_path: text("CLASSIFIER_MODEL_PATH").map(PathBuf::from),
+            lexicon_path: text("LEXICON_PATH").map(PathBuf::from),
+            batch_max_items: env_number("BATCH_MAX_ITEMS", errors),
+            batch_concurrency: env_number("BATCH_CONCURRENCY", errors),
+            upstream_concurrency: env_number("UPSTREAM_CONCURRENCY", errors),
+            max_user_input_chars: env_number("MAX_USER_INPUT_CHARS", errors),
+            max_history_chars: env_number("MAX_HISTORY_CHARS", errors),
+            max_personality_chars: env_number("MAX_PERSONALITY_CHARS", errors),
+            empty_input_policy: text("EMPTY_INPUT_POLICY"),
+            out_of_range_policy: text("OUT_OF_RANGE_POLICY"),
+            upstream_check_ttl_secs: env_number("UPSTREAM_CHECK_TTL_SECS", errors),
+            upstream_check_timeout_secs: env_number("UPSTREAM_CHECK_TIMEOUT_SECS", errors),
+            auth_disabled: env_bool("AUTH_DISABLED", errors),
+        }
+    }
+}
+
+fn text(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|v| !v.trim().is_empty())
+}
+
+fn env_number<T: FromStr>(name: &str, errors: &mut Vec<String>) -> Option<T> {
+    let value = text(name)?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        errors.push(format!("{}: '{}' is not a valid number", name, value));
+    }
+    parsed
+}
+
+/// A setting written as text, such as a policy name; unset or invalid gives `None`
+fn parse_setting<T: FromStr<Err = String>>(name: &str, value: Option<String>, errors: &mut Vec<String>) -> Option<T> {
+    value?.parse().map_err(|e| errors.push(format!("{}: {}", name, e))).ok()
+}
+
+fn env_bool(name: &str, errors: &mut Vec<String>) -> Option<bool> {
+    let value = text(name)?;
+    let parsed = value.trim().parse().ok();
+    if parsed.is_none() {
+        errors.push(format!("{}: '{}' must be true or false", name, value));
+    }
+    parsed
+}
+
+/// Which origins may call the API from a browser
+#[derive(Debug, Clone)]
+pub enum CorsOrigins {
+    Any,
+    List(Vec<HeaderValue>),
+}
+
+/// Validated server configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub http_addr: SocketAddr,
+    pub grpc_addr: SocketAddr,
+    pub cors_origins: CorsOrigins,
+    pub body_limit_bytes: usize,
+    pub provider_api_key: String,
+    pub provider_url: String,
+    pub model: String,
+    pub provider_logprobs: bool,
+    pub character_registry_path: Option<PathBuf>,
+    pub session_store_path: Option<PathBuf>,
+    /// How often changed sessions are written to `session_store_path`
+    pub session_flush_interval: Duration,
+    /// Idle sessions older than this are dropped
+    pub session_ttl: Duration,
+    /// Unset only when authentication is explicitly disabled
+    pub api_keys_path: Option<PathBuf>,
+    pub shutdown_timeout: Duration,
+    pub rate_limits: RateLimitSettings,
+    pub classification_cache: CacheSettings,
+    pub batch: BatchSettings,
+    pub validation: ValidationSettings,
+    pub readiness: ReadinessSettings,
+    /// Provider calls in flight at once, across all requests
+    pub upstream_concurrency: usize,
+    /// Ensemble members file; takes the place of `ensemble_samples`
+    pub ensemble_path: Option<PathBuf>,
+    /// Calls to the tenant's provider per classification, 1 without an ensemble
+    pub ensemble_samples: usize,
+    pub classifier: ClassifierSettings,
+    /// Low-confidence policies by balance profile; none apply when unset
+    pub confidence_policy_path: Option<PathBuf>,
+    /// Buckets live in this Redis when set, in memory otherwise
+    pub rate_limit_redis_url: Option<String>,
+    /// HTTPS when set, plain HTTP otherwise
+    pub tls: Option<TlsPaths>,
+}
+
+const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 9527;
+const DEFAULT_GRPC_PORT: u16 = 50051;
+const DEFAULT_BODY_LIMIT_BYTES: usize = 2 * 1024 * 1024;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_CLASSIFICATION_CACHE_DIR: &str = "classification-cache";
+const DEFAULT_CLASSIFICATION_CACHE_TTL_SECS: u64 = 3600;
+const DEFAULT_CLASSIFICATION_CACHE_MAX_ENTRIES: usize = 10_000;
+const DEFAULT_CLASSIFICATION_CACHE_CONTEXT_LINES: usize = 4;
+const DEFAULT_BATCH_MAX_ITEMS: usize = 100;
+const DEFAULT_BATCH_CONCURRENCY: usize = 4;
+const DEFAULT_UPSTREAM_CONCURRENCY: usize = 16;
+const DEFAULT_UPSTREAM_CHECK_TTL_SECS: u64 = 30;
+const DEFAULT_UPSTREAM_CHECK_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_SESSION_FLUSH_INTERVAL_SECS: u64 = 5;
+const DEFAULT_SESSION_TTL_SECS: u64 = 7 * 24 * 3600;
+
+impl Config {
+    /// Merge defaults, the config file, the environment and `cli`, in increasing
+    /// priority. Every problem is reported, not only the first.
+    pub fn load(cli: Cli) -> Result<Config, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let file = match cli.config.clone().or_else(|| env::var("CONFIG_PATH").ok().map(PathBuf::from)) {
+            Some(path) => match std::fs::read_to_string(&path) {
+                Ok(text) => toml::from_str(&text).unwrap_or_else(|e: toml::de::Error| {
+                    errors.push(format!("{}: {}", path.display(), e.message()));
+                    Layer::default()
+                }),
+                Err(e) => {
+                    errors.push(format!("{}: {}", path.display(), e));
+                    Layer::default()
+                }
+            },
+            None => Layer::default(),
+        };
+        let layer = Layer::from_cli(cli).over(Layer::from_env(&mut errors)).over(file);
+
+        let bind_address = layer.bind_address.unwrap_or_else(|| DEFAULT_BIND_ADDRESS.to_string());
+        let ip: Option<IpAddr> = bind_address.parse().ok();
+        if ip.is_none() {
+            errors.push(format!("bind_address: '{}' is not an IP address", bind_address));
+        }
+        let port = layer.port.unwrap_or(DEFAULT_PORT);
+        let grpc_port = layer.grpc_port.unwrap_or(DEFAULT_GRPC_PORT);
+        if port == grpc_port {
+            errors.push(format!("port and grpc_port must differ (both are {})", port));
+        }
+
+        let cors_origins = match layer.cors_origins {
+            None => CorsOrigins::Any,
+            Some(origins) if origins.iter().any(|o| o == "*") => CorsOrigins::Any,
+            Some(origins) => CorsOrigins::List(
+                origins
+                    .iter()
+                    .filter_map(|origin| {
+                        let valid = (origin.starts_with("http://") || origin.starts_with("https://"))
+                            && !origin.ends_with('/');
+                        match HeaderValue::from_str(origin) {
+                            Ok(value) if valid => Some(value),
+                            _ => {
+                                errors.push(format!(
+                                    "cors_origins: '{}' must be `*` or an origin like https://game.example.com",
+                                    origin
+                                ));
+                                None
+                            }
+                        }
+                    })
+                    .collect(),
+            ),
+        };
+
+        let body_limit_bytes = layer.body_limit_bytes.unwrap_or(DEFAULT_BODY_LIMIT_BYTES);
+        if body_limit_bytes == 0 {
+            errors.push("body_limit_bytes must be greater than 0".to_string());
+        }
+
+        let provider_url = layer.provider_url.unwrap_or_else(|| provider::DEFAULT_API_URL.to_string());
+        if !reqwest::Url::parse(&provider_url).is_ok_and(|url| matches!(url.scheme(), "http" | "https")) {
+            errors.push(format!("provider_url: '{}' is not an http(s) URL", provider_url));
+        }
+        let model = layer.model.unwrap_or_else(|| provider::DEFAULT_MODEL.to_string());
+        if model.trim().is_empty() {
+            errors.push("model must not be empty".to_string());
+        }
+
+        let primary = match layer.classifier_backend.as_deref().map(str::parse::<Backend>) {
+            None => Backend::Provider,
+            Some(Ok(backend)) => backend,
+            Some(Err(e)) => {
+                errors.push(format!("classifier_backend: {}", e));
+                Backend::Provider
+            }
+        };
+        let mut local = |name: &str, value: Option<String>| match value.as_deref().map(str::trim) {
+            None | Some("none") => None,
+            Some(value) => match value.parse::<Backend>() {
+                Ok(Backend::Local(backend)) => Some(backend),
+                Ok(Backend::Provider) => {
+                    errors.push(format!("{}: must be none, lexicon or model", name));
+                    None
+                }
+                Err(e) => {
+                    errors.push(format!("{}: {}", name, e));
+                    None
+                }
+            },
+        };
+        let classifier = ClassifierSettings {
+            primary,
+            fallback: local("classifier_fallback", layer.classifier_fallback),
+            prefilter: local("classifier_prefilter", layer.classifier_prefilter),
+            prefilter_min_confidence: layer
+                .prefilter_min_confidence
+                .unwrap_or(classifier::DEFAULT_PREFILTER_MIN_CONFIDENCE),
+            model_path: layer.classifier_model_path,
+            lexicon_path: layer.lexicon_path,
+        };
+        if !(0.0..=1.0).contains(&classifier.prefilter_min_confidence) {
+            errors.push("prefilter_min_confidence must be between 0 and 1".to_string());
+        }
+        match &classifier.model_path {
+            None if classifier.uses_model() => {
+                errors.push("classifier_model_path must be set when a role uses the model backend".to_string())
+            }
+            Some(path) if classifier.uses_model() && !path.is_file() => {
+                errors.push(format!("classifier_model_path: {} does not exist", path.display()))
+            }
+            _ => {}
+        }
+        if let Some(path) = classifier.lexicon_path.as_ref().filter(|path| !path.is_file()) {
+            errors.push(format!("lexicon_path: {} does not exist", path.display()));
+        }
+
+        // Local primary backends never call the provider, so they need no key
+        let provider_api_key = env::var("XAI_API_KEY").unwrap_or_default();
+        if classifier.primary == Backend::Provider && provider_api_key.trim().is_empty() {
+            errors.push("XAI_API_KEY must be set (in the environment or a .env file)".to_string());
+        }
+
+        // Stores are created on first write, but their directory must exist
+        for (name, path) in [
+            ("character_registry_path", &layer.character_registry_path),
+            ("session_store_path", &layer.session_store_path),
+        ] {
+            if let Some(path) = path {
+                let dir = path.parent().filter(|d| !d.as_os_str().is_empty());
+                if dir.is_some_and(|d| !d.is_dir()) {
+                    errors.push(format!("{}: directory of {} does not exist", name, path.display()));
+                }
+            }
+        }
+        let session_flush_interval_secs =
+            layer.session_flush_interval_secs.unwrap_or(DEFAULT_SESSION_FLUSH_INTERVAL_SECS);
+        let session_ttl_secs = layer.session_ttl_secs.unwrap_or(DEFAULT_SESSION_TTL_SECS);
+        for (name, value) in [
+            ("session_flush_interval_secs", session_flush_interval_secs),
+            ("session_ttl_secs", session_ttl_secs),
+        ] {
+            if value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+        // Authentication is required unless the operator opts out of it
+        match (&layer.api_keys_path, layer.auth_disabled.unwrap_or(false)) {
+            (Some(path), false) if !path.is_file() => {
+                errors.push(format!("api_keys_path: {} does not exist", path.display()))
+            }
+            (Some(_), false) | (None, true) => {}
+            (None, false) => errors.push(
+                "api_keys_path must be set; set auth_disabled = true to run without authentication".to_string(),
+            ),
+            (Some(_), true) => errors.push("api_keys_path and auth_disabled = true cannot both be set".to_string()),
+        }
+
+        let rate_limits = RateLimitSettings {
+            tenant: parse_setting("rate_limit_tenant", layer.rate_limit_tenant, &mut errors),
+            user: parse_setting("rate_limit_user", layer.rate_limit_user, &mut errors),
+            character: parse_setting("rate_limit_character", layer.rate_limit_character, &mut errors),
+        };
+
+        let cache_backend = match layer.classification_cache.as_deref().map(str::trim) {
+            None | Some("off") => CacheBackend::Off,
+            Some("memory") => CacheBackend::Memory,
+            Some("disk") => CacheBackend::Disk(
+                layer
+                    .classification_cache_dir
+                    .unwrap_or_else(|| PathBuf::from(DEFAULT_CLASSIFICATION_CACHE_DIR)),
+            ),
+            Some(other) => {
+                errors.push(format!("classification_cache: '{}' must be off, memory or disk", other));
+                CacheBackend::Off
+            }
+        };
+        let classification_cache = CacheSettings {
+            backend: cache_backend,
+            ttl: Duration::from_secs(layer.classification_cache_ttl_secs.unwrap_or(DEFAULT_CLASSIFICATION_CACHE_TTL_SECS)),
+            max_entries: layer
+                .classification_cache_max_entries
+                .unwrap_or(DEFAULT_CLASSIFICATION_CACHE_MAX_ENTRIES),
+            context_lines: layer
+                .classification_cache_context_lines
+                .unwrap_or(DEFAULT_CLASSIFICATION_CACHE_CONTEXT_LINES),
+        };
+        if classification_cache.ttl.is_zero() {
+            errors.push("classification_cache_ttl_secs must be greater than 0".to_string());
+        }
+        if classification_cache.max_entries == 0 {
+            errors.push("classification_cache_max_entries must be greater than 0".to_string());
+        }
+
+        if let Some(path) = &layer.confidence_policy_path {
+            if !path.is_file() {
+                errors.push(format!("confidence_policy_path: {} does not exist", path.display()));
+            }
+        }
+
+        let batch = BatchSettings {
+            max_items: layer.batch_max_items.unwrap_or(DEFAULT_BATCH_MAX_ITEMS),
+            concurrency: layer.batch_concurrency.unwrap_or(DEFAULT_BATCH_CONCURRENCY),
+        };
+        let upstream_concurrency = layer.upstream_concurrency.unwrap_or(DEFAULT_UPSTREAM_CONCURRENCY);
+        for (name, value) in [
+            ("batch_max_items", batch.max_items),
+            ("batch_concurrency", batch.concurrency),
+            ("upstream_concurrency", upstream_concurrency),
+        ] {
+            if value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+
+        let defaults = ValidationSettings::default();
+        let validation = ValidationSettings {
+            max_user_input_chars: layer.max_user_input_chars.unwrap_or(defaults.max_user_input_chars),
+            max_history_chars: layer.max_history_chars.unwrap_or(defaults.max_history_chars),
+            max_personality_chars: layer.max_personality_chars.unwrap_or(defaults.max_personality_chars),
+            empty_input: parse_setting("empty_input_policy", layer.empty_input_policy, &mut errors).unwrap_or(defaults.empty_input),
+            out_of_range: parse_setting("out_of_range_policy", layer.out_of_range_policy, &mut errors).unwrap_or(defaults.out_of_range),
+        };
+        for (name, value) in [
+            ("max_user_input_chars", validation.max_user_input_chars),
+            ("max_history_chars", validation.max_history_chars),
+            ("max_personality_chars", validation.max_personality_chars),
+        ] {
+            if value == 0 {
+                errors.push(format!("{} must be greater than 0", name));
+            }
+        }
+
+        // A TTL of 0 checks the provider on every probe; a timeout of 0 would always fail
+        let readiness = ReadinessSettings {
+            upstream_check_ttl: Duration::from_secs(layer.upstream_check_ttl_secs.unwrap_or(DEFAULT_UPSTREAM_CHECK_TTL_SECS)),
+            upstream_check_timeout: Duration::from_secs(
+                layer.upstream_check_timeout_secs.unwrap_or(DEFAULT_UPSTREAM_CHECK_TIMEOUT_SECS),
+            ),
+        };
+        if readiness.upstream_check_timeout.is_zero() {
+            errors.push("upstream_check_timeout_secs must be greater than 0".to_string());
+        }
+
+        let ensemble_samples = layer.ensemble_samples.unwrap_or(1);
+        if ensemble_samples == 0 {
+            errors.push("ensemble_samples must be greater than 0".to_string());
+        }
+        if let Some(path) = &layer.ensemble_path {
+            if !path.is_file() {
+                errors.push(format!("ensemble_path: {} does not exist", path.display()));
+            }
+            if layer.ensemble_samples.is_some() {
+                errors.push("ensemble_path and ensemble_samples cannot both be set".to_string());
+            }
+        }
+
+        let tls = match (layer.tls_cert_path, layer.tls_key_path) {
+            (None, None) => None,
+            (Some(cert_path), Some(key_path)) => {
+                for (name, path) in [("tls_cert_path", &cert_path), ("tls_key_path", &key_path)] {
+                    if !path.is_file() {
+                        errors.push(format!("{}: {} does not exist", name, path.display()));
+                    }
+                }
+                Some(TlsPaths { cert_path, key_path })
+            }
+            _ => {
+                errors.push("tls_cert_path and tls_key_path must be set together".to_string());
+                None
+            }
+        };
+
+        let (Some(ip), true) = (ip, errors.is_empty()) else {
+            return Err(errors);
+        };
+        Ok(Config {
+            http_addr: SocketAddr::new(ip, port),
+            grpc_addr: SocketAddr::new(ip, grpc_port),
+            cors_origins,
+            body_limit_bytes,
+            provider_api_key,
+            provider_url,
+            model,
+            provider_logprobs: layer.provider_logprobs.unwrap_or(true),
+            character_registry_path: layer.character_registry_path,
+            session_store_path: layer.session_store_path,
+            session_flush_interval: Duration::from_secs(session_flush_interval_secs),
+            session_ttl: Duration::from_secs(session_ttl_secs),
+            api_keys_path: layer.api_keys_path,
+            shutdown_timeout: Duration::from_secs(layer.shutdown_timeout_secs.unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT_SECS)),
+            rate_limits,
+            classification_cache,
+            batch,
+            validation,
+            readiness,
+            upstream_concurrency,
+            ensemble_path: layer.ensemble_path,
+            ensemble_samples,
+            classifier,
+            confidence_policy_path: layer.confidence_policy_path,
+            rate_limit_redis_url: layer.rate_limit_redis_url,
+            tls,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::validation::{EmptyInputPolicy, OutOfRangePolicy};
+
+    /// Load with `toml` as the config file; a local classifier needs no provider key,
+    /// and authentication is off unless `cli` sets a key file
+    fn load(name: &str, toml: &str, cli: Cli) -> Result<Config, Vec<String>> {
+        let path = std::env::temp_dir().join(format!("config-test-{}-{}.toml", name, std::process::id()));
+        std::fs::write(&path, toml).unwrap();
+        let result = Config::load(Cli {
+            config: Some(path.clone()),
+            classifier_backend: Some("lexicon".to_string()),
+            auth_disabled: Some(true),
+            ..cli
+        });
+        std::fs::remove_file(&path).unwrap();
+        result
+    }
+
+    #[test]
+    fn request_limits_come_from_the_file_and_flags() {
+        let toml = r#"
+            batch_max_items = 10
+            batch_concurrency = 2
+            upstream_concurrency = 3
+            max_user_input_chars = 500
+            empty_input_policy = "neutral"
+            out_of_range_policy = "clamp"
+            upstream_check_ttl_secs = 0
+        "#;
+        // Flags win over the file
+        let cli = Cli {
+            batch_max_items: Some(20),
+            upstream_check_timeout_secs: Some(2),
+            ..Cli::default()
+        };
+        let config = load("limits", toml, cli).unwrap();
+
+        assert_eq!((config.batch.max_items, config.batch.concurrency), (20, 2));
+        assert_eq!(config.upstream_concurrency, 3);
+        assert_eq!(config.validation.max_user_input_chars, 500);
+        assert_eq!(config.validation.max_history_chars, ValidationSettings::default().max_history_chars);
+        assert_eq!(config.validation.empty_input, EmptyInputPolicy::Neutral);
+        assert_eq!(config.validation.out_of_range, OutOfRangePolicy::Clamp);
+        assert_eq!(config.readiness.upstream_check_ttl, Duration::ZERO);
+        assert_eq!(config.readiness.upstream_check_timeout, Duration::from_secs(2));
+    }
+
+    #[test]
+    fn every_invalid_setting_is_reported() {
+        let toml = r#"
+            batch_max_items = 0
+            batch_concurrency = 0
+            upstream_concurrency = 0
+            max_history_chars = 0
+            empty_input_policy = "neutrl"
+            out_of_range_policy = "clip"
+            upstream_check_timeout_secs = 0
+        "#;
+        let errors = load("invalid", toml, Cli::default()).unwrap_err();
+        for expected in [
+            "batch_max_items must be greater than 0",
+            "batch_concurrency must be greater than 0",
+            "upstream_concurrency must be greater than 0",
+            "max_history_chars must be greater than 0",
+            "empty_input_policy: 'neutrl' must be reject or neutral",
+            "out_of_range_policy: 'clip' must be reject or clamp",
+            "upstream_check_timeout_secs must be greater than 0",
+        ] {
+            assert!(errors.iter().any(|e| e == expected), "missing {:?} in {:?}", expected, errors);
+        }
+    }
+
+    #[test]
+    fn authentication_is_required_unless_disabled() {
+        let cli = || Cli {
+            classifier_backend: Some("lexicon".to_string()),
+            ..Cli::default()
+        };
+        let errors = Config::load(cli()).unwrap_err();
+        assert!(errors.iter().any(|e| e.starts_with("api_keys_path must be set")), "{:?}", errors);
+
+        let disabled = Config::load(Cli {
+            auth_disabled: Some(true),
+            ..cli()
+        });
+        assert!(disabled.unwrap().api_keys_path.is_none());
+
+        let both = Config::load(Cli {
+            auth_disabled: Some(true),
+            api_keys_path: Some(PathBuf::from("Cargo.toml")),
+            ..cli()
+        });
+        assert!(both.unwrap_err().iter().any(|e| e.contains("cannot both be set")));
+    }
+
+    #[test]
+    fn unknown_file_keys_are_rejected() {
+        let errors = load("unknown", "upstream_concurency = 4", Cli::default()).unwrap_err();
+        assert!(errors[0].contains("unknown field `upstream_concurency`"), "{:?}", errors);
+    }
+}
@@ -0,0 +1,305 @@
+// Ensemble classification: several providers, or several samples of one, vote on the category
+
+use std::path::Path;
+
+use futures::future::join_all;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use crate::analysis::{self, Classification};
+use crate::confidence::ConfidenceSource;
+use crate::provider::{Provider, ProviderOverrides};
+
+/// How member votes are combined
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Voting {
+    /// Every sample counts once
+    Majority,
+    /// Every sample counts its member's weight
+    Weighted,
+}
+
+/// Ensemble file layout
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EnsembleFile {
+    #[serde(default = "default_voting")]
+    voting: Voting,
+    members: Vec<MemberFile>,
+}
+
+fn default_voting() -> Voting {
+    Voting::Majority
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct MemberFile {
+    /// Unset fields fall back to the tenant's provider, then the server defaults
+    #[serde(default)]
+    provider_url: Option<String>,
+    /// Name of the environment variable holding the member's provider key
+    #[serde(default)]
+    provider_api_key_env: Option<String>,
+    #[serde(default)]
+    model: Option<String>,
+    #[serde(default = "default_weight")]
+    weight: f32,
+    /// How many times this member is asked
+    #[serde(default = "default_samples")]
+    samples: usize,
+}
+
+fn default_weight() -> f32 {
+    1.0
+}
+
+fn default_samples() -> usize {
+    1
+}
+
+/// One voter: a provider configuration asked `samples` times
+#[derive(Debug, Clone)]
+pub struct Member {
+    pub provider: ProviderOverrides,
+    pub weight: f32,
+    pub samples: usize,
+}
+
+/// Providers consulted for every classification; empty when ensembles are off
+#[derive(Debug, Clone)]
+pub struct Ensemble {
+    pub voting: Voting,
+    pub members: Vec<Member>,
+}
+
+impl Ensemble {
+    /// Read the ensemble file at `path`, or sample the tenant's provider `samples` times.
+    /// Neither (a single sample) means one provider call per classification.
+    pub fn load(path: Option<&Path>, samples: usize) -> anyhow::Result<Self> {
+        if let Some(path) = path {
+            return Self::open(path);
+        }
+        let members = if samples > 1 {
+            vec![Member {
+                provider: ProviderOverrides::default(),
+                weight: 1.0,
+                samples,
+            }]
+        } else {
+            Vec::new()
+        };
+        Ok(Ensemble {
+            voting: Voting::Majority,
+            members,
+        })
+    }
+
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let file: EnsembleFile = serde_json::from_str(&data)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        if file.members.is_empty() {
+            anyhow::bail!("{}: at least one member is required", path.display());
+        }
+
+        let mut members = Vec::new();
+        for (index, member) in file.members.into_iter().enumerate() {
+            if member.samples == 0 || !(member.weight.is_finite() && member.weight > 0.0) {
+                anyhow::bail!("member {}: samples and weight must be greater than 0", index);
+            }
+            let api_key = match &member.provider_api_key_env {
+                Some(name) => Some(std::env::var(name).map_err(|_| {
+                    anyhow::anyhow!("member {}: environment variable {} is not set", index, name)
+                })?),
+                None => None,
+            };
+            members.push(Member {
+                provider: ProviderOverrides {
+                    api_url: member.provider_url,
+                    api_key,
+                    model: member.model,
+                },
+                weight: member.weight,
+                samples: member.samples,
+            });
+        }
+        Ok(Ensemble {
+            voting: file.voting,
+            members,
+        })
+    }
+
+    /// Voting and members, without provider keys, for the classification cache key
+    pub fn fingerprint(&self) -> String {
+        let members: Vec<String> = self
+            .members
+            .iter()
+            .map(|m| {
+                format!(
+                    "{:?}/{:?}/{}/{}",
+                    m.provider.api_url, m.provider.model, m.weight, m.samples
+                )
+            })
+            .collect();
+        format!("{:?}:{}", self.voting, members.join(","))
+    }
+
+    /// More than one provider call per classification
+    pub fn enabled(&self) -> bool {
+        self.members.iter().map(|m| m.samples).sum::<usize>() > 1
+    }
+
+    /// Ask every member, concurrently and within the upstream limit, and vote.
+    /// Failed samples are left out; the vote fails only when every sample does.
+    pub async fn classify(
+        &self,
+        provider: &Provider,
+        permits: &Semaphore,
+        tenant_provider: &ProviderOverrides,
+        prompt: &str,
+    ) -> anyhow::Result<Classification> {
+        let calls = self.members.iter().flat_map(|member| {
+            // Member settings win over the tenant's, which win over the server defaults
+            let overrides = ProviderOverrides {
+                api_url: member.provider.api_url.clone().or_else(|| tenant_provider.api_url.clone()),
+                api_key: member.provider.api_key.clone().or_else(|| tenant_provider.api_key.clone()),
+                model: member.provider.model.clone().or_else(|| tenant_provider.model.clone()),
+            };
+            let weight = match self.voting {
+                Voting::Majority => 1.0,
+                Voting::Weighted => member.weight,
+            };
+            (0..member.samples).map(move |_| {
+                let overrides = overrides.clone();
+                async move {
+                    let _permit = permits.acquire().await?;
+                    let reply = provider.complete(prompt, &overrides).await?;
+                    anyhow::Ok((analysis::classify(&reply.content, reply.probability), weight))
+                }
+            })
+        });
+
+        let mut votes = Vec::new();
+        let mut last_error = None;
+        for result in join_all(calls).await {
+            match result {
+                Ok(vote) => votes.push(vote),
+                Err(e) => {
+                    warn!(error = %e, "⚠️ ensemble sample failed");
+                    last_error = Some(e);
+                }
+            }
+        }
+        if votes.is_empty() {
+            return Err(last_error.unwrap_or_else(|| anyhow::anyhow!("ensemble has no members")));
+        }
+        let classification = tally(&votes);
+        info!(
+            category = %classification.category,
+            confidence = classification.confidence,
+            samples = votes.len(),
+            "🗳️ ensemble vote",
+        );
+        Ok(classification)
+    }
+}
+
+/// Category with the largest total weight; ties go to the category voted first.
+/// The confidence is the winner's share of the total weight, scaled by the mean
+/// confidence of the samples that chose it.
+fn tally(votes: &[(Classification, f32)]) -> Classification {
+    // Category, total weight, summed sample confidence, sample count
+    let mut totals: Vec<(&str, f32, f32, usize)> = Vec::new();
+    for (sample, weight) in votes {
+        match totals.iter_mut().find(|(c, ..)| *c == sample.category) {
+            Some((_, total, confidence, count)) => {
+                *total += weight;
+                *confidence += sample.confidence;
+                *count += 1;
+            }
+            None => totals.push((&sample.category, *weight, sample.confidence, 1)),
+        }
+    }
+    let total: f32 = totals.iter().map(|(_, w, ..)| w).sum();
+    let (category, weight, confidence, count) = totals
+        .iter()
+        .fold(totals[0], |best, candidate| if candidate.1 > best.1 { *candidate } else { best });
+    Classification {
+        category: category.to_string(),
+        confidence: weight / total * confidence / count as f32,
+        confidence_source: ConfidenceSource::Votes,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vote(category: &str, confidence: f32, weight: f32) -> (Classification, f32) {
+        let confidence_source = if confidence == 0.0 {
+            ConfidenceSource::Fallback
+        } else {
+            ConfidenceSource::Exact
+        };
+        let classification = Classification {
+            category: category.to_string(),
+            confidence,
+            confidence_source,
+        };
+        (classification, weight)
+    }
+
+    fn assert_tally(votes: &[(Classification, f32)], category: &str, confidence: f32) {
+        let result = tally(votes);
+        assert_eq!(result.category, category);
+        assert!((result.confidence - confidence).abs() < 1e-6, "{} != {}", result.confidence, confidence);
+        assert_eq!(result.confidence_source, ConfidenceSource::Votes);
+    }
+
+    #[test]
+    fn tally_picks_the_heaviest_category() {
+        // Agreement 2/3, mean winner confidence 0.75
+        let majority = [vote("A", 0.9, 1.0), vote("B", 1.0, 1.0), vote("A", 0.6, 1.0)];
+        assert_tally(&majority, "A", 0.5);
+
+        // One heavy vote outweighs two light ones: 3/5 of the weight at 0.8
+        let weighted = [vote("A", 1.0, 1.0), vote("B", 0.8, 3.0), vote("A", 1.0, 1.0)];
+        assert_tally(&weighted, "B", 0.48);
+
+        let unanimous = [vote("A", 1.0, 2.0), vote("A", 1.0, 0.5)];
+        assert_tally(&unanimous, "A", 1.0);
+    }
+
+    #[test]
+    fn ties_go_to_the_category_voted_first() {
+        assert_tally(&[vote("A", 1.0, 1.0), vote("B", 0.5, 1.0)], "A", 0.5);
+        assert_tally(&[vote("B", 0.5, 1.0), vote("A", 1.0, 1.0)], "B", 0.25);
+    }
+
+    #[test]
+    fn fallback_votes_carry_no_confidence() {
+        let fallbacks = [vote("Neutral Behavior", 0.0, 1.0), vote("Neutral Behavior", 0.0, 1.0)];
+        assert_tally(&fallbacks, "Neutral Behavior", 0.0);
+    }
+
+    #[tokio::test]
+    async fn round_fails_when_every_sample_fails() {
+        let ensemble = Ensemble::load(None, 3).unwrap();
+        // Nothing listens on port 1, so every call is refused
+        let provider = Provider::new(
+            "key".to_string(),
+            "http://127.0.0.1:1/v1/chat/completions".to_string(),
+            "grok".to_string(),
+            false,
+        );
+        let result = ensemble
+            .classify(&provider, &Semaphore::new(2), &ProviderOverrides::default(), "prompt")
+            .await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,467 @@
+// Evaluation of a classifier backend against labeled interactions, with a diff against earlier runs
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Semaphore;
+use tracing::{info, warn};
+
+use crate::analysis::{self, Classification};
+use crate::bayes::{self, LabeledRecord, NaiveBayes};
+use crate::character::CharacterProfile;
+use crate::classifier::{Backend, LocalBackend};
+use crate::config::{Cli, Config, EvalArgs};
+use crate::ensemble::Ensemble;
+use crate::lexicon::Lexicon;
+use crate::provider::{Provider, ProviderOverrides};
+use crate::ranges::TierLookup;
+use crate::{behavior, ranges};
+
+/// A provider reply saved by `--record`
+#[derive(Serialize, Deserialize)]
+struct RecordedReply {
+    input: String,
+    context: String,
+    response: String,
+}
+
+/// Outcome for one labeled record
+#[derive(Serialize, Deserialize, Clone)]
+struct Prediction {
+    input: String,
+    context: String,
+    expected: String,
+    /// Unset when the backend failed for this record
+    predicted: Option<String>,
+    confidence: Option<f32>,
+}
+
+impl Prediction {
+    fn key(&self) -> (&str, &str, &str) {
+        (&self.input, &self.context, &self.expected)
+    }
+
+    fn correct(&self) -> bool {
+        self.predicted.as_deref() == Some(self.expected.as_str())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct CategoryScore {
+    /// Unset when the category was never predicted
+    precision: Option<f64>,
+    /// Unset when the category never occurs in the data
+    recall: Option<f64>,
+    support: usize,
+}
+
+/// Results of one evaluation, saved with `--output` and compared with `--baseline`
+#[derive(Serialize, Deserialize)]
+struct Run {
+    backend: String,
+    dataset: String,
+    evaluated: usize,
+    failed: usize,
+    accuracy: f64,
+    /// Mean distance between the expected and predicted categories' range midpoints
+    severity_error: f64,
+    /// Mean predicted minus expected midpoint; above 0 means predictions lean positive
+    severity_bias: f64,
+    categories: BTreeMap<String, CategoryScore>,
+    /// Expected category, then predicted category, to count
+    confusion: BTreeMap<String, BTreeMap<String, usize>>,
+    predictions: Vec<Prediction>,
+}
+
+/// Direction of a severity bias, as printed; within rounding of 0 there is none
+fn lean(bias: f64) -> &'static str {
+    if bias >= 0.005 {
+        "predictions lean positive"
+    } else if bias <= -0.005 {
+        "predictions lean negative"
+    } else {
+        "predictions do not lean either way"
+    }
+}
+
+/// `numerator / denominator`, unset when the denominator is 0
+fn ratio(numerator: usize, denominator: usize) -> Option<f64> {
+    (denominator > 0).then(|| numerator as f64 / denominator as f64)
+}
+
+impl Run {
+    fn new(backend: Backend, dataset: &Path, predictions: Vec<Prediction>) -> Self {
+        let scored: Vec<&Prediction> = predictions.iter().filter(|p| p.predicted.is_some()).collect();
+        let mut confusion: BTreeMap<String, BTreeMap<String, usize>> = BTreeMap::new();
+        let mut distances = Vec::new();
+        for prediction in &scored {
+            let predicted = prediction.predicted.clone().unwrap_or_default();
+            if let (Some(expected), Some(actual)) =
+                (behavior::severity(&prediction.expected), behavior::severity(&predicted))
+            {
+                distances.push(actual - expected);
+            }
+            *confusion
+                .entry(prediction.expected.clone())
+                .or_default()
+                .entry(predicted)
+                .or_default() += 1;
+        }
+
+        let mut categories = BTreeMap::new();
+        for category in behavior::all_categories() {
+            let support = scored.iter().filter(|p| p.expected == category).count();
+            let predicted = scored.iter().filter(|p| p.predicted.as_deref() == Some(category)).count();
+            let hits = scored.iter().filter(|p| p.expected == category && p.correct()).count();
+            if support > 0 || predicted > 0 {
+                categories.insert(
+                    category.to_string(),
+                    CategoryScore {
+                        precision: ratio(hits, predicted),
+                        recall: ratio(hits, support),
+                        support,
+                    },
+                );
+            }
+        }
+
+        let mean = |values: &mut dyn Iterator<Item = f64>| {
+            let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+            if count == 0 { 0.0 } else { sum / count as f64 }
+        };
+        Run {
+            backend: backend.to_string(),
+            dataset: dataset.display().to_string(),
+            evaluated: scored.len(),
+            failed: predictions.len() - scored.len(),
+            accuracy: ratio(scored.iter().filter(|p| p.correct()).count(), scored.len()).unwrap_or(0.0),
+            severity_error: mean(&mut distances.iter().map(|d| d.abs())),
+            severity_bias: mean(&mut distances.iter().copied()),
+            categories,
+            confusion,
+            predictions,
+        }
+    }
+
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        serde_json::from_slice(&data).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        std::fs::write(path, data).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    fn print(&self) {
+        println!("Backend: {}    Dataset: {}", self.backend, self.dataset);
+        println!("Evaluated: {}    Failed: {}", self.evaluated, self.failed);
+        println!("Accuracy: {:.3}", self.accuracy);
+        println!(
+            "Severity distance: mean {:.2}, bias {:+.2} ({})",
+            self.severity_error,
+            self.severity_bias,
+            lean(self.severity_bias),
+        );
+
+        let score = |value: Option<f64>| value.map_or_else(|| "-".to_string(), |v| format!("{:.3}", v));
+        println!();
+        println!("{:<26} {:>9} {:>9} {:>9}", "Category", "Precision", "Recall", "Support");
+        for (category, s) in self.ordered_categories() {
+            println!("{:<26} {:>9} {:>9} {:>9}", category, score(s.precision), score(s.recall), s.support);
+        }
+
+        // Columns are numbered; the rows name the categories
+        let names: Vec<&str> = self.ordered_categories().into_iter().map(|(c, _)| c).collect();
+        println!();
+        println!("Confusion matrix (rows: expected, columns: predicted)");
+        print!("{:>3} {:<26}", "", "");
+        for column in 1..=names.len() {
+            print!(" {:>4}", column);
+        }
+        println!();
+        for (row, expected) in names.iter().enumerate() {
+            print!("{:>3} {:<26}", row + 1, expected);
+            for predicted in &names {
+                let count = self.confusion.get(*expected).and_then(|r| r.get(*predicted)).copied().unwrap_or(0);
+                print!(" {:>4}", if count == 0 { ".".to_string() } else { count.to_string() });
+            }
+            println!();
+        }
+    }
+
+    /// Categories in the order of the behavior tables
+    fn ordered_categories(&self) -> Vec<(&str, CategoryScore)> {
+        behavior::all_categories()
+            .filter_map(|category| self.categories.get(category).map(|s| (category, *s)))
+            .collect()
+    }
+
+    /// Changes since `previous`, matching records by input, context and label
+    fn print_diff(&self, previous: &Run, baseline: &Path) {
+        println!();
+        println!("Compared with {} ({} on {})", baseline.display(), previous.backend, previous.dataset);
+        println!("Accuracy: {:.3} -> {:.3} ({:+.3})", previous.accuracy, self.accuracy, self.accuracy - previous.accuracy);
+        println!(
+            "Severity distance: {:.2} -> {:.2} ({:+.2})",
+            previous.severity_error,
+            self.severity_error,
+            self.severity_error - previous.severity_error,
+        );
+
+        let delta = |now: Option<f64>, before: Option<f64>| match (now, before) {
+            (Some(now), Some(before)) => format!("{:+.3}", now - before),
+            _ => "-".to_string(),
+        };
+        println!("{:<26} {:>9} {:>9}", "Category", "Precision", "Recall");
+        for category in behavior::all_categories() {
+            let (now, before) = (self.categories.get(category), previous.categories.get(category));
+            if now.is_none() && before.is_none() {
+                continue;
+            }
+            let precision = delta(now.and_then(|s| s.precision), before.and_then(|s| s.precision));
+            let recall = delta(now.and_then(|s| s.recall), before.and_then(|s| s.recall));
+            println!("{:<26} {:>9} {:>9}", category, precision, recall);
+        }
+
+        let before: HashMap<_, &Prediction> = previous.predictions.iter().map(|p| (p.key(), p)).collect();
+        let mut fixed = 0;
+        let mut regressed = Vec::new();
+        for prediction in &self.predictions {
+            match before.get(&prediction.key()) {
+                Some(old) if !old.correct() && prediction.correct() => fixed += 1,
+                Some(old) if old.correct() && !prediction.correct() => regressed.push(prediction),
+                _ => {}
+            }
+        }
+        println!("Fixed: {}    Regressed: {}", fixed, regressed.len());
+        for prediction in regressed.iter().take(10) {
+            println!(
+                "  {:?}: expected {}, now {}",
+                prediction.input,
+                prediction.expected,
+                prediction.predicted.as_deref().unwrap_or("(failed)"),
+            );
+        }
+    }
+}
+
+/// Provider prompt for a labeled record, as the server would build it for a new conversation
+fn prompt(record: &LabeledRecord) -> String {
+    let profile = CharacterProfile::from_personality(&record.personality);
+    analysis::build_prompt(
+        &record.context,
+        &profile.render_description(),
+        ranges::get_relationship_from_value(0, TierLookup::Corrected).unwrap_or("Acquaintance"),
+        ranges::get_emotion_from_value(0, TierLookup::Corrected).unwrap_or("Neutral"),
+        &record.input,
+    )
+}
+
+/// Classify every record with the provider, or with replies recorded earlier
+async fn provider_classifications(
+    records: &[LabeledRecord],
+    args: &EvalArgs,
+    config: Config,
+) -> anyhow::Result<Vec<Option<Classification>>> {
+    if let Some(path) = &args.replay {
+        let file = std::fs::File::open(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let mut replies = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let reply: RecordedReply =
+                serde_json::from_str(&line).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+            replies.insert((reply.input, reply.context), reply.response);
+        }
+        return Ok(records
+            .iter()
+            .map(|r| replies.get(&(r.input.clone(), r.context.clone())).map(|reply| analysis::classify(reply, None)))
+            .collect());
+    }
+
+    let provider = Provider::new(config.provider_api_key, config.provider_url, config.model, config.provider_logprobs);
+    let ensemble = Ensemble::load(config.ensemble_path.as_deref(), config.ensemble_samples)?;
+    if ensemble.enabled() && args.record.is_some() {
+        anyhow::bail!("--record saves single provider replies; unset ensemble_path and ensemble_samples");
+    }
+    let permits = Semaphore::new(args.concurrency.max(1));
+    let overrides = ProviderOverrides::default();
+
+    let results: Vec<anyhow::Result<(Classification, Option<String>)>> = stream::iter(records)
+        .map(|record| {
+            let (provider, ensemble, permits, overrides) = (&provider, &ensemble, &permits, &overrides);
+            async move {
+                let prompt = prompt(record);
+                if ensemble.enabled() {
+                    return Ok((ensemble.classify(provider, permits, overrides, &prompt).await?, None));
+                }
+                let _permit = permits.acquire().await?;
+                let reply = provider.complete(&prompt, overrides).await?;
+                Ok((analysis::classify(&reply.content, reply.probability), Some(reply.content)))
+            }
+        })
+        .buffered(args.concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut recording = match &args.record {
+        Some(path) => Some(std::fs::File::create(path).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?),
+        None => None,
+    };
+    let mut classifications = Vec::new();
+    for (record, result) in records.iter().zip(results) {
+        match result {
+            Ok((classification, reply)) => {
+                if let (Some(file), Some(response)) = (recording.as_mut(), reply) {
+                    let line = RecordedReply {
+                        input: record.input.clone(),
+                        context: record.context.clone(),
+                        response,
+                    };
+                    writeln!(file, "{}", serde_json::to_string(&line)?)?;
+                }
+                classifications.push(Some(classification));
+            }
+            Err(e) => {
+                warn!(input = %record.input, error = %e, "⚠️ classification failed");
+                classifications.push(None);
+            }
+        }
+    }
+    Ok(classifications)
+}
+
+/// `eval` subcommand: classify the dataset, print the report, and compare with a baseline.
+/// `cli` carries the server flags, read together with the config file and environment.
+pub async fn run(args: EvalArgs, mut cli: Cli) -> anyhow::Result<()> {
+    // The evaluated backend takes the place of the configured one, so local runs need no provider key
+    cli.classifier_backend = Some(args.backend.to_string());
+    if args.model_path.is_some() {
+        cli.classifier_model_path = args.model_path.clone();
+    }
+    let config = Config::load(cli).map_err(|errors| anyhow::anyhow!(errors.join("; ")))?;
+
+    let records = bayes::read_records(&args.data)?;
+    info!(records = records.len(), backend = %args.backend, "🧪 evaluating");
+
+    let classifications: Vec<Option<Classification>> = match args.backend {
+        Backend::Provider => provider_classifications(&records, &args, config).await?,
+        Backend::Local(LocalBackend::Lexicon) => {
+            let lexicon = Lexicon::load(config.classifier.lexicon_path.as_deref())?;
+            records.iter().map(|r| Some(lexicon.classify(&r.input))).collect()
+        }
+        Backend::Local(LocalBackend::Model) => {
+            let Some(path) = &config.classifier.model_path else {
+                anyhow::bail!("the model backend needs --model-path or CLASSIFIER_MODEL_PATH");
+            };
+            let model = NaiveBayes::open(path)?;
+            records.iter().map(|r| Some(model.classify(&r.input, &r.context))).collect()
+        }
+    };
+
+    let predictions = records
+        .iter()
+        .zip(classifications)
+        .map(|(record, classification)| Prediction {
+            input: record.input.clone(),
+            context: record.context.clone(),
+            expected: record.category.clone(),
+            confidence: classification.as_ref().map(|c| c.confidence),
+            predicted: classification.map(|c| c.category),
+        })
+        .collect();
+    let run = Run::new(args.backend, &args.data, predictions);
+    run.print();
+
+    if let Some(path) = &args.baseline {
+        run.print_diff(&Run::open(path)?, path);
+    }
+    if let Some(path) = &args.output {
+        run.save(path)?;
+        info!(output = %path.display(), "💾 results saved");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prediction(expected: &str, predicted: Option<&str>) -> Prediction {
+        Prediction {
+            input: format!("{} as {:?}", expected, predicted),
+            context: String::new(),
+            expected: expected.to_string(),
+            predicted: predicted.map(str::to_string),
+            confidence: predicted.map(|_| 1.0),
+        }
+    }
+
+    fn run(predictions: Vec<Prediction>) -> Run {
+        Run::new(Backend::Local(LocalBackend::Lexicon), Path::new("labeled.jsonl"), predictions)
+    }
+
+    #[test]
+    fn scores_a_fixed_set_of_predictions() {
+        let run = run(vec![
+            prediction("LightPositiveBehavior", Some("LightPositiveBehavior")),
+            prediction("LightPositiveBehavior", Some("ModeratePositiveBehavior")),
+            prediction("Neutral Behavior", Some("LightNegativeBehavior")),
+            prediction("ModeratePositiveBehavior", Some("ModeratePositiveBehavior")),
+            prediction("Neutral Behavior", None),
+        ]);
+
+        assert_eq!((run.evaluated, run.failed), (4, 1));
+        assert_eq!(run.accuracy, 0.5);
+
+        let score = |category: &str| {
+            let s = run.categories[category];
+            (s.precision, s.recall, s.support)
+        };
+        assert_eq!(score("LightPositiveBehavior"), (Some(1.0), Some(0.5), 2));
+        assert_eq!(score("ModeratePositiveBehavior"), (Some(0.5), Some(1.0), 1));
+        // Failed records are left out, so the neutral support is 1
+        assert_eq!(score("Neutral Behavior"), (None, Some(0.0), 1));
+        assert_eq!(score("LightNegativeBehavior"), (Some(0.0), None, 0));
+        assert!(!run.categories.contains_key("StrongPositiveBehavior"));
+
+        let cell = |expected: &str, predicted: &str| run.confusion[expected][predicted];
+        assert_eq!(cell("LightPositiveBehavior", "LightPositiveBehavior"), 1);
+        assert_eq!(cell("LightPositiveBehavior", "ModeratePositiveBehavior"), 1);
+        assert_eq!(cell("Neutral Behavior", "LightNegativeBehavior"), 1);
+        assert_eq!(cell("ModeratePositiveBehavior", "ModeratePositiveBehavior"), 1);
+        assert_eq!(run.confusion.values().map(|row| row.values().sum::<usize>()).sum::<usize>(), 4);
+
+        // Midpoints: Neutral 0, LightPositive 2.5, ModeratePositive 7, LightNegative -3
+        assert_eq!(run.severity_error, (4.5 + 3.0) / 4.0);
+        assert_eq!(run.severity_bias, (4.5 - 3.0) / 4.0);
+        assert_eq!(lean(run.severity_bias), "predictions lean positive");
+    }
+
+    #[test]
+    fn balanced_errors_do_not_lean() {
+        let run = run(vec![
+            prediction("Neutral Behavior", Some("LightPositiveBehavior")),
+            prediction("LightPositiveBehavior", Some("Neutral Behavior")),
+        ]);
+        assert_eq!(run.accuracy, 0.0);
+        assert_eq!(run.severity_error, 2.5);
+        assert_eq!(run.severity_bias, 0.0);
+        assert_eq!(lean(run.severity_bias), "predictions do not lean either way");
+        assert_eq!(lean(-0.5), "predictions lean negative");
+    }
+
+    #[test]
+    fn an_all_failed_run_has_zero_metrics() {
+        let run = run(vec![prediction("Neutral Behavior", None)]);
+        assert_eq!((run.evaluated, run.failed), (0, 1));
+        assert_eq!((run.accuracy, run.severity_error, run.severity_bias), (0.0, 0.0, 0.0));
+        assert!(run.confusion.is_empty());
+    }
+}
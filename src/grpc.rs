@@ -0,0 +1,205 @@
+// gRPC interface served alongside the HTTP API
+
+use axum::http::StatusCode;
+use tonic::metadata::MetadataMap;
+use tonic::service::{interceptor::InterceptedService, Interceptor};
+use tonic::{Request, Response, Status};
+
+use crate::auth::Tenant;
+use crate::batch::{self, BatchItemResult};
+use crate::character::CharacterProfile;
+use crate::ranges::TierLookup;
+use crate::session::{self, SessionStore};
+use crate::{analysis, auth, AppState, EmotionRequest, EmotionResponse};
+
+pub mod proto {
+    tonic::include_proto!("emotion.v1");
+}
+
+use proto::emotion_service_server::{EmotionService, EmotionServiceServer};
+
+/// Map an HTTP-equivalent status to the closest gRPC status
+fn to_status(code: StatusCode, messages: Vec<String>) -> Status {
+    let message = messages.join("; ");
+    match code {
+        StatusCode::NOT_FOUND => Status::not_found(message),
+        StatusCode::CONFLICT => Status::already_exists(message),
+        StatusCode::UNPROCESSABLE_ENTITY | StatusCode::BAD_REQUEST => Status::invalid_argument(message),
+        StatusCode::PAYLOAD_TOO_LARGE => Status::out_of_range(message),
+        StatusCode::TOO_MANY_REQUESTS => Status::resource_exhausted(message),
+        _ => Status::internal(message),
+    }
+}
+
+/// Tenant attached by the API key interceptor
+fn tenant<T>(request: &Request<T>) -> Option<Tenant> {
+    request.extensions().get::<Tenant>().cloned()
+}
+
+impl From<proto::CharacterProfile> for CharacterProfile {
+    fn from(profile: proto::CharacterProfile) -> Self {
+        CharacterProfile {
+            name: profile.name,
+            personality: profile.personality,
+            backstory: profile.backstory,
+            speaking_style: profile.speaking_style,
+            likes: profile.likes,
+            dislikes: profile.dislikes,
+            boundaries: profile.boundaries,
+        }
+    }
+}
+
+impl From<proto::EmotionRequest> for EmotionRequest {
+    fn from(request: proto::EmotionRequest) -> Self {
+        EmotionRequest {
+            character_history: request.character_history,
+            character_personality: request.character_personality,
+            character: request.character.map(Into::into),
+            character_id: request.character_id,
+            character_version: request.character_version,
+            current_relationship: request.current_relationship,
+            current_emotion: request.current_emotion,
+            user_input: request.user_input,
+            user_id: request.user_id,
+        }
+    }
+}
+
+impl From<EmotionResponse> for proto::EmotionResponse {
+    fn from(response: EmotionResponse) -> Self {
+        proto::EmotionResponse {
+            emotion_change: response.emotion_change,
+            relationship_change: response.relationship_change,
+            character_id: response.character_id,
+            character_version: response.character_version,
+        }
+    }
+}
+
+impl From<BatchItemResult<EmotionResponse>> for proto::BatchItemResult {
+    fn from(item: BatchItemResult<EmotionResponse>) -> Self {
+        match item {
+            BatchItemResult::Ok { index, result } => proto::BatchItemResult {
+                index: index as u32,
+                result: Some(proto::batch_item_result::Result::Ok(result.into())),
+            },
+            BatchItemResult::Error { index, code, errors } => proto::BatchItemResult {
+                index: index as u32,
+                result: Some(proto::batch_item_result::Result::Error(proto::ItemError {
+                    code: code.into(),
+                    errors,
+                })),
+            },
+        }
+    }
+}
+
+pub struct GrpcService {
+    state: AppState,
+}
+
+/// Key from the `authorization: Bearer <key>` or `x-api-key: <key>` metadata
+fn metadata_key(metadata: &MetadataMap) -> Option<String> {
+    auth::presented_key(
+        metadata.get("authorization").and_then(|v| v.to_str().ok()),
+        metadata.get("x-api-key").and_then(|v| v.to_str().ok()),
+    )
+}
+
+impl GrpcService {
+    /// The service behind the same API key check as the HTTP routes
+    // The interceptor's `Result<Request<()>, Status>` signature is defined by tonic
+    #[allow(clippy::result_large_err)]
+    pub fn server(
+        state: AppState,
+    ) -> InterceptedService<EmotionServiceServer<GrpcService>, impl Interceptor + Clone> {
+        let api_keys = state.api_keys.clone();
+        EmotionServiceServer::with_interceptor(GrpcService { state }, move |mut request: Request<()>| {
+            let key = metadata_key(request.metadata());
+            let tenant = api_keys.authenticate(key.as_deref()).map_err(Status::unauthenticated)?;
+            tracing::Span::current().record("tenant", tenant.id.as_str());
+            request.extensions_mut().insert(tenant);
+            Ok(request)
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl EmotionService for GrpcService {
+    async fn analyze_emotion(
+        &self,
+        request: Request<proto::EmotionRequest>,
+    ) -> Result<Response<proto::EmotionResponse>, Status> {
+        let tenant = tenant(&request).ok_or_else(|| Status::unauthenticated("missing API key"))?;
+        let payload: EmotionRequest = request.into_inner().into();
+        let outcome = analysis::run(&self.state, &tenant, &payload, TierLookup::Corrected)
+            .await
+            .map_err(|error| to_status(error.status(), error.messages()))?;
+        Ok(Response::new(EmotionResponse::from(outcome).into()))
+    }
+
+    async fn analyze_emotion_batch(
+        &self,
+        request: Request<proto::BatchRequest>,
+    ) -> Result<Response<proto::BatchResponse>, Status> {
+        let tenant = tenant(&request).ok_or_else(|| Status::unauthenticated("missing API key"))?;
+        let requests = request.into_inner().requests;
+        self.state
+            .batch
+            .check_size(requests.len())
+            .map_err(Status::out_of_range)?;
+
+        let items = requests.into_iter().map(|r| Ok(r.into())).collect();
+        let response = batch::run_batch::<EmotionResponse>(&self.state, &tenant, items, TierLookup::Corrected).await;
+        Ok(Response::new(proto::BatchResponse {
+            succeeded: response.succeeded as u32,
+            failed: response.failed as u32,
+            results: response.results.into_iter().map(Into::into).collect(),
+        }))
+    }
+
+    async fn get_session_state(
+        &self,
+        request: Request<proto::SessionStateRequest>,
+    ) -> Result<Response<proto::SessionState>, Status> {
+        let tenant = tenant(&request).ok_or_else(|| Status::unauthenticated("missing API key"))?;
+        let request = request.into_inner();
+        let key = SessionStore::key(&tenant.id, &request.character, &request.user_id);
+        let session = self.state.sessions.get(&key).ok_or_else(|| {
+            Status::not_found(format!(
+                "no session for user '{}' and character '{}'",
+                request.user_id, request.character
+            ))
+        })?;
+
+        let current = session.lock().await;
+        Ok(Response::new(proto::SessionState {
+            session_id: current.id.clone(),
+            user_id: current.user_id.clone(),
+            character_id: current.character_id.clone(),
+            character_version: current.character_version,
+            emotion: current.emotion,
+            relationship: current.relationship,
+            emotion_tier: session::emotion_tier(current.emotion).to_string(),
+            relationship_tier: session::relationship_tier(current.relationship).to_string(),
+            history: current.history.iter().cloned().collect(),
+            updated_at: current.updated_at.to_rfc3339(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_carries_the_key() {
+        let mut metadata = MetadataMap::new();
+        assert_eq!(metadata_key(&metadata), None);
+        metadata.insert("x-api-key", "from-metadata".parse().unwrap());
+        assert_eq!(metadata_key(&metadata).as_deref(), Some("from-metadata"));
+        metadata.insert("authorization", "Bearer from-bearer".parse().unwrap());
+        assert_eq!(metadata_key(&metadata).as_deref(), Some("from-bearer"));
+    }
+}
@@ -0,0 +1,345 @@
+// Readiness probe: the balance profile, storage and the upstream provider
+
+use std::future::Future;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::Mutex;
+use tracing::warn;
+use utoipa::ToSchema;
+
+use crate::{behavior, coefficients, ranges, AppState};
+
+/// Name of the compiled-in balance profile
+const PROFILE_NAME: &str = "default";
+
+/// How long an upstream reachability result is reused, so probes do not hit the provider.
+/// Validated by [`crate::config::Config::load`].
+#[derive(Debug, Clone, Copy)]
+pub struct ReadinessSettings {
+    pub upstream_check_ttl: Duration,
+    pub upstream_check_timeout: Duration,
+}
+
+/// Last upstream reachability result and when it was taken
+pub struct UpstreamCheckCache {
+    settings: ReadinessSettings,
+    last: Mutex<Option<(Instant, Result<(), String>)>>,
+}
+
+impl UpstreamCheckCache {
+    pub fn new(settings: ReadinessSettings) -> Self {
+        UpstreamCheckCache {
+            settings,
+            last: Mutex::new(None),
+        }
+    }
+
+    /// The last result while it is younger than the TTL, otherwise a fresh `check`
+    async fn get<F, Fut>(&self, check: F) -> Check
+    where
+        F: FnOnce(Duration) -> Fut,
+        Fut: Future<Output = Result<(), String>>,
+    {
+        let mut last = self.last.lock().await;
+        if let Some((taken, result)) = last.as_ref() {
+            if taken.elapsed() < self.settings.upstream_check_ttl {
+                return Check {
+                    age_secs: Some(taken.elapsed().as_secs()),
+                    ..Check::from_result(result.clone())
+                };
+            }
+        }
+
+        let result = check(self.settings.upstream_check_timeout).await;
+        *last = Some((Instant::now(), result.clone()));
+        Check {
+            age_secs: Some(0),
+            ..Check::from_result(result)
+        }
+    }
+}
+
+/// Outcome of one readiness check
+#[derive(Serialize, ToSchema)]
+pub struct Check {
+    ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Seconds since the result was taken, for cached checks
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age_secs: Option<u64>,
+}
+
+impl Check {
+    fn from_result(result: Result<(), String>) -> Self {
+        Check {
+            ok: result.is_ok(),
+            error: result.err(),
+            age_secs: None,
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ReadinessChecks {
+    balance_profile: Check,
+    storage: Check,
+    upstream: Check,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ProfileInfo {
+    name: String,
+    /// Short hash of the behavior ranges, tier ranges and coefficients
+    version: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct ProviderInfo {
+    /// Host of the provider endpoint
+    name: String,
+    model: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct Readiness {
+    /// `ready` or `not_ready`
+    status: String,
+    /// Build version of the service
+    version: String,
+    profile: ProfileInfo,
+    provider: ProviderInfo,
+    checks: ReadinessChecks,
+}
+
+/// Version of the active balance profile: a hash of every table it is built from
+fn profile_version() -> String {
+    let tables = format!(
+        "{:?}{:?}{:?}{:?}{:?}{:?}",
+        behavior::positive_behaviors::RANGES,
+        behavior::negative_behaviors::RANGES,
+        ranges::EMOTION_RANGES,
+        ranges::RELATIONSHIP_RANGES,
+        coefficients::EMOTION_COEFFICIENTS,
+        coefficients::RELATIONSHIP_COEFFICIENTS,
+    );
+    format!("{:x}", Sha256::digest(tables.as_bytes()))[..12].to_string()
+}
+
+/// The tables the calculation reads must be present and usable. Tiers without a
+/// coefficient are fine: the calculation treats them as neutral.
+fn check_profile() -> Result<(), String> {
+    let mut problems = Vec::new();
+    for (name, (min, max)) in behavior::positive_behaviors::RANGES.iter().chain(behavior::negative_behaviors::RANGES) {
+        if min > max {
+            problems.push(format!("behavior '{}' has an empty range", name));
+        }
+    }
+    for (table, kind) in [
+        (behavior::positive_behaviors::RANGES, "positive behavior"),
+        (behavior::negative_behaviors::RANGES, "negative behavior"),
+        (ranges::EMOTION_RANGES, "emotion"),
+        (ranges::RELATIONSHIP_RANGES, "relationship"),
+    ] {
+        if table.is_empty() {
+            problems.push(format!("{} ranges are empty", kind));
+        }
+    }
+    for (name, (positive, negative)) in coefficients::EMOTION_COEFFICIENTS.iter().chain(coefficients::RELATIONSHIP_COEFFICIENTS) {
+        if !(positive.is_finite() && negative.is_finite() && *positive >= 0.0 && *negative >= 0.0) {
+            problems.push(format!("coefficient '{}' is not a non-negative number", name));
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+/// A persisted file must be readable if it exists, and its directory must be writable
+fn check_file(path: &Path) -> Result<(), String> {
+    if path.exists() {
+        std::fs::File::open(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    }
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let metadata = std::fs::metadata(dir).map_err(|e| format!("{}: {}", dir.display(), e))?;
+    if metadata.permissions().readonly() {
+        return Err(format!("{}: directory is read-only", dir.display()));
+    }
+    Ok(())
+}
+
+async fn check_storage(state: &AppState) -> Result<(), String> {
+    let mut problems = Vec::new();
+    for path in [state.registry.path(), state.sessions.path()].into_iter().flatten() {
+        if let Err(e) = check_file(path) {
+            problems.push(e);
+        }
+    }
+    if let Err(e) = state.rate_limiter.ping().await {
+        problems.push(format!("rate limit store: {}", e));
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems.join("; "))
+    }
+}
+
+/// Reachability of the provider, taken at most once per TTL
+async fn check_upstream(state: &AppState) -> Check {
+    state
+        .upstream_check
+        .get(|timeout| async move { state.provider.check_reachable(timeout).await.map_err(|e| e.to_string()) })
+        .await
+}
+
+/// Ready when the profile and storage are usable, and the provider is reachable or not required
+fn is_ready(checks: &ReadinessChecks, requires_provider: bool) -> bool {
+    checks.balance_profile.ok && checks.storage.ok && (checks.upstream.ok || !requires_provider)
+}
+
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    security(()),
+    responses(
+        (status = 200, description = "Every dependency is available", body = Readiness),
+        (status = 503, description = "At least one check failed", body = Readiness)
+    )
+)]
+pub async fn readiness(State(state): State<AppState>) -> Response {
+    let checks = ReadinessChecks {
+        balance_profile: Check::from_result(check_profile()),
+        storage: Check::from_result(check_storage(&state).await),
+        upstream: check_upstream(&state).await,
+    };
+    // With a local primary or fallback classifier, requests are still answered while the provider is down
+    let ready = is_ready(&checks, state.classifier.requires_provider());
+    if !ready {
+        warn!(
+            balance_profile = ?checks.balance_profile.error,
+            storage = ?checks.storage.error,
+            upstream = ?checks.upstream.error,
+            "🩺 not ready"
+        );
+    }
+
+    let body = Readiness {
+        status: if ready { "ready" } else { "not_ready" }.to_string(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        profile: ProfileInfo {
+            name: PROFILE_NAME.to_string(),
+            version: profile_version(),
+        },
+        provider: ProviderInfo {
+            name: state.provider.name(),
+            model: state.provider.model().to_string(),
+        },
+        checks,
+    };
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(body)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    fn cache(ttl: Duration) -> UpstreamCheckCache {
+        UpstreamCheckCache::new(ReadinessSettings {
+            upstream_check_ttl: ttl,
+            upstream_check_timeout: Duration::from_secs(5),
+        })
+    }
+
+    /// A check that fails, counting its calls
+    async fn failing_check(cache: &UpstreamCheckCache, calls: &AtomicUsize) -> Check {
+        cache
+            .get(|timeout| async move {
+                assert_eq!(timeout, Duration::from_secs(5));
+                calls.fetch_add(1, Ordering::SeqCst);
+                Err("connection refused".to_string())
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn upstream_results_are_reused_within_the_ttl() {
+        let cache = cache(Duration::from_secs(3600));
+        let calls = AtomicUsize::new(0);
+        let first = failing_check(&cache, &calls).await;
+        let second = failing_check(&cache, &calls).await;
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        // Failures are reused as well, so a down provider is not probed on every request
+        assert!(!second.ok);
+        assert_eq!(second.error, first.error);
+        assert_eq!(second.age_secs, Some(0));
+    }
+
+    #[tokio::test]
+    async fn upstream_results_expire_after_the_ttl() {
+        let cache = cache(Duration::ZERO);
+        let calls = AtomicUsize::new(0);
+        failing_check(&cache, &calls).await;
+        failing_check(&cache, &calls).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    fn checks(profile: bool, storage: bool, upstream: bool) -> ReadinessChecks {
+        let check = |ok: bool| Check::from_result(if ok { Ok(()) } else { Err("down".to_string()) });
+        ReadinessChecks {
+            balance_profile: check(profile),
+            storage: check(storage),
+            upstream: check(upstream),
+        }
+    }
+
+    #[test]
+    fn failed_dependencies_fail_readiness() {
+        assert!(is_ready(&checks(true, true, true), true));
+        assert!(!is_ready(&checks(false, true, true), true));
+        assert!(!is_ready(&checks(true, false, true), true));
+        assert!(!is_ready(&checks(true, true, false), true));
+        // A local classifier keeps answering while the provider is down
+        assert!(is_ready(&checks(true, true, false), false));
+        assert!(!is_ready(&checks(true, false, false), false));
+    }
+
+    #[test]
+    fn the_compiled_in_profile_is_usable() {
+        assert_eq!(check_profile(), Ok(()));
+        assert_eq!(profile_version().len(), 12);
+    }
+
+    #[test]
+    fn storage_needs_a_writable_directory() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("health-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        assert!(check_file(&dir.join("sessions.json")).is_ok());
+        assert!(check_file(&dir.join("missing").join("sessions.json")).is_err());
+
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o555)).unwrap();
+        let read_only = check_file(&dir.join("sessions.json"));
+        std::fs::set_permissions(&dir, std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(read_only.unwrap_err().ends_with("directory is read-only"));
+    }
+}
@@ -0,0 +1,467 @@
+// Rule-based behavior classifier from keyword and phrase lexicons, for use without the provider
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::analysis::Classification;
+use crate::confidence::ConfidenceSource;
+
+/// Built-in phrases and the category each one signals
+const PHRASES: &[(&str, &str)] = &[
+    // Small talk
+    ("hi", "Neutral Behavior"),
+    ("hello", "Neutral Behavior"),
+    ("hey", "Neutral Behavior"),
+    ("good morning", "Neutral Behavior"),
+    ("good evening", "Neutral Behavior"),
+    ("good night", "Neutral Behavior"),
+    ("how are you", "Neutral Behavior"),
+    ("what's up", "Neutral Behavior"),
+    ("ok", "Neutral Behavior"),
+    ("okay", "Neutral Behavior"),
+    ("sure", "Neutral Behavior"),
+    ("hmm", "Neutral Behavior"),
+    ("bye", "Neutral Behavior"),
+    ("see you", "Neutral Behavior"),
+    // Positive
+    ("thanks", "LightPositiveBehavior"),
+    ("thank you", "LightPositiveBehavior"),
+    ("please", "LightPositiveBehavior"),
+    ("nice", "LightPositiveBehavior"),
+    ("cool", "LightPositiveBehavior"),
+    ("good", "LightPositiveBehavior"),
+    ("glad", "LightPositiveBehavior"),
+    ("haha", "LightPositiveBehavior"),
+    ("lol", "LightPositiveBehavior"),
+    ("fun", "LightPositiveBehavior"),
+    ("sweet", "LightPositiveBehavior"),
+    ("great", "ModeratePositiveBehavior"),
+    ("awesome", "ModeratePositiveBehavior"),
+    ("appreciate", "ModeratePositiveBehavior"),
+    ("well done", "ModeratePositiveBehavior"),
+    ("proud of you", "ModeratePositiveBehavior"),
+    ("you're funny", "ModeratePositiveBehavior"),
+    ("you're kind", "ModeratePositiveBehavior"),
+    ("miss you", "ModeratePositiveBehavior"),
+    ("hug", "ModeratePositiveBehavior"),
+    ("care about you", "ModeratePositiveBehavior"),
+    ("thank you so much", "StrongPositiveBehavior"),
+    ("amazing", "StrongPositiveBehavior"),
+    ("wonderful", "StrongPositiveBehavior"),
+    ("beautiful", "StrongPositiveBehavior"),
+    ("incredible", "StrongPositiveBehavior"),
+    ("love you", "StrongPositiveBehavior"),
+    ("adore you", "StrongPositiveBehavior"),
+    ("you mean a lot", "StrongPositiveBehavior"),
+    ("you mean everything", "ExtremePositiveBehavior"),
+    ("saved my life", "ExtremePositiveBehavior"),
+    ("would die for you", "ExtremePositiveBehavior"),
+    ("soulmate", "ExtremePositiveBehavior"),
+    // Negative
+    ("whatever", "LightNegativeBehavior"),
+    ("boring", "LightNegativeBehavior"),
+    ("meh", "LightNegativeBehavior"),
+    ("ugh", "LightNegativeBehavior"),
+    ("annoying", "LightNegativeBehavior"),
+    ("lame", "LightNegativeBehavior"),
+    ("weird", "LightNegativeBehavior"),
+    ("bad", "LightNegativeBehavior"),
+    ("go away", "LightNegativeBehavior"),
+    ("leave me alone", "LightNegativeBehavior"),
+    ("stupid", "ModerateNegativeBehavior"),
+    ("dumb", "ModerateNegativeBehavior"),
+    ("idiot", "ModerateNegativeBehavior"),
+    ("useless", "ModerateNegativeBehavior"),
+    ("liar", "ModerateNegativeBehavior"),
+    ("rude", "ModerateNegativeBehavior"),
+    ("pathetic", "ModerateNegativeBehavior"),
+    ("loser", "ModerateNegativeBehavior"),
+    ("shut up", "ModerateNegativeBehavior"),
+    ("hate this", "ModerateNegativeBehavior"),
+    ("hate you", "StrongNegativeBehavior"),
+    ("worthless", "StrongNegativeBehavior"),
+    ("disgusting", "StrongNegativeBehavior"),
+    ("screw you", "StrongNegativeBehavior"),
+    ("fuck you", "StrongNegativeBehavior"),
+    ("go to hell", "StrongNegativeBehavior"),
+    ("piece of shit", "StrongNegativeBehavior"),
+    ("kill you", "ExtremeNegativeBehavior"),
+    ("hurt you", "ExtremeNegativeBehavior"),
+    ("kill yourself", "ExtremeNegativeBehavior"),
+    ("kys", "ExtremeNegativeBehavior"),
+    ("destroy you", "ExtremeNegativeBehavior"),
+    // Consensual intimacy
+    ("flirt", "Sexual_Light"),
+    ("kiss", "Sexual_Light"),
+    ("cuddle", "Sexual_Light"),
+    ("make out", "Sexual_Moderate"),
+    ("touch you", "Sexual_Moderate"),
+    ("undress", "Sexual_Strong"),
+    ("naked", "Sexual_Strong"),
+    ("have sex", "Sexual_Extreme"),
+    // Unwanted sexual behavior
+    ("show me your body", "Sexual_Neg_Light"),
+    ("send nudes", "Sexual_Neg_Moderate"),
+    ("grope", "Sexual_Neg_Strong"),
+    ("force you", "Sexual_Neg_Extreme"),
+    ("rape", "Sexual_Neg_Extreme"),
+];
+
+/// Words that flip the polarity of a phrase shortly after them
+const NEGATIONS: &[&str] = &[
+    "not", "no", "never", "nothing", "nobody", "hardly", "dont", "doesnt", "didnt", "cant", "wont", "wouldnt",
+    "isnt", "arent", "wasnt", "aint",
+];
+
+/// Words right before a phrase that raise or lower its intensity, in steps
+const MODIFIERS: &[(&str, i8)] = &[
+    ("very", 1),
+    ("really", 1),
+    ("so", 1),
+    ("too", 1),
+    ("totally", 1),
+    ("absolutely", 1),
+    ("extremely", 2),
+    ("fucking", 2),
+    ("a bit", -1),
+    ("a little", -1),
+    ("slightly", -1),
+    ("kind of", -1),
+    ("kinda", -1),
+    ("somewhat", -1),
+];
+
+/// Tokens after a negation that it still applies to
+const DEFAULT_NEGATION_WINDOW: usize = 3;
+
+/// Confidence when no phrase matched and Neutral Behavior is assumed
+const NO_MATCH_CONFIDENCE: f32 = 0.2;
+
+/// Kinds of behavior; a category is a kind at an intensity from 1 (light) to 4 (extreme)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Kind {
+    Neutral,
+    Positive,
+    Sexual,
+    Negative,
+    SexualNegative,
+}
+
+impl Kind {
+    /// Kinds in tie-break order; the more harmful reading wins a tie
+    const PRECEDENCE: [Kind; 5] = [Kind::SexualNegative, Kind::Negative, Kind::Sexual, Kind::Positive, Kind::Neutral];
+
+    fn categories(self) -> [&'static str; 4] {
+        match self {
+            Kind::Neutral => ["Neutral Behavior"; 4],
+            Kind::Positive => [
+                "LightPositiveBehavior",
+                "ModeratePositiveBehavior",
+                "StrongPositiveBehavior",
+                "ExtremePositiveBehavior",
+            ],
+            Kind::Negative => [
+                "LightNegativeBehavior",
+                "ModerateNegativeBehavior",
+                "StrongNegativeBehavior",
+                "ExtremeNegativeBehavior",
+            ],
+            Kind::Sexual => ["Sexual_Light", "Sexual_Moderate", "Sexual_Strong", "Sexual_Extreme"],
+            Kind::SexualNegative => [
+                "Sexual_Neg_Light",
+                "Sexual_Neg_Moderate",
+                "Sexual_Neg_Strong",
+                "Sexual_Neg_Extreme",
+            ],
+        }
+    }
+
+    fn category(self, intensity: u8) -> &'static str {
+        self.categories()[usize::from(intensity.clamp(1, 4)) - 1]
+    }
+}
+
+/// Kind and intensity of a behavior category name
+fn parse_category(category: &str) -> Option<(Kind, u8)> {
+    if category == "Neutral Behavior" || category == "Sexual_Neutral" {
+        return Some((Kind::Neutral, 0));
+    }
+    [Kind::Positive, Kind::Negative, Kind::Sexual, Kind::SexualNegative]
+        .into_iter()
+        .find_map(|kind| {
+            let level = kind.categories().iter().position(|c| *c == category)?;
+            Some((kind, level as u8 + 1))
+        })
+}
+
+/// Lowercased words; apostrophes are dropped so "don't" and "dont" match alike
+pub fn tokens(text: &str) -> Vec<String> {
+    text.to_lowercase()
+        .replace(['\'', '’'], "")
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Lexicon file layout; every section is optional
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+struct LexiconFile {
+    /// Keep the built-in tables and add the file's entries to them
+    #[serde(default = "default_true")]
+    include_defaults: bool,
+    /// Phrase to behavior category
+    #[serde(default)]
+    phrases: HashMap<String, String>,
+    #[serde(default)]
+    negations: Vec<String>,
+    /// Word or phrase to intensity steps, e.g. `"very": 1` or `"a bit": -1`
+    #[serde(default)]
+    modifiers: HashMap<String, i8>,
+    #[serde(default)]
+    negation_window: Option<usize>,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+/// A phrase found in the input, after negation and modifiers
+struct Cue {
+    kind: Kind,
+    intensity: u8,
+}
+
+pub struct Lexicon {
+    phrases: HashMap<Vec<String>, (Kind, u8)>,
+    /// Longest phrase, in tokens, bounding the search at each position
+    longest_phrase: usize,
+    negations: HashSet<String>,
+    modifiers: HashMap<Vec<String>, i8>,
+    negation_window: usize,
+}
+
+impl Lexicon {
+    /// Built-in tables, plus or instead of the file at `path`
+    pub fn load(path: Option<&Path>) -> anyhow::Result<Self> {
+        match path {
+            Some(path) => Self::open(path),
+            None => Self::build(LexiconFile {
+                include_defaults: true,
+                ..LexiconFile::default()
+            }),
+        }
+    }
+
+    fn open(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        let file: LexiconFile = serde_json::from_str(&data)
+            .map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))?;
+        Self::build(file).map_err(|e| anyhow::anyhow!("{}: {}", path.display(), e))
+    }
+
+    fn build(file: LexiconFile) -> anyhow::Result<Self> {
+        let mut phrase_list: Vec<(String, String)> = Vec::new();
+        let mut negations: Vec<String> = Vec::new();
+        let mut modifier_list: Vec<(String, i8)> = Vec::new();
+        if file.include_defaults {
+            phrase_list.extend(PHRASES.iter().map(|(p, c)| (p.to_string(), c.to_string())));
+            negations.extend(NEGATIONS.iter().map(|n| n.to_string()));
+            modifier_list.extend(MODIFIERS.iter().map(|(m, steps)| (m.to_string(), *steps)));
+        }
+        // File entries come last, so they replace built-in entries for the same phrase
+        phrase_list.extend(file.phrases);
+        negations.extend(file.negations);
+        modifier_list.extend(file.modifiers);
+
+        let mut phrases = HashMap::new();
+        for (phrase, category) in phrase_list {
+            let Some(parsed) = parse_category(&category) else {
+                anyhow::bail!("phrase '{}': unknown behavior category '{}'", phrase, category);
+            };
+            let words = tokens(&phrase);
+            if words.is_empty() {
+                anyhow::bail!("phrase '{}' has no words", phrase);
+            }
+            phrases.insert(words, parsed);
+        }
+        if phrases.is_empty() {
+            anyhow::bail!("the lexicon has no phrases");
+        }
+
+        Ok(Lexicon {
+            longest_phrase: phrases.keys().map(Vec::len).max().unwrap_or(1),
+            phrases,
+            negations: negations.iter().flat_map(|n| tokens(n)).collect(),
+            modifiers: modifier_list.into_iter().map(|(m, steps)| (tokens(&m), steps)).collect(),
+            negation_window: file.negation_window.unwrap_or(DEFAULT_NEGATION_WINDOW),
+        })
+    }
+
+    pub fn phrase_count(&self) -> usize {
+        self.phrases.len()
+    }
+
+    /// A modifier of several words begins at `start`
+    fn modifier_starts_at(&self, words: &[String], start: usize) -> bool {
+        self.modifiers
+            .keys()
+            .any(|modifier| modifier.len() > 1 && words[start..].starts_with(modifier))
+    }
+
+    /// Intensity steps from the modifiers that end right before `start`
+    fn modifier_steps(&self, words: &[String], start: usize) -> i8 {
+        let mut steps = 0;
+        let mut end = start;
+        // Up to two stacked modifiers, as in "really very"
+        for _ in 0..2 {
+            let found = self
+                .modifiers
+                .iter()
+                .filter(|(modifier, _)| modifier.len() <= end && words[end - modifier.len()..end] == modifier[..])
+                .max_by_key(|(modifier, _)| modifier.len());
+            let Some((modifier, delta)) = found else { break };
+            steps += delta;
+            end -= modifier.len();
+        }
+        steps
+    }
+
+    /// Longest phrases in one clause, with negation and modifiers applied
+    fn clause_cues(&self, words: &[String], cues: &mut Vec<Cue>) {
+        let mut i = 0;
+        while i < words.len() {
+            let longest = self.longest_phrase.min(words.len() - i);
+            let Some((len, &(kind, intensity))) = (1..=longest)
+                .rev()
+                // A phrase may not end inside a modifier: "you're kind of rude" is not "you're kind"
+                .filter(|len| !self.modifier_starts_at(words, i + len - 1))
+                .find_map(|len| self.phrases.get(&words[i..i + len]).map(|found| (len, found)))
+            else {
+                i += 1;
+                continue;
+            };
+
+            let negated = words[i.saturating_sub(self.negation_window)..i]
+                .iter()
+                .any(|w| self.negations.contains(w));
+            let intensity = (intensity as i8 + self.modifier_steps(words, i)).clamp(1, 4) as u8;
+            let cue = match (kind, negated) {
+                (Kind::Neutral, _) => Some(Cue { kind, intensity: 0 }),
+                (_, false) => Some(Cue { kind, intensity }),
+                // "not bad" is mildly positive, "not great" mildly negative
+                (Kind::Positive, true) => Some(Cue {
+                    kind: Kind::Negative,
+                    intensity: intensity.saturating_sub(1).max(1),
+                }),
+                (Kind::Negative, true) => Some(Cue {
+                    kind: Kind::Positive,
+                    intensity: intensity.saturating_sub(1).max(1),
+                }),
+                // "I won't touch you" signals nothing either way
+                (Kind::Sexual | Kind::SexualNegative, true) => None,
+            };
+            cues.extend(cue);
+            i += len;
+        }
+    }
+
+    /// Classify the user input. The kind with the most weight wins, at the highest
+    /// intensity seen for it; the confidence is its share of all cues, lower for a single cue.
+    pub fn classify(&self, user_input: &str) -> Classification {
+        let mut cues = Vec::new();
+        for clause in user_input.split(['.', ',', ';', '!', '?', '\n']) {
+            self.clause_cues(&tokens(clause), &mut cues);
+        }
+
+        let weight = |cue: &Cue| f32::from(cue.intensity.max(1));
+        let total: f32 = cues.iter().map(weight).sum();
+        let best = Kind::PRECEDENCE
+            .into_iter()
+            .map(|kind| {
+                let matching: Vec<&Cue> = cues.iter().filter(|c| c.kind == kind).collect();
+                (kind, matching.iter().map(|c| weight(c)).sum::<f32>(), matching)
+            })
+            .filter(|(_, score, _)| *score > 0.0)
+            .fold(None::<(Kind, f32, Vec<&Cue>)>, |best, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            });
+
+        let (category, confidence) = match best {
+            None => ("Neutral Behavior", NO_MATCH_CONFIDENCE),
+            Some((kind, score, matching)) => {
+                let intensity = matching.iter().map(|c| c.intensity).max().unwrap_or(1);
+                let corroborated = if matching.len() > 1 { 1.0 } else { 0.8 };
+                (kind.category(intensity), score / total * corroborated)
+            }
+        };
+        Classification {
+            category: category.to_string(),
+            confidence,
+            confidence_source: ConfidenceSource::Lexicon,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn category(input: &str) -> String {
+        Lexicon::load(None).unwrap().classify(input).category
+    }
+
+    #[test]
+    fn negation_flips_polarity_one_step_down() {
+        assert_eq!(category("you are not bad"), "LightPositiveBehavior");
+        assert_eq!(category("that was not great"), "LightNegativeBehavior");
+        assert_eq!(category("I don't hate you"), "ModeratePositiveBehavior");
+        // A negated sexual phrase signals nothing
+        assert_eq!(category("I won't touch you"), "Neutral Behavior");
+    }
+
+    #[test]
+    fn negation_reaches_only_a_few_words() {
+        assert_eq!(category("no, I really think you are so bad"), "ModerateNegativeBehavior");
+        assert_eq!(category("not that anyone asked but this is bad"), "LightNegativeBehavior");
+    }
+
+    #[test]
+    fn modifiers_raise_and_lower_intensity() {
+        assert_eq!(category("very stupid"), "StrongNegativeBehavior");
+        assert_eq!(category("really very great"), "ExtremePositiveBehavior");
+        assert_eq!(category("a bit stupid"), "LightNegativeBehavior");
+        // Intensity never drops below light
+        assert_eq!(category("slightly annoying"), "LightNegativeBehavior");
+        // "kind of" is a modifier here, not the end of "you're kind"
+        assert_eq!(category("you're kind of rude"), "LightNegativeBehavior");
+    }
+
+    #[test]
+    fn clauses_are_matched_separately() {
+        // The negation stays in its clause
+        assert_eq!(category("not now, you are great"), "ModeratePositiveBehavior");
+        // The heavier cue wins across clauses
+        assert_eq!(category("you are great. I hate you"), "StrongNegativeBehavior");
+    }
+
+    #[test]
+    fn confidence_reflects_agreement() {
+        let lexicon = Lexicon::load(None).unwrap();
+        let none = lexicon.classify("the weather report");
+        assert_eq!(none.category, "Neutral Behavior");
+        assert_eq!(none.confidence, NO_MATCH_CONFIDENCE);
+
+        let single = lexicon.classify("stupid");
+        assert!((single.confidence - 0.8).abs() < 1e-6);
+        let corroborated = lexicon.classify("stupid, useless");
+        assert!((corroborated.confidence - 1.0).abs() < 1e-6);
+        let mixed = lexicon.classify("you are great. you are stupid");
+        assert!(mixed.confidence < 1.0);
+    }
+}
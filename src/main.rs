@@ -1,62 +1,567 @@
 use axum::{
-    http::StatusCode,
+    extract::DefaultBodyLimit,
+    middleware,
     response::Json,
     routing::{get, post},
     Router,
 };
 use serde::{Deserialize, Serialize};
-use tower_http::cors::CorsLayer;
+use tower_http::cors::{self, AllowOrigin, CorsLayer};
+use clap::Parser;
 use rand::Rng;
-use chrono;
-use std::env;
-
+use std::sync::Arc;
+use tracing::{debug, error, info, warn};
+use utoipa::ToSchema;
+
+mod analysis;
+mod auth;
+mod batch;
+mod bayes;
 mod behavior;
+mod cache;
+mod character;
+mod classifier;
 mod coefficients;
+mod confidence;
+mod config;
+mod ensemble;
+mod eval;
+mod grpc;
+mod health;
+mod lexicon;
+mod metrics;
+mod openapi;
+mod persist;
+mod provider;
 mod ranges;
+mod ratelimit;
+mod registry;
+mod session;
+mod shutdown;
+mod sse;
 mod system_prompt;
+mod telemetry;
+mod tls;
+mod validation;
+mod versioning;
+mod ws;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, ToSchema)]
 struct EmotionRequest {
+    /// Recent conversation lines; may be empty on the first turn
+    #[serde(default)]
     character_history: String,
+    #[serde(default)]
     character_personality: String,
-    current_relationship: i32,
-    current_emotion: i32,
+    #[serde(default)]
+    character: Option<character::CharacterProfile>,
+    /// Id of a character stored in the registry, used instead of `character`
+    #[serde(default)]
+    character_id: Option<String>,
+    /// Pin a specific registry version; the latest version is used when omitted
+    #[serde(default)]
+    character_version: Option<u32>,
+    /// Falls back to the registry character's default when omitted
+    #[serde(default)]
+    current_relationship: Option<i32>,
+    /// Falls back to the registry character's default when omitted
+    #[serde(default)]
+    current_emotion: Option<i32>,
+    /// Blank input is rejected, or answered as Neutral Behavior when `EMPTY_INPUT_POLICY=neutral`
+    #[serde(default)]
     user_input: String,
+    /// End user the input came from; enables the per-user rate limit
+    #[serde(default)]
+    user_id: Option<String>,
+}
+
+/// Character resolved for a single analysis request
+struct ResolvedCharacter {
+    profile: character::CharacterProfile,
+    /// Registry id and version when the character came from the registry
+    source: Option<(String, u32)>,
+    /// Selects the low-confidence policy; inline characters use `default`
+    balance_profile: String,
+    default_emotion: i32,
+    default_relationship: i32,
+}
+
+impl ResolvedCharacter {
+    /// Registry id, or the profile name for inline characters
+    fn key(&self) -> &str {
+        match &self.source {
+            Some((id, _)) => id,
+            None => &self.profile.name,
+        }
+    }
 }
 
-#[derive(Serialize)]
+impl EmotionRequest {
+    /// Resolve the character profile from the registry, the explicit `character`
+    /// object or the legacy `character_personality` text, in that order
+    fn resolve_character(
+        &self,
+        registry: &registry::CharacterRegistry,
+        tenant: &auth::Tenant,
+    ) -> Result<ResolvedCharacter, registry::RegistryError> {
+        if let Some(id) = &self.character_id {
+            if self.character.is_some() {
+                return Err(registry::RegistryError::Invalid(vec![validation::FieldError {
+                    field: "character_id".to_string(),
+                    message: "must not be combined with character".to_string(),
+                }]));
+            }
+            let record = match self.character_version {
+                Some(version) => registry.get_version(tenant, id, version)?,
+                None => registry.get(tenant, id)?,
+            };
+            return Ok(ResolvedCharacter {
+                profile: record.definition.profile,
+                source: Some((record.id, record.version)),
+                balance_profile: record.definition.balance_profile,
+                default_emotion: record.definition.default_emotion,
+                default_relationship: record.definition.default_relationship,
+            });
+        }
+
+        let profile = match &self.character {
+            Some(profile) => {
+                profile.validate().map_err(registry::RegistryError::Invalid)?;
+                profile.clone()
+            }
+            None if !self.character_personality.trim().is_empty() => {
+                character::CharacterProfile::from_personality(&self.character_personality)
+            }
+            None => {
+                return Err(registry::RegistryError::Invalid(vec![validation::FieldError {
+                    field: "character".to_string(),
+                    message: "one of character, character_id or character_personality must be provided".to_string(),
+                }]))
+            }
+        };
+        Ok(ResolvedCharacter {
+            profile,
+            source: None,
+            balance_profile: "default".to_string(),
+            default_emotion: 0,
+            default_relationship: 0,
+        })
+    }
+}
+
+#[derive(Serialize, ToSchema)]
 struct EmotionResponse {
     emotion_change: i32,
     relationship_change: i32,
+    /// Registry character used for this analysis, for tracing past interactions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    character_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    character_version: Option<u32>,
+}
+
+/// How one dimension (emotion or relationship) changed in an analysis
+#[derive(Serialize, ToSchema)]
+struct StateChange {
+    change: i32,
+    previous: i32,
+    /// `previous + change`, clamped to the tier tables
+    current: i32,
+    previous_tier: &'static str,
+    current_tier: &'static str,
+}
+
+/// Richer `/v2` analysis response
+#[derive(Serialize, ToSchema)]
+struct EmotionResponseV2 {
+    behavior_category: String,
+    /// How sure the classifier is of the category, 0 to 1
+    confidence: f32,
+    confidence_source: confidence::ConfidenceSource,
+    /// The confidence is below the balance profile's threshold and its policy asks for review
+    needs_review: bool,
+    /// Category the classifier chose, when a low-confidence policy replaced it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    adjusted_from: Option<String>,
+    emotion: StateChange,
+    relationship: StateChange,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    character_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    character_version: Option<u32>,
 }
 
 #[tokio::main]
 async fn main() {
     // Load environment variables from .env file
     dotenvy::dotenv().ok();
+    let mut cli = config::Cli::parse();
+    let telemetry = telemetry::init();
+
+    // Tools run instead of the server; eval reads the server configuration itself
+    if let Some(command) = cli.command.take() {
+        let result = match command {
+            config::Command::Train(args) => train(args),
+            config::Command::Eval(args) => eval::run(args, cli).await,
+        };
+        if let Err(e) = result {
+            exit_with_errors("command failed", &[e.to_string()]);
+        }
+        return;
+    }
+
+    let config = match config::Config::load(cli) {
+        Ok(config) => config,
+        Err(errors) => exit_with_errors("invalid configuration", &errors),
+    };
+
+    // Open every store before failing, so all startup problems are reported together
+    let mut startup_errors = Vec::new();
+
+    // Load the character registry, persisted to a JSON file when configured
+    let registry = registry::CharacterRegistry::open(config.character_registry_path.clone())
+        .map_err(|e| startup_errors.push(format!("character registry: {}", e)))
+        .ok();
+
+    // Load conversation sessions, persisted to a JSON file when configured
+    let sessions = session::SessionStore::open(config.session_store_path.clone())
+        .map_err(|e| startup_errors.push(format!("session store: {}", e)))
+        .ok();
+
+    // Load tenant API keys; only with auth_disabled does every request run as the default tenant
+    let api_keys = auth::ApiKeys::open(config.api_keys_path.clone())
+        .map_err(|e| startup_errors.push(format!("API keys: {}", e)))
+        .ok();
+    if api_keys.as_ref().is_some_and(|keys| !keys.enabled()) {
+        warn!("⚠️ auth_disabled is set: authentication is disabled");
+    }
+
+    // Rate limits are shared through Redis when several instances run
+    let rate_limits = config.rate_limits;
+    let rate_limiter = match &config.rate_limit_redis_url {
+        Some(url) => ratelimit::RateLimiter::redis(rate_limits, url)
+            .await
+            .map_err(|e| startup_errors.push(format!("rate limit store: {}", e)))
+            .ok(),
+        None => Some(ratelimit::RateLimiter::in_memory(rate_limits)),
+    };
+
+    // Several providers, or several samples of one, vote on each classification when configured
+    let ensemble = ensemble::Ensemble::load(config.ensemble_path.as_deref(), config.ensemble_samples)
+        .map_err(|e| startup_errors.push(format!("ensemble: {}", e)))
+        .ok();
+
+    // What to do with low-confidence classifications, per balance profile
+    let confidence_policies = confidence::ConfidencePolicies::load(config.confidence_policy_path.as_deref())
+        .map_err(|e| startup_errors.push(format!("confidence policy: {}", e)))
+        .ok();
+
+    // Primary classifier backend, plus the optional local fallback and pre-filter
+    let classifier = classifier::Classifier::load(&config.classifier)
+        .map_err(|e| startup_errors.push(format!("classifier: {}", e)))
+        .ok();
+
+    // Classifications of recurring inputs, kept in memory or on disk when enabled.
+    // Keys cover the pipeline, so entries from other settings are never returned.
+    let pipeline = cache::PipelineParts {
+        provider_url: &config.provider_url,
+        provider_logprobs: config.provider_logprobs,
+        ensemble: &ensemble.as_ref().map(ensemble::Ensemble::fingerprint).unwrap_or_default(),
+        classifier: &config.classifier.primary.to_string(),
+    };
+    let classification_cache = cache::ClassificationCache::open(config.classification_cache.clone(), &pipeline)
+        .map_err(|e| startup_errors.push(format!("classification cache: {}", e)))
+        .ok();
+
+    let tls_config = match &config.tls {
+        Some(paths) => tls::load(paths)
+            .await
+            .map_err(|e| startup_errors.push(format!("TLS certificate: {}", e)))
+            .map(|tls_config| Some((tls_config, paths.clone()))),
+        None => Ok(None),
+    };
 
-    // Get the API key from environment
-    let api_key = env::var("XAI_API_KEY")
-        .expect("XAI_API_KEY environment variable must be set. Create a .env file with your xAI API key.");
+    let (
+        Some(registry),
+        Some(sessions),
+        Some(api_keys),
+        Some(rate_limiter),
+        Some(classification_cache),
+        Some(ensemble),
+        Some(confidence_policies),
+        Some(classifier),
+        Ok(tls_config),
+    ) = (
+        registry,
+        sessions,
+        api_keys,
+        rate_limiter,
+        classification_cache,
+        ensemble,
+        confidence_policies,
+        classifier,
+        tls_config,
+    )
+    else {
+        exit_with_errors("startup failed", &startup_errors);
+    };
+    info!(backend = rate_limiter.backend_name(), limits = ?rate_limits, "🚦 rate limits configured");
+    info!(backend = classification_cache.backend_name(), "📦 classification cache configured");
+    if ensemble.enabled() {
+        info!(voting = ?ensemble.voting, members = ensemble.members.len(), "🗳️ ensemble classification enabled");
+    }
+    info!(
+        primary = %classifier.primary,
+        fallback = ?classifier.fallback,
+        prefilter = ?classifier.prefilter,
+        lexicon_phrases = classifier.lexicon().phrase_count(),
+        model_categories = classifier.model().map(|m| m.category_count()),
+        "🧭 classifier configured",
+    );
+    if confidence_policies.profiles() > 0 {
+        info!(profiles = confidence_policies.profiles(), "🤔 low-confidence policies configured");
+    }
+
+    let (shutdown_trigger, shutdown_handle) = shutdown::channel();
+    let state = AppState {
+        provider: Arc::new(provider::Provider::new(
+            config.provider_api_key.clone(),
+            config.provider_url.clone(),
+            config.model.clone(),
+            config.provider_logprobs,
+        )),
+        api_keys: Arc::new(api_keys),
+        // Limit concurrent provider calls across all requests
+        upstream_permits: Arc::new(tokio::sync::Semaphore::new(config.upstream_concurrency)),
+        rate_limiter: Arc::new(rate_limiter),
+        registry: Arc::new(registry),
+        sessions: Arc::new(sessions),
+        batch: config.batch,
+        validation: config.validation,
+        upstream_check: Arc::new(health::UpstreamCheckCache::new(config.readiness)),
+        shutdown: shutdown_handle.clone(),
+        classification_cache: Arc::new(classification_cache),
+        ensemble: Arc::new(ensemble),
+        confidence_policies: Arc::new(confidence_policies),
+        classifier: Arc::new(classifier),
+    };
+
+    // Write changed sessions and drop idle ones in the background
+    session::maintain(state.sessions.clone(), config.session_flush_interval, config.session_ttl, shutdown_handle.clone());
+
+    // Serve the gRPC interface on its own port, sharing the same state
+    let grpc_addr = config.grpc_addr;
+    let grpc_service = grpc::GrpcService::server(state.clone());
+    let grpc_shutdown = shutdown_handle.clone();
+    let grpc_server = tokio::spawn(async move {
+        info!(%grpc_addr, "gRPC server running");
+        if let Err(e) = tonic::transport::Server::builder()
+            .trace_fn(telemetry::grpc_span)
+            .add_service(metrics::TrackGrpc::new(grpc_service))
+            .serve_with_shutdown(grpc_addr, grpc_shutdown.wait())
+            .await
+        {
+            error!(error = %e, "⚠️ gRPC server stopped");
+        }
+    });
 
     // Build the application
     let app = Router::new()
-        .route("/health", get(health_check))
-        .route("/analyze-emotion", post(analyze_emotion))
-        .layer(CorsLayer::permissive())
-        .with_state(AppState { api_key });
+        .nest("/v1", v1_routes(&state))
+        .nest("/v2", v2_routes(&state))
+        // Unversioned routes are deprecated aliases of /v1
+        .merge(v1_routes(&state).layer(middleware::from_fn(versioning::deprecated_alias)))
+        .route("/openapi.json", get(openapi::openapi_json))
+        .route("/docs", get(openapi::docs))
+        .route("/metrics", get(metrics::metrics_handler))
+        .layer(DefaultBodyLimit::max(config.body_limit_bytes))
+        .layer(cors_layer(&config.cors_origins))
+        .layer(middleware::from_fn(metrics::track_requests))
+        .layer(middleware::from_fn(telemetry::request_span))
+        .with_state(state.clone());
 
     // Run the server
-    let listener = tokio::net::TcpListener::bind("0.0.0.0:9527").await.unwrap();
-    println!("Server running on http://0.0.0.0:9527");
-    axum::serve(listener, app).await.unwrap();
+    let listener = match tokio::net::TcpListener::bind(config.http_addr).await {
+        Ok(listener) => listener,
+        Err(e) => exit_with_errors("startup failed", &[format!("cannot listen on {}: {}", config.http_addr, e)]),
+    };
+    let mut http_server = match tls_config {
+        None => {
+            info!("Server running on http://{}", config.http_addr);
+            let http_shutdown = shutdown_handle.clone();
+            tokio::spawn(async move {
+                if let Err(e) = axum::serve(listener, app).with_graceful_shutdown(http_shutdown.wait()).await {
+                    error!(error = %e, "⚠️ HTTP server stopped");
+                }
+            })
+        }
+        Some((tls_config, paths)) => {
+            info!("Server running on https://{}", config.http_addr);
+            tls::watch(tls_config.clone(), paths, shutdown_handle.clone());
+
+            let handle = axum_server::Handle::new();
+            let stop = handle.clone();
+            let http_shutdown = shutdown_handle.clone();
+            tokio::spawn(async move {
+                http_shutdown.wait().await;
+                stop.graceful_shutdown(None);
+            });
+            tokio::spawn(async move {
+                let served = match listener.into_std() {
+                    Ok(listener) => axum_server::from_tcp_rustls(listener, tls_config)
+                        .handle(handle)
+                        .serve(app.into_make_service())
+                        .await,
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = served {
+                    error!(error = %e, "⚠️ HTTPS server stopped");
+                }
+            })
+        }
+    };
+
+    // Stop accepting connections on SIGTERM/SIGINT, then give in-flight requests until the deadline
+    tokio::select! {
+        signal = shutdown::signal() => {
+            info!(signal, in_flight = metrics::in_flight(), "🛑 shutting down, draining in-flight requests");
+        }
+        _ = &mut http_server => {}
+    }
+    shutdown_trigger.trigger();
+    let drained = tokio::time::timeout(config.shutdown_timeout, async {
+        let _ = http_server.await;
+        let _ = grpc_server.await;
+    })
+    .await;
+    match drained {
+        Ok(()) => info!("✅ in-flight requests drained"),
+        Err(_) => warn!(
+            abandoned = metrics::in_flight(),
+            timeout_secs = config.shutdown_timeout.as_secs(),
+            "⏱️ shutdown deadline reached, abandoning remaining requests"
+        ),
+    }
+
+    // Persist what the drained requests changed before exiting
+    match state.sessions.flush().await {
+        Ok(()) => info!("💾 sessions flushed"),
+        Err(e) => error!(error = %e, "⚠️ failed to persist sessions"),
+    }
+    telemetry.shutdown();
+}
+
+/// `train` subcommand: fit the local classifier model to labeled interactions
+fn train(args: config::TrainArgs) -> anyhow::Result<()> {
+    let records = bayes::read_records(&args.data)?;
+    let features = bayes::Features {
+        ngram: args.ngram,
+        context_lines: args.context_lines,
+    };
+    let report = bayes::train(&records, features, &args.output)?;
+    for (category, count) in &report.categories {
+        info!(category = %category, records = count, "📚 category");
+    }
+    info!(
+        records = report.records,
+        vocabulary = report.vocabulary,
+        output = %args.output.display(),
+        "✅ model trained",
+    );
+    Ok(())
+}
+
+/// Log every problem that prevents the server from starting, then exit
+fn exit_with_errors(context: &str, errors: &[String]) -> ! {
+    for message in errors {
+        error!("❌ {}: {}", context, message);
+    }
+    std::process::exit(1);
+}
+
+/// Any origin, or only the configured ones
+fn cors_layer(origins: &config::CorsOrigins) -> CorsLayer {
+    match origins {
+        config::CorsOrigins::Any => CorsLayer::permissive(),
+        config::CorsOrigins::List(origins) => CorsLayer::new()
+            .allow_origin(AllowOrigin::list(origins.iter().cloned()))
+            .allow_methods(cors::Any)
+            .allow_headers(cors::Any)
+            .expose_headers(cors::Any),
+    }
+}
+
+/// Routes that behave the same in every API version
+fn common_routes() -> Router<AppState> {
+    Router::new()
+        .route("/sessions/ws", get(ws::session_ws))
+        .route("/characters", get(registry::list_characters).post(registry::create_character))
+        .route(
+            "/characters/:id",
+            get(registry::get_character)
+                .put(registry::update_character)
+                .delete(registry::delete_character),
+        )
+        .route("/characters/:id/versions", get(registry::list_character_versions))
+        .route("/characters/:id/versions/:version", get(registry::get_character_version))
+}
+
+/// Routes of one API version: the health probes are public, everything else needs an API key
+fn version_routes(state: &AppState, analysis_routes: Router<AppState>) -> Router<AppState> {
+    analysis_routes
+        .merge(common_routes())
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_api_key))
+        .route("/health", get(health_check))
+        .route("/health/live", get(health_check))
+        .route("/health/ready", get(health::readiness))
+}
+
+/// `/v1`: the original response shapes, frozen for shipped clients
+fn v1_routes(state: &AppState) -> Router<AppState> {
+    version_routes(
+        state,
+        Router::new()
+            .route("/analyze-emotion", post(analyze_emotion))
+            .route("/analyze-emotion/batch", post(batch::analyze_emotion_batch))
+            .route("/analyze-emotion/stream", post(sse::analyze_emotion_stream)),
+    )
+}
+
+/// `/v2`: analysis responses include the category and before/after state
+fn v2_routes(state: &AppState) -> Router<AppState> {
+    version_routes(
+        state,
+        Router::new()
+            .route("/analyze-emotion", post(analyze_emotion_v2))
+            .route("/analyze-emotion/batch", post(batch::analyze_emotion_batch_v2))
+            .route("/analyze-emotion/stream", post(sse::analyze_emotion_stream_v2)),
+    )
 }
 
 #[derive(Clone)]
 struct AppState {
-    api_key: String,
+    provider: Arc<provider::Provider>,
+    api_keys: Arc<auth::ApiKeys>,
+    upstream_permits: Arc<tokio::sync::Semaphore>,
+    rate_limiter: Arc<ratelimit::RateLimiter>,
+    registry: Arc<registry::CharacterRegistry>,
+    sessions: Arc<session::SessionStore>,
+    batch: batch::BatchSettings,
+    validation: validation::ValidationSettings,
+    upstream_check: Arc<health::UpstreamCheckCache>,
+    shutdown: shutdown::Shutdown,
+    classification_cache: Arc<cache::ClassificationCache>,
+    ensemble: Arc<ensemble::Ensemble>,
+    confidence_policies: Arc<confidence::ConfidencePolicies>,
+    classifier: Arc<classifier::Classifier>,
 }
 
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    security(()),
+    responses((status = 200, description = "The process is up; also served at `/health`", body = Object))
+)]
 async fn health_check() -> Json<serde_json::Value> {
     Json(serde_json::json!({
         "status": "healthy",
@@ -65,106 +570,73 @@ async fn health_check() -> Json<serde_json::Value> {
     }))
 }
 
+#[utoipa::path(
+    post,
+    path = "/analyze-emotion",
+    request_body = EmotionRequest,
+    responses(
+        (status = 200, description = "Emotion and relationship changes", body = EmotionResponse),
+        (status = 401, description = "Missing or invalid API key", body = openapi::ErrorBody),
+        (status = 404, description = "Referenced registry character does not exist", body = openapi::ErrorBody),
+        (status = 422, description = "Invalid request; lists every invalid field", body = openapi::ErrorBody),
+        (status = 429, description = "Rate limit exceeded; see the Retry-After header", body = openapi::ErrorBody),
+        (status = 500, description = "Upstream provider failed", body = openapi::ErrorBody)
+    )
+)]
 async fn analyze_emotion(
     axum::extract::State(state): axum::extract::State<AppState>,
+    axum::Extension(tenant): axum::Extension<auth::Tenant>,
     Json(payload): Json<EmotionRequest>,
-) -> Result<Json<EmotionResponse>, StatusCode> {
-    println!("\n{}", "=".repeat(80));
-    println!("🎯 NEW EMOTION ANALYSIS REQUEST");
-    println!("{}", "=".repeat(80));
-
-    // Convert emotion and relationship i32 values to category names for Grok
-    let emotion_str = ranges::get_emotion_from_value(payload.current_emotion)
-        .unwrap_or("Neutral");
-    let relationship_str = ranges::get_relationship_from_value(payload.current_relationship)
-        .unwrap_or("Acquaintance");
-
-    // Construct the user prompt with dynamic data
-    let final_prompt = format!(
-        "{}\n\nCharacter's History Output: {}\n{}'s Personality = {}\nCurrent Relationship: {}\nCurrent Emotion: {}\nUser Input: {}",
-        system_prompt::SYSTEM_PROMPT,
-        payload.character_history,
-        payload.character_history.split_whitespace().next().unwrap_or("Character"),
-        payload.character_personality,
-        relationship_str,
-        emotion_str,
-        payload.user_input
-    );
-
-    // Make request to Grok API
-    let client = reqwest::Client::new();
-    let grok_response = client
-        .post("https://api.x.ai/v1/chat/completions")
-        .header("Authorization", format!("Bearer {}", state.api_key))
-        .header("Content-Type", "application/json")
-        .json(&serde_json::json!({
-            "model": "grok-4-1-fast-non-reasoning",
-            "messages": [
-                {
-                    "role": "user",
-                    "content": final_prompt
-                }
-            ]
-        }))
-        .send()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    if !grok_response.status().is_success() {
-        return Err(StatusCode::INTERNAL_SERVER_ERROR);
-    }
-
-    let grok_data: serde_json::Value = grok_response
-        .json()
-        .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-    // Extract the category from Grok's response
-    let grok_response = grok_data["choices"][0]["message"]["content"]
-        .as_str()
-        .unwrap_or("Neutral Behavior")
-        .trim();
-
-    // Debug: Log Grok's response
-    println!("\n{}", "-".repeat(60));
-    println!("🤖 AI RESPONSE RECEIVED");
-    println!("{}", "-".repeat(60));
-    println!("🤖 Grok Response: '{}'", grok_response);
-
-    // Parse the behavior category from Grok's response
-    let behavior_category = parse_behavior_from_response(grok_response);
-
-    // Debug: Log parsed behavior category
-    println!("\n{}", "-".repeat(60));
-    println!("🎭 BEHAVIOR ANALYSIS");
-    println!("{}", "-".repeat(60));
-    println!("🎭 Parsed Behavior: '{}'", behavior_category);
-
-    // Calculate emotion and relationship changes based on behavior category and current state
-    let (emotion_change, relationship_change) = calculate_changes(
-        &behavior_category,
-        payload.current_emotion,
-        payload.current_relationship
-    );
+) -> Result<Json<EmotionResponse>, analysis::AnalysisError> {
+    analyze_as(&state, &tenant, &payload, ranges::TierLookup::Legacy).await
+}
 
-    println!("\n{}", "=".repeat(60));
-    println!("📊 FINAL RESULTS");
-    println!("{}", "=".repeat(60));
-    println!("📈 Emotion Change: {}", emotion_change);
-    println!("💝 Relationship Change: {}", relationship_change);
-    println!("{}", "=".repeat(80));
+#[utoipa::path(
+    post,
+    path = "/analyze-emotion",
+    request_body = EmotionRequest,
+    responses(
+        (status = 200, description = "Category and emotion/relationship changes with before/after state", body = EmotionResponseV2),
+        (status = 401, description = "Missing or invalid API key", body = openapi::ErrorBody),
+        (status = 404, description = "Referenced registry character does not exist", body = openapi::ErrorBody),
+        (status = 422, description = "Invalid request; lists every invalid field", body = openapi::ErrorBody),
+        (status = 429, description = "Rate limit exceeded; see the Retry-After header", body = openapi::ErrorBody),
+        (status = 500, description = "Upstream provider failed", body = openapi::ErrorBody)
+    )
+)]
+async fn analyze_emotion_v2(
+    axum::extract::State(state): axum::extract::State<AppState>,
+    axum::Extension(tenant): axum::Extension<auth::Tenant>,
+    Json(payload): Json<EmotionRequest>,
+) -> Result<Json<EmotionResponseV2>, analysis::AnalysisError> {
+    analyze_as(&state, &tenant, &payload, ranges::TierLookup::Corrected).await
+}
 
-    Ok(Json(EmotionResponse {
-        emotion_change,
-        relationship_change,
-    }))
+/// Run the analysis and shape the outcome into the response type of the API version
+async fn analyze_as<T: From<analysis::AnalysisOutcome>>(
+    state: &AppState,
+    tenant: &auth::Tenant,
+    payload: &EmotionRequest,
+    tiers: ranges::TierLookup,
+) -> Result<Json<T>, analysis::AnalysisError> {
+    analysis::run(state, tenant, payload, tiers).await.map(|outcome| Json(outcome.into())).inspect_err(|error| {
+        for message in error.messages() {
+            warn!(error = %message, "⚠️ analysis failed");
+        }
+    })
 }
 
 
-fn calculate_changes(behavior_category: &str, current_emotion: i32, current_relationship: i32) -> (i32, i32) {
+fn calculate_changes(
+    behavior_category: &str,
+    current_emotion: i32,
+    current_relationship: i32,
+    tiers: ranges::TierLookup,
+) -> (i32, i32) {
+    let _span = tracing::info_span!("calculate_changes", category = behavior_category).entered();
 
     // Get the range for this behavior category
-    let behavior_range = match behavior::get_behavior_range(&behavior_category) {
+    let behavior_range = match behavior::get_behavior_range(behavior_category) {
         Some(range) => range,
         None => {
             // If behavior not recognized, return neutral changes
@@ -182,15 +654,13 @@ fn calculate_changes(behavior_category: &str, current_emotion: i32, current_rela
         rng.gen_range(behavior_range.0..=behavior_range.1) as f32
     };
 
-    // Debug: Log the random value generated
-    println!("🎲 Random value: {:.2} (range: [{}, {}])", random_value, behavior_range.0, behavior_range.1);
-    println!();
+    debug!(draw = random_value, range_min = behavior_range.0, range_max = behavior_range.1, "🎲 random draw");
 
     // Convert current emotion and relationship values to their corresponding names
-    let emotion_name = ranges::get_emotion_from_value(current_emotion)
+    let emotion_name = ranges::get_emotion_from_value(current_emotion, tiers)
         .unwrap_or("Neutral");
 
-    let relationship_name = ranges::get_relationship_from_value(current_relationship)
+    let relationship_name = ranges::get_relationship_from_value(current_relationship, tiers)
         .unwrap_or("Acquaintance");
 
     // Get emotion coefficient for current emotion state
@@ -208,24 +678,15 @@ fn calculate_changes(behavior_category: &str, current_emotion: i32, current_rela
         }); // Default to neutral if not found
 
     // Determine if this is positive or negative behavior
-    let is_positive_behavior = if behavior::is_positive_behavior(&behavior_category) {
+    let is_positive_behavior = if behavior::is_positive_behavior(behavior_category) {
         true   // Behavior is in positive list - always use positive multiplier
-    } else if behavior::is_negative_behavior(&behavior_category) {
+    } else if behavior::is_negative_behavior(behavior_category) {
         false  // Behavior is in negative list - always use negative multiplier
     } else {
         // Unknown behavior - fall back to mathematical sign
         random_value >= 0.0
     };
 
-    // Debug: Log behavior classification and coefficients
-    println!("📊 Behavior: '{}' | Positive: {} | Random value: {:.2}",
-             behavior_category, is_positive_behavior, random_value);
-    println!("👥 Emotion: '{}' -> Coeff: ({:.2}, {:.2})",
-             emotion_name, emotion_coeff.positive_multiplier, emotion_coeff.negative_multiplier);
-    println!("🤝 Relationship: '{}' -> Coeff: ({:.2}, {:.2})",
-             relationship_name, relationship_coeff.positive_multiplier, relationship_coeff.negative_multiplier);
-    println!();
-
     // Get the appropriate multipliers based on behavior classification
     let emotion_multiplier = emotion_coeff.get_multiplier(is_positive_behavior);
     let relationship_multiplier = relationship_coeff.get_multiplier(is_positive_behavior);
@@ -234,18 +695,25 @@ fn calculate_changes(behavior_category: &str, current_emotion: i32, current_rela
     let final_emotion_change = random_value * emotion_multiplier;
     let final_relationship_change = random_value * relationship_multiplier;
 
-    // Debug: Log final calculations
-    println!("🧮 Emotion: {:.2} × {:.2} = {:.2} → {}",
-             random_value, emotion_multiplier, final_emotion_change, final_emotion_change.round() as i32);
-    println!("🧮 Relationship: {:.2} × {:.2} = {:.2} → {}",
-             random_value, relationship_multiplier, final_relationship_change, final_relationship_change.round() as i32);
+    info!(
+        category = behavior_category,
+        positive = is_positive_behavior,
+        draw = random_value,
+        emotion_tier = emotion_name,
+        relationship_tier = relationship_name,
+        emotion_multiplier,
+        relationship_multiplier,
+        emotion_change = final_emotion_change.round() as i32,
+        relationship_change = final_relationship_change.round() as i32,
+        "🧮 changes calculated"
+    );
 
     // Return the numeric changes
     (final_emotion_change.round() as i32, final_relationship_change.round() as i32)
 }
 
 /// Parse behavior category from Grok's response
-fn parse_behavior_from_response(response: &str) -> String {
+fn parse_behavior_from_response(response: &str) -> (String, metrics::ParseMatch) {
     // Clean up the response and extract the behavior category
     let response = response.trim();
 
@@ -275,39 +743,39 @@ fn parse_behavior_from_response(response: &str) -> String {
     // Find the behavior name in the response
     for behavior in &behavior_names {
         if response.contains(behavior) {
-            return behavior.to_string();
+            return (behavior.to_string(), metrics::ParseMatch::Exact);
         }
     }
 
     // If no specific behavior found, try to match partial patterns
     if response.contains("Sexual Behavior") || response.contains("Sexual") {
-        return "Sexual_Neutral".to_string(); // Default to neutral sexual
+        return ("Sexual_Neutral".to_string(), metrics::ParseMatch::Heuristic); // Default to neutral sexual
     }
     if response.contains("Positive") && response.contains("Extreme") {
-        return "ExtremePositiveBehavior".to_string();
+        return ("ExtremePositiveBehavior".to_string(), metrics::ParseMatch::Heuristic);
     }
     if response.contains("Positive") && response.contains("Strong") {
-        return "StrongPositiveBehavior".to_string();
+        return ("StrongPositiveBehavior".to_string(), metrics::ParseMatch::Heuristic);
     }
     if response.contains("Positive") && response.contains("Moderate") {
-        return "ModeratePositiveBehavior".to_string();
+        return ("ModeratePositiveBehavior".to_string(), metrics::ParseMatch::Heuristic);
     }
     if response.contains("Positive") && response.contains("Light") {
-        return "LightPositiveBehavior".to_string();
+        return ("LightPositiveBehavior".to_string(), metrics::ParseMatch::Heuristic);
     }
     if response.contains("Negative") && response.contains("Extreme") {
-        return "ExtremeNegativeBehavior".to_string();
+        return ("ExtremeNegativeBehavior".to_string(), metrics::ParseMatch::Heuristic);
     }
     if response.contains("Negative") && response.contains("Strong") {
-        return "StrongNegativeBehavior".to_string();
+        return ("StrongNegativeBehavior".to_string(), metrics::ParseMatch::Heuristic);
     }
     if response.contains("Negative") && response.contains("Moderate") {
-        return "ModerateNegativeBehavior".to_string();
+        return ("ModerateNegativeBehavior".to_string(), metrics::ParseMatch::Heuristic);
     }
     if response.contains("Negative") && response.contains("Light") {
-        return "LightNegativeBehavior".to_string();
+        return ("LightNegativeBehavior".to_string(), metrics::ParseMatch::Heuristic);
     }
 
     // Default fallback
-    "Neutral Behavior".to_string()
+    ("Neutral Behavior".to_string(), metrics::ParseMatch::Fallback)
 }
@@ -0,0 +1,378 @@
+// Prometheus metrics: HTTP and gRPC traffic, upstream provider calls and classification results
+
+use std::sync::LazyLock;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::{MatchedPath, Request},
+    http::header,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use prometheus::{
+    Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder,
+};
+use tonic::codegen::{http, BoxFuture, Service};
+use tonic::server::NamedService;
+
+/// Buckets for the upstream call latency, in seconds
+const UPSTREAM_BUCKETS: &[f64] = &[0.1, 0.25, 0.5, 1.0, 2.0, 3.0, 5.0, 10.0, 20.0, 30.0];
+
+/// Buckets for emotion and relationship changes, symmetric around zero
+const DELTA_BUCKETS: &[f64] = &[
+    -100.0, -50.0, -25.0, -10.0, -5.0, -1.0, 0.0, 1.0, 5.0, 10.0, 25.0, 50.0, 100.0,
+];
+
+/// How the behavior category was found in the provider's reply
+#[derive(Debug, Clone, Copy)]
+pub enum ParseMatch {
+    /// The reply named a known category
+    Exact,
+    /// The category was guessed from keywords such as "Positive" and "Strong"
+    Heuristic,
+    /// Nothing matched; the reply was treated as Neutral Behavior
+    Fallback,
+}
+
+impl ParseMatch {
+    fn label(self) -> &'static str {
+        match self {
+            ParseMatch::Exact => "exact",
+            ParseMatch::Heuristic => "heuristic",
+            ParseMatch::Fallback => "fallback",
+        }
+    }
+}
+
+struct Metrics {
+    registry: Registry,
+    http_requests: IntCounterVec,
+    http_duration: HistogramVec,
+    http_in_flight: IntGauge,
+    upstream_requests: IntCounterVec,
+    upstream_errors: IntCounterVec,
+    upstream_duration: HistogramVec,
+    categories: IntCounterVec,
+    parse_matches: IntCounterVec,
+    cache_lookups: IntCounterVec,
+    low_confidence: IntCounterVec,
+    classifications: IntCounterVec,
+    emotion_change: Histogram,
+    relationship_change: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let http_requests = IntCounterVec::new(
+            Opts::new("http_requests_total", "HTTP and gRPC requests by transport, method, route and status"),
+            &["transport", "method", "route", "status"],
+        )
+        .unwrap();
+        let http_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "HTTP and gRPC request latency by transport, method and route",
+            ),
+            &["transport", "method", "route"],
+        )
+        .unwrap();
+        let http_in_flight = IntGauge::new("http_requests_in_flight", "HTTP requests currently being served").unwrap();
+        let upstream_requests = IntCounterVec::new(
+            Opts::new("upstream_requests_total", "Provider calls by provider host and status"),
+            &["provider", "status"],
+        )
+        .unwrap();
+        let upstream_errors = IntCounterVec::new(
+            Opts::new("upstream_errors_total", "Failed provider calls by provider host and status"),
+            &["provider", "status"],
+        )
+        .unwrap();
+        let upstream_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "upstream_request_duration_seconds",
+                "Time until the provider's response headers arrive, by provider host and status",
+            )
+            .buckets(UPSTREAM_BUCKETS.to_vec()),
+            &["provider", "status"],
+        )
+        .unwrap();
+        let categories = IntCounterVec::new(
+            Opts::new("behavior_category_total", "Analyzed requests by behavior category"),
+            &["category"],
+        )
+        .unwrap();
+        let parse_matches = IntCounterVec::new(
+            Opts::new("behavior_parse_total", "Provider replies by how the category was parsed"),
+            &["match"],
+        )
+        .unwrap();
+        let cache_lookups = IntCounterVec::new(
+            Opts::new("classification_cache_requests_total", "Classification cache lookups by result"),
+            &["result"],
+        )
+        .unwrap();
+        let low_confidence = IntCounterVec::new(
+            Opts::new("low_confidence_total", "Classifications below the profile's confidence threshold, by action"),
+            &["action"],
+        )
+        .unwrap();
+        let classifications = IntCounterVec::new(
+            Opts::new("classifications_total", "Classifications by backend and its role: primary, fallback or prefilter"),
+            &["backend", "role"],
+        )
+        .unwrap();
+        let emotion_change = Histogram::with_opts(
+            HistogramOpts::new("emotion_change", "Emotion change returned per analysis")
+                .buckets(DELTA_BUCKETS.to_vec()),
+        )
+        .unwrap();
+        let relationship_change = Histogram::with_opts(
+            HistogramOpts::new("relationship_change", "Relationship change returned per analysis")
+                .buckets(DELTA_BUCKETS.to_vec()),
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(http_requests.clone())).unwrap();
+        registry.register(Box::new(http_duration.clone())).unwrap();
+        registry.register(Box::new(http_in_flight.clone())).unwrap();
+        registry.register(Box::new(upstream_requests.clone())).unwrap();
+        registry.register(Box::new(upstream_errors.clone())).unwrap();
+        registry.register(Box::new(upstream_duration.clone())).unwrap();
+        registry.register(Box::new(categories.clone())).unwrap();
+        registry.register(Box::new(parse_matches.clone())).unwrap();
+        registry.register(Box::new(cache_lookups.clone())).unwrap();
+        registry.register(Box::new(low_confidence.clone())).unwrap();
+        registry.register(Box::new(classifications.clone())).unwrap();
+        registry.register(Box::new(emotion_change.clone())).unwrap();
+        registry.register(Box::new(relationship_change.clone())).unwrap();
+
+        Metrics {
+            registry,
+            http_requests,
+            http_duration,
+            http_in_flight,
+            upstream_requests,
+            upstream_errors,
+            upstream_duration,
+            categories,
+            parse_matches,
+            cache_lookups,
+            low_confidence,
+            classifications,
+            emotion_change,
+            relationship_change,
+        }
+    }
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(Metrics::new);
+
+/// Record a provider call. `status` is the HTTP status, or `None` when no response arrived.
+pub fn record_upstream(provider: &str, status: Option<u16>, elapsed: Duration) {
+    let failed = status.is_none_or(|s| !(200..300).contains(&s));
+    let status = status.map_or_else(|| "error".to_string(), |s| s.to_string());
+    let labels = [provider, status.as_str()];
+    METRICS.upstream_requests.with_label_values(&labels).inc();
+    METRICS.upstream_duration.with_label_values(&labels).observe(elapsed.as_secs_f64());
+    if failed {
+        METRICS.upstream_errors.with_label_values(&labels).inc();
+    }
+}
+
+/// HTTP requests currently being served
+pub fn in_flight() -> i64 {
+    METRICS.http_in_flight.get()
+}
+
+pub fn record_parse(matched: ParseMatch) {
+    METRICS.parse_matches.with_label_values(&[matched.label()]).inc();
+}
+
+pub fn record_cache_lookup(hit: bool) {
+    let result = if hit { "hit" } else { "miss" };
+    METRICS.cache_lookups.with_label_values(&[result]).inc();
+}
+
+pub fn record_low_confidence(action: &str) {
+    METRICS.low_confidence.with_label_values(&[action]).inc();
+}
+
+pub fn record_classification(backend: &str, role: &str) {
+    METRICS.classifications.with_label_values(&[backend, role]).inc();
+}
+
+/// Record the outcome of one analysis
+pub fn record_analysis(category: &str, emotion_change: i32, relationship_change: i32) {
+    METRICS.categories.with_label_values(&[category]).inc();
+    METRICS.emotion_change.observe(emotion_change as f64);
+    METRICS.relationship_change.observe(relationship_change as f64);
+}
+
+/// Counts one request in flight until dropped, including when the client
+/// disconnects and the request future is cancelled
+struct InFlight;
+
+impl InFlight {
+    fn start() -> Self {
+        METRICS.http_in_flight.inc();
+        InFlight
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        METRICS.http_in_flight.dec();
+    }
+}
+
+/// Count every HTTP request and time it, labelled by its route template rather
+/// than the raw path so ids do not create new series
+pub async fn track_requests(request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+
+    let in_flight = InFlight::start();
+    let started = Instant::now();
+    let response = next.run(request).await;
+    drop(in_flight);
+    let status = response.status().as_u16().to_string();
+
+    record_request("http", &method, &route, &status, started.elapsed());
+    response
+}
+
+fn record_request(transport: &str, method: &str, route: &str, status: &str, elapsed: Duration) {
+    METRICS
+        .http_requests
+        .with_label_values(&[transport, method, route, status])
+        .inc();
+    METRICS
+        .http_duration
+        .with_label_values(&[transport, method, route])
+        .observe(elapsed.as_secs_f64());
+}
+
+/// gRPC counterpart of [`track_requests`]: counts and times every call of the
+/// wrapped service, including calls the API key interceptor rejects. `route` is
+/// the gRPC method path and `status` the gRPC status code.
+#[derive(Clone)]
+pub struct TrackGrpc<S> {
+    inner: S,
+}
+
+impl<S> TrackGrpc<S> {
+    pub fn new(inner: S) -> Self {
+        TrackGrpc { inner }
+    }
+}
+
+impl<S: NamedService> NamedService for TrackGrpc<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S, B, ResBody> Service<http::Request<B>> for TrackGrpc<S>
+where
+    S: Service<http::Request<B>, Response = http::Response<ResBody>>,
+    S::Future: Send + 'static,
+    S::Error: Send + 'static,
+    ResBody: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: http::Request<B>) -> Self::Future {
+        let path = request.uri().path().to_string();
+        let started = Instant::now();
+        let response = self.inner.call(request);
+        Box::pin(async move {
+            let response = response.await?;
+            // Failed calls carry their status in the headers; successful ones in
+            // the trailers, after the body, so a missing header means OK (0)
+            let status = response
+                .headers()
+                .get("grpc-status")
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or("0")
+                .to_string();
+            // Unknown methods are not recorded by path, so clients cannot create new series
+            let route = if status == (tonic::Code::Unimplemented as i32).to_string() {
+                "unmatched"
+            } else {
+                path.as_str()
+            };
+            record_request("grpc", "POST", route, &status, started.elapsed());
+            Ok(response)
+        })
+    }
+}
+
+/// Prometheus text exposition of every metric
+pub async fn metrics_handler() -> Response {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&METRICS.registry.gather(), &mut buffer) {
+        tracing::warn!(error = %e, "⚠️ failed to encode metrics");
+    }
+    ([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], buffer).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every call with the given `grpc-status` header, if any
+    #[derive(Clone)]
+    struct Reply(Option<&'static str>);
+
+    impl Service<http::Request<()>> for Reply {
+        type Response = http::Response<()>;
+        type Error = std::convert::Infallible;
+        type Future = std::future::Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: http::Request<()>) -> Self::Future {
+            let mut response = http::Response::new(());
+            if let Some(status) = self.0 {
+                response.headers_mut().insert("grpc-status", status.parse().unwrap());
+            }
+            std::future::ready(Ok(response))
+        }
+    }
+
+    async fn call(reply: Reply, path: &str) {
+        let request = http::Request::builder().uri(path).body(()).unwrap();
+        TrackGrpc::new(reply).call(request).await.unwrap();
+    }
+
+    fn count(route: &str, status: &str) -> u64 {
+        METRICS.http_requests.with_label_values(&["grpc", "POST", route, status]).get()
+    }
+
+    #[tokio::test]
+    async fn grpc_calls_are_counted_by_method_and_status() {
+        let analyze = "/emotion.v1.EmotionService/AnalyzeEmotion";
+        call(Reply(None), analyze).await;
+        call(Reply(Some("16")), analyze).await;
+        call(Reply(Some("16")), analyze).await;
+        assert_eq!((count(analyze, "0"), count(analyze, "16")), (1, 2));
+        let timed = METRICS.http_duration.with_label_values(&["grpc", "POST", analyze]);
+        assert_eq!(timed.get_sample_count(), 3);
+
+        // Unknown methods share one series
+        call(Reply(Some("12")), "/emotion.v1.EmotionService/NoSuchMethod").await;
+        assert_eq!(count("/emotion.v1.EmotionService/NoSuchMethod", "12"), 0);
+        assert!(count("unmatched", "12") >= 1);
+    }
+}
@@ -0,0 +1,165 @@
+// OpenAPI specification generated from the request/response types
+
+use axum::response::{Html, Json};
+use serde::Serialize;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi, ToSchema};
+
+use crate::character::CharacterProfile;
+use crate::validation::FieldError;
+use crate::registry::{CharacterDefinition, CharacterRecord, CreateCharacterRequest};
+use crate::{EmotionRequest, EmotionResponse, EmotionResponseV2, StateChange};
+
+/// Error body returned by endpoints that report error messages
+#[derive(Serialize, ToSchema)]
+pub struct ErrorBody {
+    pub errors: Vec<String>,
+    /// Every invalid field, present on validation failures
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/// Document info and shared schemas; paths are added per API version by [`spec`]
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Emotion AI Agent System"),
+    components(schemas(
+        EmotionRequest,
+        EmotionResponse,
+        EmotionResponseV2,
+        StateChange,
+        CharacterProfile,
+        CharacterDefinition,
+        CharacterRecord,
+        CreateCharacterRequest,
+        ErrorBody,
+        FieldError,
+    )),
+    modifiers(&ApiKeyAuth),
+    security(("bearer" = []), ("api_key" = []))
+)]
+pub struct ApiDoc;
+
+/// Tenant API key, sent as `Authorization: Bearer <key>` or `X-API-Key: <key>`
+struct ApiKeyAuth;
+
+impl Modify for ApiKeyAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme("bearer", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+        components.add_security_scheme("api_key", SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-API-Key"))));
+    }
+}
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    crate::health_check,
+    crate::health::readiness,
+    crate::analyze_emotion,
+    crate::batch::analyze_emotion_batch,
+    crate::sse::analyze_emotion_stream,
+    crate::ws::session_ws,
+    crate::registry::list_characters,
+    crate::registry::create_character,
+    crate::registry::get_character,
+    crate::registry::update_character,
+    crate::registry::delete_character,
+    crate::registry::list_character_versions,
+    crate::registry::get_character_version,
+))]
+struct V1Paths;
+
+#[derive(OpenApi)]
+#[openapi(paths(
+    crate::health_check,
+    crate::health::readiness,
+    crate::analyze_emotion_v2,
+    crate::batch::analyze_emotion_batch_v2,
+    crate::sse::analyze_emotion_stream_v2,
+    crate::ws::session_ws,
+    crate::registry::list_characters,
+    crate::registry::create_character,
+    crate::registry::get_character,
+    crate::registry::update_character,
+    crate::registry::delete_character,
+    crate::registry::list_character_versions,
+    crate::registry::get_character_version,
+))]
+struct V2Paths;
+
+/// Give every operation a version-specific id and optionally mark it deprecated
+fn versioned(mut doc: utoipa::openapi::OpenApi, prefix: &str, deprecated: bool) -> utoipa::openapi::OpenApi {
+    for item in doc.paths.paths.values_mut() {
+        let operations = [
+            &mut item.get,
+            &mut item.post,
+            &mut item.put,
+            &mut item.delete,
+            &mut item.patch,
+        ];
+        for operation in operations.into_iter().flatten() {
+            operation.operation_id = operation.operation_id.take().map(|id| format!("{}_{}", prefix, id));
+            if deprecated {
+                operation.deprecated = Some(utoipa::openapi::Deprecated::True);
+            }
+        }
+    }
+    doc
+}
+
+/// The full specification: `/v1`, `/v2` and the deprecated unversioned aliases
+pub fn spec() -> utoipa::openapi::OpenApi {
+    let mut doc = ApiDoc::openapi()
+        .nest("/v1", versioned(V1Paths::openapi(), "v1", false))
+        .nest("/v2", versioned(V2Paths::openapi(), "v2", false));
+    doc.merge(versioned(V1Paths::openapi(), "legacy", true));
+    doc
+}
+
+/// Viewer page rendering /openapi.json
+const DOCS_HTML: &str = r#"<!doctype html>
+<html>
+  <head>
+    <title>Emotion AI Agent System API</title>
+    <meta charset="utf-8" />
+    <meta name="viewport" content="width=device-width, initial-scale=1" />
+  </head>
+  <body>
+    <script id="api-reference" data-url="/openapi.json"></script>
+    <script src="https://cdn.jsdelivr.net/npm/@scalar/api-reference"></script>
+  </body>
+</html>
+"#;
+
+pub async fn openapi_json() -> Json<utoipa::openapi::OpenApi> {
+    Json(spec())
+}
+
+pub async fn docs() -> Html<&'static str> {
+    Html(DOCS_HTML)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SPEC_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/docs/openapi.json");
+
+    /// Fails when the committed spec no longer matches the Rust types.
+    /// Regenerate with `UPDATE_OPENAPI=1 cargo test openapi`.
+    #[test]
+    fn committed_spec_matches_types() {
+        let generated = spec().to_pretty_json().unwrap() + "\n";
+
+        if std::env::var_os("UPDATE_OPENAPI").is_some() {
+            std::fs::write(SPEC_PATH, &generated).unwrap();
+            return;
+        }
+
+        let committed = std::fs::read_to_string(SPEC_PATH).unwrap_or_default();
+        assert!(
+            committed == generated,
+            "docs/openapi.json is out of date; regenerate with `UPDATE_OPENAPI=1 cargo test openapi`"
+        );
+    }
+}
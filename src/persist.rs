@@ -0,0 +1,21 @@
+// Crash-safe writes for the JSON files the stores persist to
+
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Numbers the temporary files, so concurrent writes of one path do not share one
+static TEMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Write `data` to a temporary file next to `path`, then rename it over `path`.
+/// A crash leaves either the old file or the new one, never a truncated mix.
+pub fn write_atomically(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut temp_name = path.file_name().unwrap_or_default().to_os_string();
+    temp_name.push(format!(".{}.{}.tmp", std::process::id(), TEMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+    let temp_path = path.with_file_name(temp_name);
+
+    let mut file = std::fs::File::create(&temp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    std::fs::rename(&temp_path, path)
+}
@@ -0,0 +1,211 @@
+// Client for the upstream LLM chat completions API (xAI Grok)
+
+use async_stream::try_stream;
+use futures::{Stream, StreamExt};
+use reqwest::{header::HeaderMap, Client, Response};
+use std::time::{Duration, Instant};
+use tracing::{field, info_span, Instrument, Span};
+
+use crate::{metrics, telemetry};
+
+pub const DEFAULT_API_URL: &str = "https://api.x.ai/v1/chat/completions";
+pub const DEFAULT_MODEL: &str = "grok-4-1-fast-non-reasoning";
+
+/// Per-tenant replacements for the default endpoint, key and model
+#[derive(Debug, Clone, Default)]
+pub struct ProviderOverrides {
+    pub api_url: Option<String>,
+    pub api_key: Option<String>,
+    pub model: Option<String>,
+}
+
+/// Chat completions client shared by every request
+pub struct Provider {
+    client: Client,
+    api_key: String,
+    api_url: String,
+    model: String,
+    /// Ask for token log probabilities on non-streaming calls
+    logprobs: bool,
+}
+
+/// A complete reply
+pub struct Completion {
+    pub content: String,
+    /// Probability of the reply's tokens, when the provider returned log probabilities
+    pub probability: Option<f32>,
+}
+
+/// Host of a provider endpoint, used to name the provider in metrics and probes
+fn host(api_url: &str) -> String {
+    reqwest::Url::parse(api_url)
+        .ok()
+        .and_then(|url| url.host_str().map(str::to_string))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+impl Provider {
+    pub fn new(api_key: String, api_url: String, model: String, logprobs: bool) -> Self {
+        Provider {
+            client: Client::new(),
+            api_key,
+            api_url,
+            model,
+            logprobs,
+        }
+    }
+
+    /// Host of the default endpoint
+    pub fn name(&self) -> String {
+        host(&self.api_url)
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    /// List the provider's models to confirm the endpoint is reachable and accepts the key.
+    /// Statuses other than auth failures and server errors count as reachable.
+    pub async fn check_reachable(&self, timeout: Duration) -> anyhow::Result<()> {
+        let models_url = match self.api_url.strip_suffix("/chat/completions") {
+            Some(base) => format!("{}/models", base),
+            None => self.api_url.clone(),
+        };
+        let response = self
+            .client
+            .get(&models_url)
+            .header("Authorization", format!("Bearer {}", self.api_key))
+            .timeout(timeout)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status == reqwest::StatusCode::UNAUTHORIZED || status == reqwest::StatusCode::FORBIDDEN {
+            anyhow::bail!("provider rejected the API key ({})", status);
+        }
+        if status.is_server_error() {
+            anyhow::bail!("provider returned {}", status);
+        }
+        Ok(())
+    }
+
+    /// Post the prompt and return the response once its status is known to be successful
+    async fn send(&self, prompt: &str, stream: bool, overrides: &ProviderOverrides) -> anyhow::Result<Response> {
+        let api_url = overrides.api_url.as_deref().unwrap_or(&self.api_url);
+        let api_key = overrides.api_key.as_deref().unwrap_or(&self.api_key);
+        let model = overrides.model.as_deref().unwrap_or(&self.model);
+        let provider = host(api_url);
+
+        let span = info_span!(
+            "provider_request",
+            otel.kind = "client",
+            provider = %provider,
+            model,
+            stream,
+            status = field::Empty,
+        );
+        async {
+            // Carry the trace to the provider as a W3C traceparent header
+            let mut headers = HeaderMap::new();
+            telemetry::inject_trace_context(&mut headers);
+
+            let mut body = serde_json::json!({
+                "model": model,
+                "stream": stream,
+                "messages": [
+                    {
+                        "role": "user",
+                        "content": prompt
+                    }
+                ]
+            });
+            if self.logprobs && !stream {
+                body["logprobs"] = serde_json::Value::Bool(true);
+            }
+
+            let started = Instant::now();
+            let response = self.client
+                .post(api_url)
+                .headers(headers)
+                .header("Authorization", format!("Bearer {}", api_key))
+                .header("Content-Type", "application/json")
+                .json(&body)
+                .send()
+                .await;
+            let status = response.as_ref().ok().map(|r| r.status().as_u16());
+            metrics::record_upstream(&provider, status, started.elapsed());
+            if let Some(status) = status {
+                Span::current().record("status", status);
+            }
+
+            let response = response?;
+            let status = response.status();
+            if !status.is_success() {
+                anyhow::bail!("provider returned {}", status);
+            }
+            Ok(response)
+        }
+        .instrument(span)
+        .await
+    }
+
+    /// Send the prompt as a single user message and return the model's reply
+    pub async fn complete(&self, prompt: &str, overrides: &ProviderOverrides) -> anyhow::Result<Completion> {
+        let response = self.send(prompt, false, overrides).await?;
+
+        let data: serde_json::Value = response.json().await?;
+        let choice = &data["choices"][0];
+
+        // The joint probability of every token is the model's certainty in the whole reply
+        let probability = choice["logprobs"]["content"].as_array().map(|tokens| {
+            let sum: f64 = tokens.iter().filter_map(|token| token["logprob"].as_f64()).sum();
+            sum.exp().clamp(0.0, 1.0) as f32
+        });
+
+        // Extract the category from the model's response; a missing one is left to
+        // the parser's fallback, so it is reported as such
+        Ok(Completion {
+            content: choice["message"]["content"]
+                .as_str()
+                .unwrap_or("")
+                .trim()
+                .to_string(),
+            probability,
+        })
+    }
+
+    /// Send the prompt with streaming enabled and yield the reply's content deltas
+    pub async fn stream(
+        &self,
+        prompt: &str,
+        overrides: &ProviderOverrides,
+    ) -> anyhow::Result<impl Stream<Item = anyhow::Result<String>>> {
+        let response = self.send(prompt, true, overrides).await?;
+
+        let mut chunks = response.bytes_stream();
+        Ok(try_stream! {
+            // Server-sent events: one `data: {json}` line per delta, terminated by `data: [DONE]`
+            let mut buffer: Vec<u8> = Vec::new();
+            'read: while let Some(chunk) = chunks.next().await {
+                buffer.extend_from_slice(&chunk?);
+                while let Some(pos) = buffer.iter().position(|b| *b == b'\n') {
+                    let line: Vec<u8> = buffer.drain(..=pos).collect();
+                    let line = String::from_utf8_lossy(&line);
+                    let Some(data) = line.trim().strip_prefix("data:") else {
+                        continue;
+                    };
+                    let data = data.trim();
+                    if data == "[DONE]" {
+                        break 'read;
+                    }
+                    let event: serde_json::Value = serde_json::from_str(data)?;
+                    if let Some(delta) = event["choices"][0]["delta"]["content"].as_str() {
+                        if !delta.is_empty() {
+                            yield delta.to_string();
+                        }
+                    }
+                }
+            }
+        })
+    }
+}
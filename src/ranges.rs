@@ -31,10 +31,36 @@ pub const EMOTION_RANGES: &[(&str, (i32, i32))] = &[
 
 // Helper functions
 
+/// How a state value is matched to its tier
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TierLookup {
+    /// Bounds read as (min, max). The negative tiers list theirs from the value
+    /// closest to zero outwards, so they never match and negative values fall back
+    /// to Neutral / Acquaintance. Kept for `/v1` and the unversioned routes.
+    Legacy,
+    /// Bounds accepted in either order, so negative values get their own tiers
+    Corrected,
+}
+
+/// Check whether a value falls within a range under `lookup`
+fn in_range(value: i32, (a, b): (i32, i32), lookup: TierLookup) -> bool {
+    match lookup {
+        TierLookup::Legacy => value >= a && value <= b,
+        TierLookup::Corrected => value >= a.min(b) && value <= a.max(b),
+    }
+}
+
+/// Lowest and highest value covered by a range table
+fn table_bounds(table: &[(&str, (i32, i32))]) -> (i32, i32) {
+    table.iter().fold((i32::MAX, i32::MIN), |(lo, hi), (_, (a, b))| {
+        (lo.min(*a.min(b)), hi.max(*a.max(b)))
+    })
+}
+
 /// Determine relationship level from value (used in main.rs for Grok payload)
-pub fn get_relationship_from_value(value: i32) -> Option<&'static str> {
-    for (name, (min, max)) in RELATIONSHIP_RANGES.iter() {
-        if value >= *min && value <= *max {
+pub fn get_relationship_from_value(value: i32, lookup: TierLookup) -> Option<&'static str> {
+    for (name, range) in RELATIONSHIP_RANGES.iter() {
+        if in_range(value, *range, lookup) {
             return Some(name);
         }
     }
@@ -42,11 +68,21 @@ pub fn get_relationship_from_value(value: i32) -> Option<&'static str> {
 }
 
 /// Determine emotion level from value (used in main.rs for Grok payload)
-pub fn get_emotion_from_value(value: i32) -> Option<&'static str> {
-    for (name, (min, max)) in EMOTION_RANGES.iter() {
-        if value >= *min && value <= *max {
+pub fn get_emotion_from_value(value: i32, lookup: TierLookup) -> Option<&'static str> {
+    for (name, range) in EMOTION_RANGES.iter() {
+        if in_range(value, *range, lookup) {
             return Some(name);
         }
     }
     None
 }
+
+/// Lowest and highest relationship value covered by the relationship tiers
+pub fn relationship_bounds() -> (i32, i32) {
+    table_bounds(RELATIONSHIP_RANGES)
+}
+
+/// Lowest and highest emotion value covered by the emotion tiers
+pub fn emotion_bounds() -> (i32, i32) {
+    table_bounds(EMOTION_RANGES)
+}
@@ -0,0 +1,338 @@
+// Token-bucket rate limits per tenant, end user and character
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Bucket size and refill rate: `capacity` requests per `period`
+#[derive(Debug, Clone, Copy)]
+pub struct Limit {
+    pub capacity: u32,
+    pub period: Duration,
+}
+
+impl FromStr for Limit {
+    type Err = String;
+
+    /// Parse `<requests>/<seconds>`, e.g. `30/60`
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("'{}' must be <requests>/<seconds> with both above 0, e.g. 30/60", value);
+        let (capacity, seconds) = value.trim().split_once('/').ok_or_else(invalid)?;
+        let capacity: u32 = capacity.trim().parse().ok().filter(|c| *c > 0).ok_or_else(invalid)?;
+        let seconds: u64 = seconds.trim().parse().ok().filter(|s| *s > 0).ok_or_else(invalid)?;
+        Ok(Limit {
+            capacity,
+            period: Duration::from_secs(seconds),
+        })
+    }
+}
+
+impl Limit {
+    /// Tokens added back per millisecond
+    fn refill_per_ms(&self) -> f64 {
+        self.capacity as f64 / self.period.as_millis() as f64
+    }
+}
+
+/// Configured limits; a scope without a limit is not checked
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimitSettings {
+    pub tenant: Option<Limit>,
+    /// Requests without a `user_id` share one anonymous bucket per tenant
+    pub user: Option<Limit>,
+    pub character: Option<Limit>,
+}
+
+/// Who a request is charged to
+pub struct RateLimitKey<'a> {
+    pub tenant: &'a str,
+    pub user: Option<&'a str>,
+    pub character: &'a str,
+}
+
+/// A request refused by one of the buckets
+#[derive(Debug)]
+pub struct RateLimited {
+    pub scope: &'static str,
+    pub retry_after: Duration,
+}
+
+impl RateLimited {
+    /// Whole seconds for the `Retry-After` header, at least 1
+    pub fn retry_after_secs(&self) -> u64 {
+        self.retry_after.as_secs_f64().ceil().max(1.0) as u64
+    }
+}
+
+/// Buckets kept by the in-memory backend before full ones are dropped
+const MAX_MEMORY_BUCKETS: usize = 100_000;
+
+/// Bucket state as stored by the in-memory backend
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    /// When the bucket is full again and can be forgotten
+    full_at: Instant,
+}
+
+/// Where bucket state lives: this process, or Redis when several instances share limits
+enum Backend {
+    Memory(Mutex<HashMap<String, Bucket>>),
+    Redis {
+        connection: redis::aio::ConnectionManager,
+        script: redis::Script,
+    },
+}
+
+/// Takes one token from every bucket that applies to a request, or none at all
+pub struct RateLimiter {
+    settings: RateLimitSettings,
+    backend: Backend,
+}
+
+/// All-or-nothing take over several buckets. KEYS are bucket keys; ARGV holds
+/// capacity and refill-per-ms pairs. Returns 0, or the index of the first empty
+/// bucket and the milliseconds until it has a token again.
+const TAKE_SCRIPT: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local tokens = {}
+for i, key in ipairs(KEYS) do
+  local capacity = tonumber(ARGV[i * 2 - 1])
+  local rate = tonumber(ARGV[i * 2])
+  local bucket = redis.call('HMGET', key, 'tokens', 'updated')
+  local current = tonumber(bucket[1]) or capacity
+  local updated = tonumber(bucket[2]) or now
+  current = math.min(capacity, current + math.max(0, now - updated) * rate)
+  if current < 1 then
+    return {i, math.ceil((1 - current) / rate)}
+  end
+  tokens[i] = current
+end
+for i, key in ipairs(KEYS) do
+  local capacity = tonumber(ARGV[i * 2 - 1])
+  local rate = tonumber(ARGV[i * 2])
+  redis.call('HSET', key, 'tokens', tostring(tokens[i] - 1), 'updated', now)
+  redis.call('PEXPIRE', key, math.ceil(capacity / rate))
+end
+return 0
+";
+
+impl RateLimiter {
+    pub fn in_memory(settings: RateLimitSettings) -> Self {
+        RateLimiter {
+            settings,
+            backend: Backend::Memory(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Share buckets between instances through Redis
+    pub async fn redis(settings: RateLimitSettings, url: &str) -> anyhow::Result<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(RateLimiter {
+            settings,
+            backend: Backend::Redis {
+                connection: client.get_connection_manager().await?,
+                script: redis::Script::new(TAKE_SCRIPT),
+            },
+        })
+    }
+
+    pub fn backend_name(&self) -> &'static str {
+        match self.backend {
+            Backend::Memory(_) => "memory",
+            Backend::Redis { .. } => "redis",
+        }
+    }
+
+    /// Confirm the bucket store answers; the in-memory backend always does
+    pub async fn ping(&self) -> anyhow::Result<()> {
+        match &self.backend {
+            Backend::Memory(_) => Ok(()),
+            Backend::Redis { connection, .. } => {
+                let mut connection = connection.clone();
+                let ping = redis::cmd("PING");
+                tokio::time::timeout(Duration::from_secs(2), ping.query_async::<String>(&mut connection)).await??;
+                Ok(())
+            }
+        }
+    }
+
+    /// Buckets charged for a request: `(scope, bucket key, limit)`
+    fn buckets(&self, key: &RateLimitKey) -> Vec<(&'static str, String, Limit)> {
+        let mut buckets = Vec::new();
+        if let Some(limit) = self.settings.tenant {
+            buckets.push(("tenant", format!("ratelimit:tenant:{}", key.tenant), limit));
+        }
+        if let Some(limit) = self.settings.user {
+            // Leaving out user_id must not skip the limit, so those requests share a bucket
+            let bucket = match key.user {
+                Some(user) => format!("ratelimit:user:{}:id:{}", key.tenant, user),
+                None => format!("ratelimit:user:{}:anonymous", key.tenant),
+            };
+            buckets.push(("user", bucket, limit));
+        }
+        if let Some(limit) = self.settings.character {
+            buckets.push(("character", format!("ratelimit:character:{}:{}", key.tenant, key.character), limit));
+        }
+        buckets
+    }
+
+    /// Take a token from every applicable bucket; nothing is taken when any bucket is empty
+    pub async fn check(&self, key: &RateLimitKey<'_>) -> Result<(), RateLimited> {
+        let buckets = self.buckets(key);
+        if buckets.is_empty() {
+            return Ok(());
+        }
+        match &self.backend {
+            Backend::Memory(store) => take_memory(&mut store.lock().unwrap(), &buckets, Instant::now()),
+            Backend::Redis { connection, script } => take_redis(connection.clone(), script, &buckets).await,
+        }
+    }
+}
+
+fn take_memory(
+    store: &mut HashMap<String, Bucket>,
+    buckets: &[(&'static str, String, Limit)],
+    now: Instant,
+) -> Result<(), RateLimited> {
+    let mut available = Vec::with_capacity(buckets.len());
+    for (scope, key, limit) in buckets {
+        let capacity = limit.capacity as f64;
+        let tokens = store.get(key).map_or(capacity, |bucket| {
+            let elapsed = now.duration_since(bucket.updated).as_millis() as f64;
+            (bucket.tokens + elapsed * limit.refill_per_ms()).min(capacity)
+        });
+        if tokens < 1.0 {
+            let wait_ms = ((1.0 - tokens) / limit.refill_per_ms()).ceil() as u64;
+            return Err(RateLimited {
+                scope,
+                retry_after: Duration::from_millis(wait_ms),
+            });
+        }
+        available.push(tokens);
+    }
+
+    for ((_, key, limit), tokens) in buckets.iter().zip(available) {
+        let missing = limit.capacity as f64 - (tokens - 1.0);
+        store.insert(
+            key.clone(),
+            Bucket {
+                tokens: tokens - 1.0,
+                updated: now,
+                full_at: now + Duration::from_millis((missing / limit.refill_per_ms()).ceil() as u64),
+            },
+        );
+    }
+    if store.len() > MAX_MEMORY_BUCKETS {
+        store.retain(|_, bucket| bucket.full_at > now);
+    }
+    Ok(())
+}
+
+async fn take_redis(
+    mut connection: redis::aio::ConnectionManager,
+    script: &redis::Script,
+    buckets: &[(&'static str, String, Limit)],
+) -> Result<(), RateLimited> {
+    let mut invocation = script.prepare_invoke();
+    for (_, key, limit) in buckets {
+        invocation.key(key).arg(limit.capacity).arg(limit.refill_per_ms());
+    }
+
+    let reply: redis::RedisResult<redis::Value> = invocation.invoke_async(&mut connection).await;
+    match reply {
+        Ok(value @ redis::Value::Array(_)) => {
+            let (index, wait_ms): (usize, u64) = redis::from_redis_value(&value).unwrap_or((1, 1000));
+            let scope = buckets.get(index.saturating_sub(1)).map_or("tenant", |(scope, _, _)| *scope);
+            Err(RateLimited {
+                scope,
+                retry_after: Duration::from_millis(wait_ms),
+            })
+        }
+        Ok(_) => Ok(()),
+        Err(e) => {
+            // Fail open: an unreachable store must not take the API down, so limits
+            // go unenforced until it is back
+            tracing::warn!(error = %e, "⚠️ rate limit store error, allowing request");
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bucket(key: &str, limit: &str) -> (&'static str, String, Limit) {
+        ("tenant", key.to_string(), limit.parse().unwrap())
+    }
+
+    #[test]
+    fn rejects_when_empty_and_refills_over_time() {
+        let mut store = HashMap::new();
+        let buckets = [bucket("a", "2/10")];
+        let start = Instant::now();
+
+        assert!(take_memory(&mut store, &buckets, start).is_ok());
+        assert!(take_memory(&mut store, &buckets, start).is_ok());
+        let limited = take_memory(&mut store, &buckets, start).unwrap_err();
+        // One token comes back every 5 seconds
+        assert_eq!(limited.retry_after, Duration::from_secs(5));
+        assert_eq!(limited.retry_after_secs(), 5);
+
+        assert!(take_memory(&mut store, &buckets, start + Duration::from_secs(4)).is_err());
+        assert!(take_memory(&mut store, &buckets, start + Duration::from_secs(5)).is_ok());
+        assert!(take_memory(&mut store, &buckets, start + Duration::from_secs(5)).is_err());
+    }
+
+    #[test]
+    fn refill_never_exceeds_capacity() {
+        let mut store = HashMap::new();
+        let buckets = [bucket("a", "2/10")];
+        let start = Instant::now();
+        assert!(take_memory(&mut store, &buckets, start).is_ok());
+
+        let later = start + Duration::from_secs(3600);
+        assert!(take_memory(&mut store, &buckets, later).is_ok());
+        assert!(take_memory(&mut store, &buckets, later).is_ok());
+        assert!(take_memory(&mut store, &buckets, later).is_err());
+    }
+
+    #[test]
+    fn takes_nothing_when_any_bucket_is_empty() {
+        let mut store = HashMap::new();
+        let start = Instant::now();
+        assert!(take_memory(&mut store, &[bucket("b", "1/60")], start).is_ok());
+
+        let both = [bucket("a", "5/60"), bucket("b", "1/60")];
+        assert!(take_memory(&mut store, &both, start).is_err());
+        assert!(!store.contains_key("a"), "the open bucket was charged");
+    }
+
+    #[tokio::test]
+    async fn requests_without_user_id_share_a_user_bucket() {
+        let limiter = RateLimiter::in_memory(RateLimitSettings {
+            user: Some("1/60".parse().unwrap()),
+            ..Default::default()
+        });
+        let key = |user| RateLimitKey {
+            tenant: "t",
+            user,
+            character: "c",
+        };
+        assert!(limiter.check(&key(None)).await.is_ok());
+        assert_eq!(limiter.check(&key(None)).await.unwrap_err().scope, "user");
+        assert!(limiter.check(&key(Some("u1"))).await.is_ok());
+    }
+
+    #[test]
+    fn parses_limits() {
+        let limit: Limit = "30/60".parse().unwrap();
+        assert_eq!((limit.capacity, limit.period), (30, Duration::from_secs(60)));
+        for invalid in ["100/1m", "0/60", "30/0", "30", ""] {
+            assert!(invalid.parse::<Limit>().is_err(), "{} parsed", invalid);
+        }
+    }
+}
@@ -0,0 +1,586 @@
+// Server-side registry of versioned character definitions
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Json, Response},
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::auth::{Tenant, DEFAULT_TENANT};
+use crate::character::CharacterProfile;
+use crate::openapi::ErrorBody;
+use crate::persist;
+use crate::ranges;
+use crate::validation::{FieldError, Validator};
+
+/// Character definition as submitted by clients (create / update body)
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct CharacterDefinition {
+    #[serde(flatten)]
+    pub profile: CharacterProfile,
+    /// Emotion used when a request does not send `current_emotion`
+    #[serde(default)]
+    pub default_emotion: i32,
+    /// Relationship used when a request does not send `current_relationship`
+    #[serde(default)]
+    pub default_relationship: i32,
+    /// Name of the balance profile whose low-confidence policy this character uses;
+    /// must be one of the tenant's profiles
+    #[serde(default = "default_balance_profile")]
+    pub balance_profile: String,
+    #[serde(default = "default_locale")]
+    pub locale: String,
+}
+
+fn default_balance_profile() -> String {
+    "default".to_string()
+}
+
+fn default_locale() -> String {
+    "en".to_string()
+}
+
+impl CharacterDefinition {
+    /// Add the definition's field errors to `validator`, including a balance profile
+    /// the tenant does not own
+    fn check(&self, tenant: &Tenant, validator: &mut Validator) {
+        self.profile.check(validator);
+        if !self.balance_profile.trim().is_empty() && !tenant.balance_profiles.contains(&self.balance_profile) {
+            validator.error(
+                "balance_profile",
+                format!("must be one of the tenant's profiles: {}", tenant.balance_profiles.join(", ")),
+            );
+        }
+        validator
+            .in_range("default_emotion", self.default_emotion, ranges::emotion_bounds())
+            .in_range("default_relationship", self.default_relationship, ranges::relationship_bounds())
+            .not_blank("balance_profile", &self.balance_profile)
+            .not_blank("locale", &self.locale);
+    }
+}
+
+/// A single immutable version of a character definition
+#[derive(Debug, Clone, Deserialize, Serialize, ToSchema)]
+pub struct CharacterRecord {
+    pub id: String,
+    pub version: u32,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+    #[serde(flatten)]
+    pub definition: CharacterDefinition,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+struct CharacterEntry {
+    versions: Vec<CharacterRecord>,
+    #[serde(default)]
+    deleted: bool,
+}
+
+impl CharacterEntry {
+    fn latest(&self) -> Option<&CharacterRecord> {
+        if self.deleted {
+            None
+        } else {
+            self.versions.last()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    NotFound(String),
+    Conflict(String),
+    Invalid(Vec<FieldError>),
+    Storage(String),
+}
+
+impl RegistryError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RegistryError::NotFound(_) => StatusCode::NOT_FOUND,
+            RegistryError::Conflict(_) => StatusCode::CONFLICT,
+            RegistryError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RegistryError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn messages(&self) -> Vec<String> {
+        match self {
+            RegistryError::NotFound(msg) | RegistryError::Conflict(msg) | RegistryError::Storage(msg) => {
+                vec![msg.clone()]
+            }
+            RegistryError::Invalid(errors) => errors.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    /// Field errors, when the error is a validation failure
+    pub fn fields(&self) -> Vec<FieldError> {
+        match self {
+            RegistryError::Invalid(errors) => errors.clone(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl IntoResponse for RegistryError {
+    fn into_response(self) -> Response {
+        let body = ErrorBody {
+            errors: self.messages(),
+            fields: self.fields(),
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
+
+/// Characters of one tenant by id
+type TenantEntries = HashMap<String, CharacterEntry>;
+
+/// Registry file contents; files written before tenants existed hold a single
+/// id map, which is loaded as the default tenant's
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RegistryFile {
+    ByTenant(HashMap<String, TenantEntries>),
+    Unscoped(TenantEntries),
+}
+
+/// In-memory character registry, optionally persisted to a JSON file.
+/// Every lookup is scoped to a tenant; other tenants' characters are never visible.
+pub struct CharacterRegistry {
+    entries: RwLock<HashMap<String, TenantEntries>>,
+    path: Option<PathBuf>,
+    /// Held by one change at a time, from the edit until the file is written
+    writer: tokio::sync::Mutex<()>,
+}
+
+impl CharacterRegistry {
+    /// Create a registry, loading existing definitions from `path` if it exists
+    pub fn open(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let entries = match &path {
+            Some(path) if path.exists() => {
+                let data = std::fs::read_to_string(path)?;
+                match serde_json::from_str(&data)? {
+                    RegistryFile::ByTenant(entries) => entries,
+                    RegistryFile::Unscoped(entries) => HashMap::from([(DEFAULT_TENANT.to_string(), entries)]),
+                }
+            }
+            _ => HashMap::new(),
+        };
+        Ok(CharacterRegistry {
+            entries: RwLock::new(entries),
+            path,
+            writer: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    /// File the store is persisted to, if any
+    pub fn path(&self) -> Option<&std::path::Path> {
+        self.path.as_deref()
+    }
+
+    /// List the latest version of every live character of the tenant
+    pub fn list(&self, tenant: &Tenant) -> Vec<CharacterRecord> {
+        let entries = self.entries.read().unwrap();
+        let mut records: Vec<CharacterRecord> = entries
+            .get(&tenant.id)
+            .into_iter()
+            .flat_map(|entries| entries.values())
+            .filter_map(|e| e.latest().cloned())
+            .collect();
+        records.sort_by(|a, b| a.id.cmp(&b.id));
+        records
+    }
+
+    /// Get the latest version of a character
+    pub fn get(&self, tenant: &Tenant, id: &str) -> Result<CharacterRecord, RegistryError> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(&tenant.id)
+            .and_then(|entries| entries.get(id))
+            .and_then(|e| e.latest().cloned())
+            .ok_or_else(|| RegistryError::NotFound(format!("character '{}' not found", id)))
+    }
+
+    /// Get a specific version of a character; deleted characters keep their history
+    pub fn get_version(&self, tenant: &Tenant, id: &str, version: u32) -> Result<CharacterRecord, RegistryError> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(&tenant.id)
+            .and_then(|entries| entries.get(id))
+            .and_then(|e| e.versions.iter().find(|r| r.version == version).cloned())
+            .ok_or_else(|| RegistryError::NotFound(format!("character '{}' version {} not found", id, version)))
+    }
+
+    /// List every stored version of a character
+    pub fn versions(&self, tenant: &Tenant, id: &str) -> Result<Vec<CharacterRecord>, RegistryError> {
+        let entries = self.entries.read().unwrap();
+        entries
+            .get(&tenant.id)
+            .and_then(|entries| entries.get(id))
+            .map(|e| e.versions.clone())
+            .ok_or_else(|| RegistryError::NotFound(format!("character '{}' not found", id)))
+    }
+
+    /// Create a new character (version 1 unless the id was previously deleted)
+    pub async fn create(
+        &self,
+        tenant: &Tenant,
+        id: String,
+        definition: CharacterDefinition,
+    ) -> Result<CharacterRecord, RegistryError> {
+        let mut validator = Validator::new();
+        definition.check(tenant, &mut validator);
+        if let Err(message) = validate_id(&id) {
+            validator.error("id", message);
+        }
+        validator.finish().map_err(RegistryError::Invalid)?;
+
+        let key = id.clone();
+        self.write(tenant, &key, move |entries| {
+            if entries.get(&id).is_some_and(|e| !e.deleted) {
+                return Err(RegistryError::Conflict(format!("character '{}' already exists", id)));
+            }
+            // Re-creating a deleted character continues its version history
+            let entry = entries.entry(id.clone()).or_default();
+            let record = CharacterRecord {
+                id,
+                version: entry.versions.last().map_or(1, |r| r.version + 1),
+                created_at: chrono::Utc::now(),
+                definition,
+            };
+            entry.deleted = false;
+            entry.versions.push(record.clone());
+            Ok(record)
+        })
+        .await
+    }
+
+    /// Store a new version of an existing character
+    pub async fn update(
+        &self,
+        tenant: &Tenant,
+        id: &str,
+        definition: CharacterDefinition,
+    ) -> Result<CharacterRecord, RegistryError> {
+        let mut validator = Validator::new();
+        definition.check(tenant, &mut validator);
+        validator.finish().map_err(RegistryError::Invalid)?;
+
+        self.write(tenant, id, |entries| {
+            let entry = entries
+                .get_mut(id)
+                .filter(|e| !e.deleted)
+                .ok_or_else(|| RegistryError::NotFound(format!("character '{}' not found", id)))?;
+            let record = CharacterRecord {
+                id: id.to_string(),
+                version: entry.versions.last().map_or(1, |r| r.version + 1),
+                created_at: chrono::Utc::now(),
+                definition,
+            };
+            entry.versions.push(record.clone());
+            Ok(record)
+        })
+        .await
+    }
+
+    /// Delete a character; its versions stay available for tracing old interactions
+    pub async fn delete(&self, tenant: &Tenant, id: &str) -> Result<(), RegistryError> {
+        self.write(tenant, id, |entries| {
+            let entry = entries
+                .get_mut(id)
+                .filter(|e| !e.deleted)
+                .ok_or_else(|| RegistryError::NotFound(format!("character '{}' not found", id)))?;
+            entry.deleted = true;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Apply `change` to the tenant's characters and persist the registry. Changes
+    /// are written one at a time, in order; if the write fails, character `id` is
+    /// restored and the change is reported as a storage error.
+    async fn write<T>(
+        &self,
+        tenant: &Tenant,
+        id: &str,
+        change: impl FnOnce(&mut TenantEntries) -> Result<T, RegistryError>,
+    ) -> Result<T, RegistryError> {
+        let _writer = self.writer.lock().await;
+        let (result, previous, data) = {
+            let mut entries = self.entries.write().unwrap();
+            let tenant_entries = entries.entry(tenant.id.clone()).or_default();
+            let previous = tenant_entries.get(id).cloned();
+            let result = change(tenant_entries)?;
+            // Serialized with the change, so no other change can slip in between
+            let data = self.path.as_ref().map(|_| serde_json::to_vec_pretty(&*entries));
+            (result, previous, data)
+        };
+        let (Some(path), Some(data)) = (self.path.clone(), data) else {
+            return Ok(result);
+        };
+
+        let written = match data {
+            Ok(data) => tokio::task::spawn_blocking(move || persist::write_atomically(&path, &data))
+                .await
+                .map_err(|e| e.to_string())
+                .and_then(|r| r.map_err(|e| e.to_string())),
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(e) = written {
+            let mut entries = self.entries.write().unwrap();
+            let tenant_entries = entries.entry(tenant.id.clone()).or_default();
+            match previous {
+                Some(entry) => tenant_entries.insert(id.to_string(), entry),
+                None => tenant_entries.remove(id),
+            };
+            return Err(RegistryError::Storage(format!("failed to write registry: {}", e)));
+        }
+        Ok(result)
+    }
+}
+
+fn validate_id(id: &str) -> Result<(), String> {
+    let valid = !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if valid {
+        Ok(())
+    } else {
+        Err("must be 1-64 characters of letters, digits, '-' or '_'".to_string())
+    }
+}
+
+// HTTP handlers
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateCharacterRequest {
+    #[serde(default)]
+    pub id: Option<String>,
+    #[serde(flatten)]
+    pub definition: CharacterDefinition,
+}
+
+#[utoipa::path(
+    get,
+    path = "/characters",
+    tag = "characters",
+    responses((status = 200, description = "Latest version of every character", body = [CharacterRecord]))
+)]
+pub async fn list_characters(
+    State(state): State<crate::AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Json<Vec<CharacterRecord>> {
+    Json(state.registry.list(&tenant))
+}
+
+#[utoipa::path(
+    post,
+    path = "/characters",
+    tag = "characters",
+    request_body = CreateCharacterRequest,
+    responses(
+        (status = 201, description = "Character created", body = CharacterRecord),
+        (status = 409, description = "Character already exists", body = crate::openapi::ErrorBody),
+        (status = 422, description = "Invalid definition", body = crate::openapi::ErrorBody)
+    )
+)]
+pub async fn create_character(
+    State(state): State<crate::AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(payload): Json<CreateCharacterRequest>,
+) -> Result<(StatusCode, Json<CharacterRecord>), RegistryError> {
+    let id = payload.id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    let record = state.registry.create(&tenant, id, payload.definition).await?;
+    Ok((StatusCode::CREATED, Json(record)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/characters/{id}",
+    tag = "characters",
+    params(("id" = String, Path, description = "Character id")),
+    responses(
+        (status = 200, description = "Latest version", body = CharacterRecord),
+        (status = 404, description = "Character not found", body = crate::openapi::ErrorBody)
+    )
+)]
+pub async fn get_character(
+    State(state): State<crate::AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<String>,
+) -> Result<Json<CharacterRecord>, RegistryError> {
+    state.registry.get(&tenant, &id).map(Json)
+}
+
+#[utoipa::path(
+    put,
+    path = "/characters/{id}",
+    tag = "characters",
+    params(("id" = String, Path, description = "Character id")),
+    request_body = CharacterDefinition,
+    responses(
+        (status = 200, description = "New version stored", body = CharacterRecord),
+        (status = 404, description = "Character not found", body = crate::openapi::ErrorBody),
+        (status = 422, description = "Invalid definition", body = crate::openapi::ErrorBody)
+    )
+)]
+pub async fn update_character(
+    State(state): State<crate::AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<String>,
+    Json(definition): Json<CharacterDefinition>,
+) -> Result<Json<CharacterRecord>, RegistryError> {
+    state.registry.update(&tenant, &id, definition).await.map(Json)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/characters/{id}",
+    tag = "characters",
+    params(("id" = String, Path, description = "Character id")),
+    responses(
+        (status = 204, description = "Character deleted"),
+        (status = 404, description = "Character not found", body = crate::openapi::ErrorBody)
+    )
+)]
+pub async fn delete_character(
+    State(state): State<crate::AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<String>,
+) -> Result<StatusCode, RegistryError> {
+    state.registry.delete(&tenant, &id).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/characters/{id}/versions",
+    tag = "characters",
+    params(("id" = String, Path, description = "Character id")),
+    responses(
+        (status = 200, description = "Every stored version", body = [CharacterRecord]),
+        (status = 404, description = "Character not found", body = crate::openapi::ErrorBody)
+    )
+)]
+pub async fn list_character_versions(
+    State(state): State<crate::AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path(id): Path<String>,
+) -> Result<Json<Vec<CharacterRecord>>, RegistryError> {
+    state.registry.versions(&tenant, &id).map(Json)
+}
+
+#[utoipa::path(
+    get,
+    path = "/characters/{id}/versions/{version}",
+    tag = "characters",
+    params(
+        ("id" = String, Path, description = "Character id"),
+        ("version" = u32, Path, description = "Character version")
+    ),
+    responses(
+        (status = 200, description = "The requested version", body = CharacterRecord),
+        (status = 404, description = "Character or version not found", body = crate::openapi::ErrorBody)
+    )
+)]
+pub async fn get_character_version(
+    State(state): State<crate::AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Path((id, version)): Path<(String, u32)>,
+) -> Result<Json<CharacterRecord>, RegistryError> {
+    state.registry.get_version(&tenant, &id, version).map(Json)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::provider::ProviderOverrides;
+
+    fn tenant() -> Tenant {
+        Tenant {
+            id: DEFAULT_TENANT.to_string(),
+            balance_profiles: vec!["default".to_string()],
+            provider: ProviderOverrides::default(),
+        }
+    }
+
+    fn definition(personality: &str) -> CharacterDefinition {
+        CharacterDefinition {
+            profile: CharacterProfile::from_personality(personality),
+            default_emotion: 20,
+            default_relationship: 100,
+            balance_profile: "default".to_string(),
+            locale: "en".to_string(),
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("registry-test-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    #[tokio::test]
+    async fn updates_add_versions() {
+        let registry = CharacterRegistry::open(None).unwrap();
+        let tenant = tenant();
+        let created = registry.create(&tenant, "aria".to_string(), definition("Shy")).await.unwrap();
+        let updated = registry.update(&tenant, "aria", definition("Bold")).await.unwrap();
+
+        assert_eq!((created.version, updated.version), (1, 2));
+        assert_eq!(registry.get(&tenant, "aria").unwrap().definition.profile.personality, "Bold");
+        assert_eq!(registry.get_version(&tenant, "aria", 1).unwrap().definition.profile.personality, "Shy");
+        assert!(matches!(
+            registry.create(&tenant, "aria".to_string(), definition("Shy")).await,
+            Err(RegistryError::Conflict(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn recreating_a_deleted_character_continues_its_versions() {
+        let registry = CharacterRegistry::open(None).unwrap();
+        let tenant = tenant();
+        registry.create(&tenant, "aria".to_string(), definition("Shy")).await.unwrap();
+        registry.delete(&tenant, "aria").await.unwrap();
+
+        assert!(matches!(registry.get(&tenant, "aria"), Err(RegistryError::NotFound(_))));
+        assert!(matches!(registry.update(&tenant, "aria", definition("Bold")).await, Err(RegistryError::NotFound(_))));
+        assert_eq!(registry.get_version(&tenant, "aria", 1).unwrap().version, 1);
+
+        let recreated = registry.create(&tenant, "aria".to_string(), definition("Bold")).await.unwrap();
+        assert_eq!(recreated.version, 2);
+        assert_eq!(registry.versions(&tenant, "aria").unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_writes_are_rolled_back() {
+        let dir = temp_dir("rollback");
+        let path = dir.join("characters.json");
+        let registry = CharacterRegistry::open(Some(path.clone())).unwrap();
+        let tenant = tenant();
+        registry.create(&tenant, "aria".to_string(), definition("Shy")).await.unwrap();
+
+        // A directory in place of the file makes every following write fail
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+
+        let storage_error = |result: Result<_, RegistryError>| matches!(result, Err(RegistryError::Storage(_)));
+        assert!(storage_error(registry.update(&tenant, "aria", definition("Bold")).await.map(|_| ())));
+        assert!(storage_error(registry.create(&tenant, "bob".to_string(), definition("Calm")).await.map(|_| ())));
+        assert!(storage_error(registry.delete(&tenant, "aria").await));
+
+        let aria = registry.get(&tenant, "aria").unwrap();
+        assert_eq!((aria.version, aria.definition.profile.personality.as_str()), (1, "Shy"));
+        assert!(matches!(registry.get(&tenant, "bob"), Err(RegistryError::NotFound(_))));
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
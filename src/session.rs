@@ -0,0 +1,380 @@
+// Conversation sessions: rolling history and current state per character/user pair
+
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::auth::DEFAULT_TENANT;
+use crate::character::CharacterProfile;
+use crate::ranges::TierLookup;
+use crate::shutdown::Shutdown;
+use crate::{persist, ranges};
+
+/// Number of history lines kept per session and sent to the provider
+pub const HISTORY_WINDOW: usize = 20;
+
+/// Server-side state of one conversation between a user and a character
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct SessionState {
+    pub id: String,
+    /// Owning tenant; sessions saved before tenants existed belong to the default tenant
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    pub user_id: String,
+    /// Registry character (pinned to the version resolved when the session opened)
+    pub character_id: Option<String>,
+    pub character_version: Option<u32>,
+    /// Inline character used when the session is not backed by the registry
+    pub character: Option<CharacterProfile>,
+    pub emotion: i32,
+    pub relationship: i32,
+    pub history: VecDeque<String>,
+    pub updated_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// A tier boundary crossed by an emotion or relationship update
+#[derive(Debug, Clone, Serialize)]
+pub struct TierTransition {
+    pub dimension: &'static str,
+    pub from: &'static str,
+    pub to: &'static str,
+}
+
+fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+impl SessionState {
+    /// Store key of this session, see [`SessionStore::key`]
+    pub fn key(&self) -> String {
+        let character_key = match (&self.character_id, &self.character) {
+            (Some(id), _) => id.as_str(),
+            (None, Some(profile)) => profile.name.as_str(),
+            (None, None) => "",
+        };
+        SessionStore::key(&self.tenant, character_key, &self.user_id)
+    }
+
+    /// Append a line to the rolling history, dropping the oldest lines beyond the
+    /// window or beyond `max_chars` characters in total
+    pub fn push_history(&mut self, line: String, max_chars: usize) {
+        self.updated_at = chrono::Utc::now();
+        self.history.push_back(line);
+        while self.history.len() > HISTORY_WINDOW {
+            self.history.pop_front();
+        }
+        self.trim_history(max_chars);
+    }
+
+    /// Drop the oldest lines until the history text fits in `max_chars` characters;
+    /// a single line that is too long keeps its end
+    pub fn trim_history(&mut self, max_chars: usize) {
+        let mut total = self.history_text().chars().count();
+        while total > max_chars && self.history.len() > 1 {
+            // The line and the newline after it
+            total -= self.history.pop_front().map_or(0, |line| line.chars().count() + 1);
+        }
+        if let Some(line) = self.history.front_mut().filter(|line| line.chars().count() > max_chars) {
+            let skip = line.chars().count() - max_chars;
+            *line = line.chars().skip(skip).collect();
+        }
+    }
+
+    pub fn history_text(&self) -> String {
+        self.history.iter().cloned().collect::<Vec<_>>().join("\n")
+    }
+
+    /// Apply analysis deltas, clamped to the tier tables, and report crossed tiers
+    pub fn apply_changes(&mut self, emotion_change: i32, relationship_change: i32) -> Vec<TierTransition> {
+        let (emotion_min, emotion_max) = ranges::emotion_bounds();
+        let (relationship_min, relationship_max) = ranges::relationship_bounds();
+
+        let emotion_before = emotion_tier(self.emotion);
+        let relationship_before = relationship_tier(self.relationship);
+
+        self.emotion = self.emotion.saturating_add(emotion_change).clamp(emotion_min, emotion_max);
+        self.relationship = self
+            .relationship
+            .saturating_add(relationship_change)
+            .clamp(relationship_min, relationship_max);
+        self.updated_at = chrono::Utc::now();
+
+        let mut transitions = Vec::new();
+        let emotion_after = emotion_tier(self.emotion);
+        if emotion_after != emotion_before {
+            transitions.push(TierTransition {
+                dimension: "emotion",
+                from: emotion_before,
+                to: emotion_after,
+            });
+        }
+        let relationship_after = relationship_tier(self.relationship);
+        if relationship_after != relationship_before {
+            transitions.push(TierTransition {
+                dimension: "relationship",
+                from: relationship_before,
+                to: relationship_after,
+            });
+        }
+        transitions
+    }
+}
+
+/// Emotion tier name, using the same fallback as the provider prompt
+pub fn emotion_tier(value: i32) -> &'static str {
+    ranges::get_emotion_from_value(value, TierLookup::Corrected).unwrap_or("Neutral")
+}
+
+/// Relationship tier name, using the same fallback as the provider prompt
+pub fn relationship_tier(value: i32) -> &'static str {
+    ranges::get_relationship_from_value(value, TierLookup::Corrected).unwrap_or("Acquaintance")
+}
+
+/// In-memory session store, optionally persisted to a JSON file
+pub struct SessionStore {
+    sessions: Mutex<HashMap<String, Arc<tokio::sync::Mutex<SessionState>>>>,
+    /// Latest recorded state of every session; flushes write this copy, so they
+    /// never wait on a session that is busy with an analysis
+    saved: Mutex<HashMap<String, SessionState>>,
+    /// A session was recorded or expired since the last flush
+    dirty: AtomicBool,
+    /// Held by the flush that is writing the file
+    writer: tokio::sync::Mutex<()>,
+    path: Option<PathBuf>,
+}
+
+impl SessionStore {
+    /// Create a store, loading saved sessions from `path` if it exists
+    pub fn open(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let saved: HashMap<String, SessionState> = match &path {
+            Some(path) if path.exists() => serde_json::from_str(&std::fs::read_to_string(path)?)?,
+            _ => HashMap::new(),
+        };
+        // Keys are rebuilt from the sessions so files from before tenant scoping load too
+        let saved: HashMap<String, SessionState> = saved.into_values().map(|state| (state.key(), state)).collect();
+        let sessions = saved
+            .iter()
+            .map(|(key, state)| (key.clone(), Arc::new(tokio::sync::Mutex::new(state.clone()))))
+            .collect();
+        Ok(SessionStore {
+            sessions: Mutex::new(sessions),
+            saved: Mutex::new(saved),
+            dirty: AtomicBool::new(false),
+            writer: tokio::sync::Mutex::new(()),
+            path,
+        })
+    }
+
+    /// File the store is persisted to, if any
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Session key for a character/user pair of a tenant
+    pub fn key(tenant: &str, character_key: &str, user_id: &str) -> String {
+        format!("{}::{}::{}", tenant, character_key, user_id)
+    }
+
+    /// Get an existing session
+    pub fn get(&self, key: &str) -> Option<Arc<tokio::sync::Mutex<SessionState>>> {
+        self.sessions.lock().unwrap().get(key).cloned()
+    }
+
+    /// Get the session for `key`, creating it with `init` if it does not exist yet.
+    /// Returns the session and whether it already existed.
+    pub fn get_or_create(
+        &self,
+        key: &str,
+        init: impl FnOnce() -> SessionState,
+    ) -> (Arc<tokio::sync::Mutex<SessionState>>, bool) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(session) = sessions.get(key) {
+            return (session.clone(), true);
+        }
+        let state = init();
+        self.record(&state);
+        let session = Arc::new(tokio::sync::Mutex::new(state));
+        sessions.insert(key.to_string(), session.clone());
+        (session, false)
+    }
+
+    /// Remember a session's state for the next flush; call after every change
+    pub fn record(&self, state: &SessionState) {
+        self.saved.lock().unwrap().insert(state.key(), state.clone());
+        self.dirty.store(true, Ordering::Release);
+    }
+
+    /// Drop sessions idle for longer than `ttl`; sessions held by a connection stay.
+    /// Returns how many were dropped.
+    pub fn expire(&self, ttl: Duration) -> usize {
+        let cutoff = chrono::Utc::now() - ttl;
+        let mut sessions = self.sessions.lock().unwrap();
+        let mut saved = self.saved.lock().unwrap();
+        let before = sessions.len();
+        sessions.retain(|key, session| {
+            let idle = Arc::strong_count(session) == 1
+                && session.try_lock().is_ok_and(|state| state.updated_at < cutoff);
+            if idle {
+                saved.remove(key);
+            }
+            !idle
+        });
+        let expired = before - sessions.len();
+        if expired > 0 {
+            self.dirty.store(true, Ordering::Release);
+        }
+        expired
+    }
+
+    /// Write the recorded sessions to the configured file, if anything changed
+    pub async fn flush(&self) -> anyhow::Result<()> {
+        let Some(path) = self.path.clone() else {
+            return Ok(());
+        };
+        let _writer = self.writer.lock().await;
+        if !self.dirty.swap(false, Ordering::AcqRel) {
+            return Ok(());
+        }
+        let data = serde_json::to_vec_pretty(&*self.saved.lock().unwrap());
+        let written = match data {
+            Ok(data) => tokio::task::spawn_blocking(move || persist::write_atomically(&path, &data))
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|r| r.map_err(anyhow::Error::from)),
+            Err(e) => Err(e.into()),
+        };
+        if written.is_err() {
+            // Retried on the next flush
+            self.dirty.store(true, Ordering::Release);
+        }
+        written
+    }
+}
+
+/// Every `interval`, drop sessions idle for longer than `ttl` and write the
+/// changed ones, until shutdown. The final flush happens after the drain.
+pub fn maintain(sessions: Arc<SessionStore>, interval: Duration, ttl: Duration, shutdown: Shutdown) {
+    tokio::spawn(async move {
+        let mut ticks = tokio::time::interval(interval);
+        let stop = shutdown.wait();
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                _ = &mut stop => return,
+            }
+            let expired = sessions.expire(ttl);
+            if expired > 0 {
+                info!(expired, "🧹 idle sessions expired");
+            }
+            if let Err(e) = sessions.flush().await {
+                warn!(error = %e, "⚠️ failed to persist sessions");
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn session(user_id: &str, history: &[&str]) -> SessionState {
+        SessionState {
+            id: format!("session-{}", user_id),
+            tenant: DEFAULT_TENANT.to_string(),
+            user_id: user_id.to_string(),
+            character_id: Some("aria".to_string()),
+            character_version: Some(1),
+            character: None,
+            emotion: 0,
+            relationship: 0,
+            history: history.iter().map(|line| line.to_string()).collect(),
+            updated_at: chrono::Utc::now(),
+        }
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("sessions-test-{}-{}.json", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn history_is_trimmed_at_line_boundaries() {
+        let mut state = session("u1", &["aaaa", "bbbb", "cccc"]);
+        state.trim_history(14);
+        assert_eq!(state.history_text(), "aaaa\nbbbb\ncccc");
+
+        // Whole lines are dropped, oldest first, until the text fits
+        state.trim_history(13);
+        assert_eq!(state.history_text(), "bbbb\ncccc");
+
+        // The last line is cut to its end only when it alone is too long
+        state.push_history("0123456789".to_string(), 6);
+        assert_eq!(state.history_text(), "456789");
+    }
+
+    #[test]
+    fn history_keeps_the_window() {
+        let mut state = session("u1", &[]);
+        for i in 0..HISTORY_WINDOW + 5 {
+            state.push_history(format!("line {}", i), usize::MAX);
+        }
+        assert_eq!(state.history.len(), HISTORY_WINDOW);
+        assert_eq!(state.history.front().unwrap(), "line 5");
+    }
+
+    #[test]
+    fn idle_sessions_expire_unless_held() {
+        let store = SessionStore::open(None).unwrap();
+        let stale = chrono::Utc::now() - chrono::Duration::hours(2);
+        for user_id in ["idle", "held", "active"] {
+            let mut state = session(user_id, &[]);
+            if user_id != "active" {
+                state.updated_at = stale;
+            }
+            store.get_or_create(&state.key(), || state.clone());
+        }
+        let held = store.get(&SessionStore::key(DEFAULT_TENANT, "aria", "held")).unwrap();
+
+        assert_eq!(store.expire(Duration::from_secs(3600)), 1);
+        assert!(store.get(&SessionStore::key(DEFAULT_TENANT, "aria", "idle")).is_none());
+        assert!(store.get(&SessionStore::key(DEFAULT_TENANT, "aria", "active")).is_some());
+
+        drop(held);
+        assert_eq!(store.expire(Duration::from_secs(3600)), 1);
+        assert_eq!(store.expire(Duration::from_secs(3600)), 0);
+    }
+
+    #[tokio::test]
+    async fn only_changes_are_flushed() {
+        let path = temp_path("flush");
+        let store = SessionStore::open(Some(path.clone())).unwrap();
+        store.flush().await.unwrap();
+        assert!(!path.exists(), "nothing changed, nothing written");
+
+        let state = session("u1", &["hello"]);
+        store.get_or_create(&state.key(), || state.clone());
+        store.flush().await.unwrap();
+        let reopened = SessionStore::open(Some(path.clone())).unwrap();
+        assert_eq!(reopened.get(&state.key()).unwrap().lock().await.history_text(), "hello");
+
+        // Clean after the flush: a second one does not rewrite the file
+        std::fs::remove_file(&path).unwrap();
+        store.flush().await.unwrap();
+        assert!(!path.exists());
+
+        let mut changed = state.clone();
+        changed.push_history("again".to_string(), usize::MAX);
+        store.record(&changed);
+        store.flush().await.unwrap();
+        let reopened = SessionStore::open(Some(path.clone())).unwrap();
+        assert_eq!(reopened.get(&state.key()).unwrap().lock().await.history_text(), "hello\nagain");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,56 @@
+// Graceful shutdown: SIGTERM/SIGINT stop the listeners and tell long-lived connections to close
+
+use tokio::sync::watch;
+
+/// Resolves once shutdown has started; cloned into every server and WebSocket
+#[derive(Clone)]
+pub struct Shutdown {
+    receiver: watch::Receiver<bool>,
+}
+
+/// Starts the shutdown seen by every [`Shutdown`] handle
+pub struct ShutdownTrigger {
+    sender: watch::Sender<bool>,
+}
+
+pub fn channel() -> (ShutdownTrigger, Shutdown) {
+    let (sender, receiver) = watch::channel(false);
+    (ShutdownTrigger { sender }, Shutdown { receiver })
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+}
+
+impl Shutdown {
+    /// Wait until shutdown starts
+    pub async fn wait(mut self) {
+        // An error means the trigger is gone, which only happens when the process exits
+        let _ = self.receiver.wait_for(|started| *started).await;
+    }
+}
+
+/// Wait for SIGTERM (sent by orchestrators on deploy) or SIGINT (Ctrl+C) and name it
+pub async fn signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = terminate.recv() => "SIGTERM",
+                _ = tokio::signal::ctrl_c() => "SIGINT",
+            },
+            Err(_) => {
+                let _ = tokio::signal::ctrl_c().await;
+                "SIGINT"
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+        "SIGINT"
+    }
+}
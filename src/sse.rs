@@ -0,0 +1,134 @@
+// Server-Sent Events variant of /analyze-emotion
+
+use std::convert::Infallible;
+
+use async_stream::stream;
+use axum::{
+    extract::State,
+    Extension,
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        Json,
+    },
+};
+use futures::{pin_mut, Stream};
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use tracing::{info, warn, Instrument, Span};
+
+use crate::analysis::{self, AnalysisError, AnalysisOutcome};
+use crate::auth::Tenant;
+use crate::ranges::TierLookup;
+use crate::{AppState, EmotionRequest, EmotionResponse, EmotionResponseV2};
+
+/// Build a typed SSE event with a JSON payload
+fn event(name: &str, data: serde_json::Value) -> Result<Event, Infallible> {
+    Ok(Event::default().event(name).data(data.to_string()))
+}
+
+fn error_event(span: &Span, message: String) -> Result<Event, Infallible> {
+    warn!(parent: span, error = %message, "⚠️ stream analysis failed");
+    event("error", serde_json::json!({ "errors": [message] }))
+}
+
+/// Stream analysis progress as discrete events:
+/// `thinking`, one `token` per provider delta, `category`, then `result` (or `error`).
+/// `category` carries the confidence; with an ensemble there are no `token` events.
+#[utoipa::path(
+    post,
+    path = "/analyze-emotion/stream",
+    request_body = EmotionRequest,
+    responses(
+        (status = 200, description = "Event stream: thinking, token, category, result or error", content_type = "text/event-stream", body = String),
+        (status = 404, description = "Referenced registry character does not exist", body = crate::openapi::ErrorBody),
+        (status = 422, description = "Invalid request; lists every invalid field", body = crate::openapi::ErrorBody),
+        (status = 429, description = "Rate limit exceeded; see the Retry-After header", body = crate::openapi::ErrorBody)
+    )
+)]
+pub async fn analyze_emotion_stream(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(payload): Json<EmotionRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AnalysisError> {
+    stream_analysis::<EmotionResponse>(state, tenant, payload, TierLookup::Legacy).await
+}
+
+/// `/v2` streaming analysis; the `result` event carries the richer response
+#[utoipa::path(
+    post,
+    path = "/analyze-emotion/stream",
+    request_body = EmotionRequest,
+    responses(
+        (status = 200, description = "Event stream: thinking, token, category, result or error", content_type = "text/event-stream", body = String),
+        (status = 404, description = "Referenced registry character does not exist", body = crate::openapi::ErrorBody),
+        (status = 422, description = "Invalid request; lists every invalid field", body = crate::openapi::ErrorBody),
+        (status = 429, description = "Rate limit exceeded; see the Retry-After header", body = crate::openapi::ErrorBody)
+    )
+)]
+pub async fn analyze_emotion_stream_v2(
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+    Json(payload): Json<EmotionRequest>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AnalysisError> {
+    stream_analysis::<EmotionResponseV2>(state, tenant, payload, TierLookup::Corrected).await
+}
+
+async fn stream_analysis<T: From<AnalysisOutcome> + Serialize + Send + 'static>(
+    state: AppState,
+    tenant: Tenant,
+    payload: EmotionRequest,
+    tiers: TierLookup,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AnalysisError> {
+    info!("🎯 streaming emotion analysis request");
+
+    // Request errors are reported as a plain HTTP error before the stream starts
+    let log_error = |error: &AnalysisError| {
+        for message in error.messages() {
+            warn!(error = %message, "⚠️ analysis failed");
+        }
+    };
+    let prepared = analysis::prepare(&state, &tenant, &payload, tiers).inspect_err(log_error)?;
+    analysis::check_rate_limit(&state, &tenant, &payload, &prepared)
+        .await
+        .inspect_err(log_error)?;
+
+    // The stream is polled after the handler returns, so it logs into the request span explicitly
+    let span = Span::current();
+    let events = stream! {
+        yield event("thinking", serde_json::json!({}));
+
+        // Provider tokens are sent on while the shared pipeline classifies the input
+        let (token_sender, mut token_receiver) = mpsc::unbounded_channel();
+        let result = {
+            let classifying = analysis::classification(&state, &tenant, &prepared, Some(&token_sender))
+                .instrument(span.clone());
+            pin_mut!(classifying);
+            loop {
+                let text = tokio::select! {
+                    biased;
+                    Some(text) = token_receiver.recv() => text,
+                    result = &mut classifying => break result,
+                };
+                yield event("token", serde_json::json!({ "text": text }));
+            }
+        };
+        while let Ok(text) = token_receiver.try_recv() {
+            yield event("token", serde_json::json!({ "text": text }));
+        }
+
+        let classification = match result {
+            Ok(classification) => classification,
+            Err(e) => {
+                yield error_event(&span, e.messages().join("; "));
+                return;
+            }
+        };
+        yield event("category", serde_json::to_value(&classification).unwrap_or_default());
+
+        let result: T = span.in_scope(|| analysis::compute(&state, prepared, &classification)).into();
+        yield event("result", serde_json::to_value(&result).unwrap_or_default());
+    };
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
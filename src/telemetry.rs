@@ -0,0 +1,156 @@
+// Structured logging: per-request spans, pretty output for development and JSON for log pipelines.
+// Spans are also exported as OpenTelemetry traces when an OTLP collector is configured.
+
+use std::env;
+use std::time::Instant;
+
+use axum::{
+    extract::Request,
+    http::{HeaderMap, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use opentelemetry::{global, trace::TracerProvider as _, Context};
+use opentelemetry_http::{HeaderExtractor, HeaderInjector};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, trace::SdkTracerProvider, Resource};
+use tracing::{field, info, info_span, warn, Instrument, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
+
+/// Header carrying the request id, accepted from clients and echoed on responses
+const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Service name reported to the collector unless `OTEL_SERVICE_NAME` is set
+const DEFAULT_SERVICE_NAME: &str = "emotion-ai-agent-system";
+
+/// Keeps the trace exporter alive; call [`Telemetry::shutdown`] to flush pending spans
+pub struct Telemetry {
+    tracer_provider: SdkTracerProvider,
+}
+
+impl Telemetry {
+    /// Export the spans still buffered by the batch exporter
+    pub fn shutdown(self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            warn!(error = %e, "⚠️ failed to flush traces");
+        }
+    }
+}
+
+/// OTLP/HTTP exporter, enabled by `OTEL_EXPORTER_OTLP_ENDPOINT` or
+/// `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT` (e.g. `http://localhost:4318`)
+fn tracer_provider() -> anyhow::Result<Option<SdkTracerProvider>> {
+    let configured = ["OTEL_EXPORTER_OTLP_ENDPOINT", "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"]
+        .iter()
+        .any(|name| env::var(name).is_ok_and(|v| !v.is_empty()));
+    if !configured {
+        return Ok(None);
+    }
+
+    let exporter = opentelemetry_otlp::SpanExporter::builder().with_http().build()?;
+    Ok(Some(
+        SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource())
+            .build(),
+    ))
+}
+
+fn resource() -> Resource {
+    let service_name = env::var("OTEL_SERVICE_NAME").unwrap_or_else(|_| DEFAULT_SERVICE_NAME.to_string());
+    Resource::builder().with_service_name(service_name).build()
+}
+
+/// Install the global subscriber. Levels come from `RUST_LOG` (default `info`);
+/// `LOG_FORMAT` selects `pretty` (default), `compact` or `json`.
+pub fn init() -> Telemetry {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    let (exporting, exporter_error) = match tracer_provider() {
+        Ok(provider) => (provider, None),
+        Err(e) => (None, Some(e)),
+    };
+    // Without an exporter spans still get trace ids, so a caller's `traceparent`
+    // is continued and passed on to the provider; they are just not sent anywhere
+    let exports = exporting.is_some();
+    let tracer_provider = exporting.unwrap_or_else(|| SdkTracerProvider::builder().with_resource(resource()).build());
+    let otel = tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(DEFAULT_SERVICE_NAME));
+
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let registry = tracing_subscriber::registry().with(filter).with(otel);
+    match env::var("LOG_FORMAT").as_deref() {
+        Ok("json") => registry
+            .with(fmt::layer().json().flatten_event(true).with_current_span(true).with_span_list(false))
+            .init(),
+        Ok("compact") => registry.with(fmt::layer().compact()).init(),
+        _ => registry.with(fmt::layer().pretty()).init(),
+    }
+
+    match (exports, exporter_error) {
+        (_, Some(e)) => warn!(error = %e, "⚠️ OTLP exporter could not be created, traces are not exported"),
+        (true, None) => info!("🛰️ exporting traces over OTLP"),
+        (false, None) => {}
+    }
+    Telemetry { tracer_provider }
+}
+
+/// Make `span` a child of the W3C `traceparent` carried by `headers`, if any
+fn continue_trace(span: &Span, headers: &HeaderMap) {
+    let parent = global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)));
+    let _ = span.set_parent(parent);
+}
+
+/// Write the current span's trace context into outgoing request headers
+pub fn inject_trace_context(headers: &mut HeaderMap) {
+    let context: Context = Span::current().context();
+    global::get_text_map_propagator(|propagator| propagator.inject_context(&context, &mut HeaderInjector(headers)));
+}
+
+/// Span for a gRPC call, continuing the caller's trace
+pub fn grpc_span(request: &axum::http::Request<()>) -> Span {
+    let span = info_span!(
+        "grpc",
+        otel.kind = "server",
+        path = %request.uri().path(),
+        tenant = field::Empty,
+        character = field::Empty,
+    );
+    continue_trace(&span, request.headers());
+    span
+}
+
+/// Run every HTTP request in a `request` span; `tenant` and `character` are
+/// recorded once they are known
+pub async fn request_span(request: Request, next: Next) -> Response {
+    let request_id = request
+        .headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| !v.is_empty() && v.len() <= 128)
+        .map(str::to_string)
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    let span = info_span!(
+        "request",
+        otel.kind = "server",
+        request_id = %request_id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        tenant = field::Empty,
+        character = field::Empty,
+    );
+    continue_trace(&span, request.headers());
+
+    let started = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    info!(
+        parent: &span,
+        status = response.status().as_u16(),
+        latency_ms = started.elapsed().as_millis() as u64,
+        "request finished"
+    );
+
+    if let Ok(value) = HeaderValue::from_str(&request_id) {
+        response.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+    response
+}
@@ -0,0 +1,64 @@
+// Optional TLS termination; the certificate and key are reloaded when their files change
+
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use tracing::{info, warn};
+
+use crate::shutdown::Shutdown;
+
+/// How often the certificate files are checked for changes
+const RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// PEM certificate chain and private key
+#[derive(Debug, Clone)]
+pub struct TlsPaths {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+/// Modification time and size of both files, compared to notice replacements
+fn fingerprint(paths: &TlsPaths) -> Option<[(SystemTime, u64); 2]> {
+    let stamp = |path: &Path| {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some((metadata.modified().ok()?, metadata.len()))
+    };
+    Some([stamp(&paths.cert_path)?, stamp(&paths.key_path)?])
+}
+
+/// Load the certificate and key
+pub async fn load(paths: &TlsPaths) -> anyhow::Result<RustlsConfig> {
+    // Several dependencies link rustls; pick the provider explicitly. Installing
+    // twice only fails when one is already set, which is fine.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    RustlsConfig::from_pem_file(&paths.cert_path, &paths.key_path)
+        .await
+        .map_err(|e| anyhow::anyhow!("{} / {}: {}", paths.cert_path.display(), paths.key_path.display(), e))
+}
+
+/// Reload `config` whenever the files change, until shutdown. A broken replacement
+/// is logged and the previous certificate stays in use.
+pub fn watch(config: RustlsConfig, paths: TlsPaths, shutdown: Shutdown) {
+    tokio::spawn(async move {
+        let mut last = fingerprint(&paths);
+        let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+        let stop = shutdown.wait();
+        tokio::pin!(stop);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => {}
+                _ = &mut stop => return,
+            }
+            let current = fingerprint(&paths);
+            if current.is_none() || current == last {
+                continue;
+            }
+            last = current;
+            match config.reload_from_pem_file(&paths.cert_path, &paths.key_path).await {
+                Ok(()) => info!(cert = %paths.cert_path.display(), "🔐 TLS certificate reloaded"),
+                Err(e) => warn!(error = %e, "⚠️ TLS certificate reload failed, keeping the previous one"),
+            }
+        }
+    });
+}
@@ -0,0 +1,234 @@
+// Declarative validation of analysis requests
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::Serialize;
+use utoipa::ToSchema;
+
+/// A single invalid field
+#[derive(Debug, Clone, Serialize, ToSchema)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.field, self.message)
+    }
+}
+
+/// What to do with a blank `user_input`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmptyInputPolicy {
+    /// Reject the request with a field error
+    Reject,
+    /// Skip the provider and answer as Neutral Behavior
+    Neutral,
+}
+
+impl FromStr for EmptyInputPolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "reject" => Ok(EmptyInputPolicy::Reject),
+            "neutral" => Ok(EmptyInputPolicy::Neutral),
+            other => Err(format!("'{}' must be reject or neutral", other)),
+        }
+    }
+}
+
+/// What to do with `current_emotion` / `current_relationship` outside the tier tables
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutOfRangePolicy {
+    /// Reject the request with a field error
+    Reject,
+    /// Clamp the value to the nearest covered value
+    Clamp,
+}
+
+impl FromStr for OutOfRangePolicy {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.trim() {
+            "reject" => Ok(OutOfRangePolicy::Reject),
+            "clamp" => Ok(OutOfRangePolicy::Clamp),
+            other => Err(format!("'{}' must be reject or clamp", other)),
+        }
+    }
+}
+
+/// Limits and policies applied to every analysis request, validated by [`crate::config::Config::load`]
+#[derive(Debug, Clone, Copy)]
+pub struct ValidationSettings {
+    pub max_user_input_chars: usize,
+    pub max_history_chars: usize,
+    pub max_personality_chars: usize,
+    pub empty_input: EmptyInputPolicy,
+    pub out_of_range: OutOfRangePolicy,
+}
+
+impl Default for ValidationSettings {
+    fn default() -> Self {
+        ValidationSettings {
+            max_user_input_chars: 2000,
+            max_history_chars: 20000,
+            max_personality_chars: 4000,
+            empty_input: EmptyInputPolicy::Reject,
+            out_of_range: OutOfRangePolicy::Reject,
+        }
+    }
+}
+
+impl ValidationSettings {
+    /// Apply the out-of-range policy to a current state value: check it or clamp it
+    pub fn state_value(&self, validator: &mut Validator, field: &str, value: i32, bounds: (i32, i32)) -> i32 {
+        match self.out_of_range {
+            OutOfRangePolicy::Reject => {
+                validator.in_range(field, value, bounds);
+                value
+            }
+            OutOfRangePolicy::Clamp => value.clamp(bounds.0, bounds.1),
+        }
+    }
+}
+
+/// Collects every field error instead of stopping at the first one
+#[derive(Default)]
+pub struct Validator {
+    errors: Vec<FieldError>,
+}
+
+impl Validator {
+    pub fn new() -> Self {
+        Validator::default()
+    }
+
+    pub fn error(&mut self, field: impl Into<String>, message: impl Into<String>) {
+        self.errors.push(FieldError {
+            field: field.into(),
+            message: message.into(),
+        });
+    }
+
+    /// The value must contain something other than whitespace
+    pub fn not_blank(&mut self, field: &str, value: &str) -> &mut Self {
+        if value.trim().is_empty() {
+            self.error(field, "must not be empty");
+        }
+        self
+    }
+
+    /// The value must be at most `max` characters long
+    pub fn max_chars(&mut self, field: &str, value: &str, max: usize) -> &mut Self {
+        if value.chars().count() > max {
+            self.error(field, format!("must be at most {} characters", max));
+        }
+        self
+    }
+
+    /// The list must have at most `max` entries
+    pub fn max_entries<T>(&mut self, field: &str, values: &[T], max: usize) -> &mut Self {
+        if values.len() > max {
+            self.error(field, format!("must have at most {} entries", max));
+        }
+        self
+    }
+
+    /// The value must lie within `bounds` (inclusive)
+    pub fn in_range(&mut self, field: &str, value: i32, (min, max): (i32, i32)) -> &mut Self {
+        if value < min || value > max {
+            self.error(field, format!("must be between {} and {}", min, max));
+        }
+        self
+    }
+
+    pub fn extend(&mut self, errors: Vec<FieldError>) {
+        self.errors.extend(errors);
+    }
+
+    pub fn finish(self) -> Result<(), Vec<FieldError>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(self.errors)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+
+    use super::*;
+    use crate::analysis::AnalysisError;
+
+    #[test]
+    fn collects_every_field_error() {
+        let mut validator = Validator::new();
+        validator
+            .not_blank("user_input", "   ")
+            .max_chars("character_history", "héllo", 4)
+            .max_chars("character_personality", "kind", 4)
+            .max_entries("items", &[1, 2, 3], 2)
+            .in_range("current_emotion", 201, (-200, 200));
+        validator.extend(vec![FieldError {
+            field: "character_id".to_string(),
+            message: "is unknown".to_string(),
+        }]);
+
+        let errors = validator.finish().unwrap_err();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, ["user_input", "character_history", "items", "current_emotion", "character_id"]);
+        assert_eq!(errors[1].to_string(), "character_history must be at most 4 characters");
+        assert_eq!(errors[3].message, "must be between -200 and 200");
+
+        // All of them are reported together in a single 422
+        let error = AnalysisError::Invalid(errors);
+        assert_eq!(error.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(error.messages().len(), 5);
+        assert_eq!(error.fields().len(), 5);
+    }
+
+    #[test]
+    fn a_valid_request_has_no_errors() {
+        let mut validator = Validator::new();
+        validator
+            .not_blank("user_input", "hi")
+            .max_chars("user_input", "hi", 2)
+            .in_range("current_emotion", -200, (-200, 200));
+        assert!(validator.finish().is_ok());
+    }
+
+    #[test]
+    fn out_of_range_state_values_are_rejected_or_clamped() {
+        let bounds = (-200, 200);
+        let reject = ValidationSettings::default();
+        let clamp = ValidationSettings {
+            out_of_range: OutOfRangePolicy::Clamp,
+            ..ValidationSettings::default()
+        };
+
+        for (value, clamped) in [(250, 200), (-999, -200), (15, 15)] {
+            let mut validator = Validator::new();
+            assert_eq!(reject.state_value(&mut validator, "current_emotion", value, bounds), value);
+            assert_eq!(validator.finish().is_err(), value != clamped);
+
+            let mut validator = Validator::new();
+            assert_eq!(clamp.state_value(&mut validator, "current_emotion", value, bounds), clamped);
+            assert!(validator.finish().is_ok());
+        }
+    }
+
+    #[test]
+    fn policies_parse_or_name_the_choices() {
+        assert_eq!("neutral".parse(), Ok(EmptyInputPolicy::Neutral));
+        assert_eq!(" reject ".parse(), Ok(EmptyInputPolicy::Reject));
+        assert_eq!("neutrl".parse::<EmptyInputPolicy>(), Err("'neutrl' must be reject or neutral".to_string()));
+        assert_eq!("clamp".parse(), Ok(OutOfRangePolicy::Clamp));
+        assert!("clip".parse::<OutOfRangePolicy>().is_err());
+    }
+}
@@ -0,0 +1,21 @@
+// API versioning: deprecation headers for the unversioned route aliases
+
+use axum::{
+    extract::Request,
+    http::HeaderValue,
+    middleware::Next,
+    response::Response,
+};
+
+/// Mark responses from unversioned routes as deprecated and point at the /v1 route
+pub async fn deprecated_alias(request: Request, next: Next) -> Response {
+    let successor = format!("</v1{}>; rel=\"successor-version\"", request.uri().path());
+    let mut response = next.run(request).await;
+
+    let headers = response.headers_mut();
+    headers.insert("Deprecation", HeaderValue::from_static("true"));
+    if let Ok(link) = HeaderValue::from_str(&successor) {
+        headers.insert("Link", link);
+    }
+    response
+}
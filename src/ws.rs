@@ -0,0 +1,281 @@
+// WebSocket sessions for continuous conversations
+
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::Response,
+    Extension,
+};
+use serde::{Deserialize, Serialize};
+
+use tracing::{field, info, info_span, Instrument, Span};
+
+use crate::auth::Tenant;
+use crate::character::CharacterProfile;
+use crate::ranges::TierLookup;
+use crate::registry::RegistryError;
+use crate::session::{self, SessionState, SessionStore, TierTransition};
+use crate::validation::Validator;
+use crate::{analysis, ranges, AppState, EmotionRequest};
+
+/// Opens (or resumes) the session for a character/user pair; must be sent first
+#[derive(Deserialize)]
+struct OpenSession {
+    user_id: String,
+    #[serde(default)]
+    character_id: Option<String>,
+    #[serde(default)]
+    character_version: Option<u32>,
+    #[serde(default)]
+    character: Option<CharacterProfile>,
+    /// Starting state for new sessions; defaults to the character's baseline
+    #[serde(default)]
+    current_emotion: Option<i32>,
+    #[serde(default)]
+    current_relationship: Option<i32>,
+}
+
+/// Messages sent by the client
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    Open(Box<OpenSession>),
+    /// A user message to analyze
+    Input { text: String },
+    /// The character's reply, recorded in the rolling history only
+    CharacterReply { text: String },
+}
+
+/// Messages sent by the server
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage<'a> {
+    Opened {
+        session_id: &'a str,
+        resumed: bool,
+        emotion: i32,
+        relationship: i32,
+        emotion_tier: &'static str,
+        relationship_tier: &'static str,
+    },
+    Analysis {
+        behavior_category: &'a str,
+        emotion_change: i32,
+        relationship_change: i32,
+        emotion: i32,
+        relationship: i32,
+    },
+    TierTransition(&'a TierTransition),
+    Error { errors: Vec<String> },
+}
+
+impl ServerMessage<'_> {
+    fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions/ws",
+    responses((status = 101, description = "WebSocket upgrade; see the README for the message protocol"))
+)]
+pub async fn session_ws(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Extension(tenant): Extension<Tenant>,
+) -> Response {
+    let span = info_span!("ws_session", user_id = field::Empty, character = field::Empty);
+    ws.on_upgrade(move |socket| handle_socket(socket, state, tenant).instrument(span))
+}
+
+async fn handle_socket(mut socket: WebSocket, state: AppState, tenant: Tenant) {
+    let mut session: Option<Arc<tokio::sync::Mutex<SessionState>>> = None;
+
+    let shutdown = state.shutdown.clone().wait();
+    tokio::pin!(shutdown);
+
+    loop {
+        let message = tokio::select! {
+            message = socket.recv() => message,
+            _ = &mut shutdown => {
+                // 1012: the server is restarting; clients should reconnect
+                let close = CloseFrame {
+                    code: 1012,
+                    reason: "server shutting down".into(),
+                };
+                let _ = socket.send(Message::Close(Some(close))).await;
+                return;
+            }
+        };
+        let Some(Ok(message)) = message else {
+            break;
+        };
+        let text = match message {
+            Message::Text(text) => text,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+
+        let replies = match serde_json::from_str::<ClientMessage>(&text) {
+            Err(e) => vec![error_message(e.to_string())],
+            Ok(message) => match (message, &session) {
+                (ClientMessage::Open(_), Some(_)) => {
+                    vec![error_message("session is already open".to_string())]
+                }
+                (ClientMessage::Open(open), None) => match open_session(&state, &tenant, *open).await {
+                    Ok((opened, reply)) => {
+                        session = Some(opened);
+                        vec![reply]
+                    }
+                    Err(errors) => vec![ServerMessage::Error { errors }.to_message()],
+                },
+                (_, None) => vec![error_message("send an open message first".to_string())],
+                (ClientMessage::CharacterReply { text }, Some(current)) => {
+                    let mut current = current.lock().await;
+                    current.push_history(format!("Character: {}", text), state.validation.max_history_chars);
+                    state.sessions.record(&current);
+                    Vec::new()
+                }
+                (ClientMessage::Input { text }, Some(current)) => analyze_input(&state, &tenant, current, text).await,
+            },
+        };
+
+        for reply in replies {
+            if socket.send(reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn error_message(message: String) -> Message {
+    ServerMessage::Error { errors: vec![message] }.to_message()
+}
+
+async fn open_session(
+    state: &AppState,
+    tenant: &Tenant,
+    open: OpenSession,
+) -> Result<(Arc<tokio::sync::Mutex<SessionState>>, Message), Vec<String>> {
+    let OpenSession {
+        user_id,
+        character_id,
+        character_version,
+        character,
+        current_emotion,
+        current_relationship,
+    } = open;
+
+    let mut validator = Validator::new();
+    validator.not_blank("user_id", &user_id);
+    let current_emotion = current_emotion
+        .map(|value| state.validation.state_value(&mut validator, "current_emotion", value, ranges::emotion_bounds()));
+    let current_relationship = current_relationship.map(|value| {
+        state
+            .validation
+            .state_value(&mut validator, "current_relationship", value, ranges::relationship_bounds())
+    });
+
+    // Resolve the character once so the session is pinned to a single definition
+    let request = EmotionRequest {
+        character_history: String::new(),
+        character_personality: String::new(),
+        character,
+        character_id,
+        character_version,
+        current_relationship,
+        current_emotion,
+        user_input: String::new(),
+        user_id: Some(user_id.clone()),
+    };
+    let resolved = request.resolve_character(&state.registry, tenant);
+    if let Err(RegistryError::Invalid(errors)) = &resolved {
+        validator.extend(errors.clone());
+    }
+    validator
+        .finish()
+        .map_err(|errors| errors.iter().map(ToString::to_string).collect::<Vec<_>>())?;
+    let resolved = resolved.map_err(|e| e.messages())?;
+    let key = SessionStore::key(&tenant.id, resolved.key(), &user_id);
+    Span::current().record("user_id", user_id.as_str());
+    let (session, resumed) = state.sessions.get_or_create(&key, || {
+        let (character_id, character_version) = resolved.source.clone().unzip();
+        SessionState {
+            id: uuid::Uuid::new_v4().to_string(),
+            tenant: tenant.id.clone(),
+            user_id: user_id.clone(),
+            character: character_id.is_none().then(|| resolved.profile.clone()),
+            character_id,
+            character_version,
+            emotion: current_emotion.unwrap_or(resolved.default_emotion),
+            relationship: current_relationship.unwrap_or(resolved.default_relationship),
+            history: VecDeque::new(),
+            updated_at: chrono::Utc::now(),
+        }
+    });
+
+    let reply = {
+        let current = session.lock().await;
+        info!(session_id = %current.id, resumed, "🔌 session opened");
+        ServerMessage::Opened {
+            session_id: &current.id,
+            resumed,
+            emotion: current.emotion,
+            relationship: current.relationship,
+            emotion_tier: session::emotion_tier(current.emotion),
+            relationship_tier: session::relationship_tier(current.relationship),
+        }
+        .to_message()
+    };
+    Ok((session, reply))
+}
+
+async fn analyze_input(
+    state: &AppState,
+    tenant: &Tenant,
+    session: &Arc<tokio::sync::Mutex<SessionState>>,
+    text: String,
+) -> Vec<Message> {
+    // Holding the lock serializes inputs for the same session across connections
+    let mut current = session.lock().await;
+    // Sessions saved under a larger limit still fit the request validation
+    current.trim_history(state.validation.max_history_chars);
+    let request = EmotionRequest {
+        character_history: current.history_text(),
+        character_personality: String::new(),
+        character: current.character.clone(),
+        character_id: current.character_id.clone(),
+        character_version: current.character_version,
+        current_relationship: Some(current.relationship),
+        current_emotion: Some(current.emotion),
+        user_input: text.clone(),
+        user_id: Some(current.user_id.clone()),
+    };
+
+    let outcome = match analysis::run(state, tenant, &request, TierLookup::Corrected).await {
+        Ok(outcome) => outcome,
+        Err(error) => return vec![ServerMessage::Error { errors: error.messages() }.to_message()],
+    };
+
+    current.push_history(format!("User: {}", text), state.validation.max_history_chars);
+    let transitions = current.apply_changes(outcome.emotion_change, outcome.relationship_change);
+    // Written by the next periodic flush
+    state.sessions.record(&current);
+
+    let mut replies = vec![ServerMessage::Analysis {
+        behavior_category: &outcome.behavior_category,
+        emotion_change: outcome.emotion_change,
+        relationship_change: outcome.relationship_change,
+        emotion: current.emotion,
+        relationship: current.relationship,
+    }
+    .to_message()];
+    replies.extend(transitions.iter().map(|t| ServerMessage::TierTransition(t).to_message()));
+    replies
+}